@@ -0,0 +1,466 @@
+//! Background analyzer service. Owns a RealtimeFft on its own thread, wakes
+//! whenever the audio source receives new samples and publishes every new
+//! spectrum to any number of subscribers.
+
+use crate::realtime_fft::realtime_fft_src::{RealtimeFftSrc, SampleNotifier};
+use crate::realtime_fft::RealtimeFft;
+use rustfft::num_complex::Complex;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A single spectrum produced by the analyzer.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    /// Spectrum of the window. Same layout as RealtimeFft::dft.
+    pub spectrum: Vec<Complex<f32>>,
+    /// Time domain samples the spectrum was computed from.
+    pub samples: Vec<f32>,
+    /// Index since the source started of the first sample of the window.
+    pub sample_index: u64,
+    /// Sample rate of the audio source.
+    pub sample_rate: u32,
+    /// When the first sample of the window was recorded, see
+    /// RealtimeFft::frame_instant.
+    pub instant: Instant,
+}
+
+/// What to do when a subscriber's queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Discard the oldest queued frame to make room for the new one.
+    DropOldest,
+    /// Discard the new frame.
+    DropNewest,
+    /// Block the analyzer until the subscriber makes room.
+    Block,
+}
+
+/// State of a subscriber's queue.
+struct QueueState {
+    frames: VecDeque<Arc<Frame>>,
+    /// Number of frames discarded because the queue was full.
+    dropped: usize,
+    /// Set when either the analyzer or the subscriber goes away.
+    closed: bool,
+}
+
+/// Bounded queue of frames shared between the analyzer and one subscriber.
+struct FrameQueue {
+    state: Mutex<QueueState>,
+    condvar: Condvar,
+    capacity: usize,
+    policy: DropPolicy,
+}
+
+impl FrameQueue {
+    /// Pushes a frame according to the drop policy. Blocking pushes give up
+    /// once the queue is closed or the analyzer stops running.
+    fn push(&self, frame: Arc<Frame>, running: &AtomicBool) {
+        let mut state = self.state.lock().unwrap();
+        if state.frames.len() >= self.capacity {
+            match self.policy {
+                DropPolicy::DropOldest => {
+                    state.frames.pop_front();
+                    state.dropped += 1;
+                }
+                DropPolicy::DropNewest => {
+                    state.dropped += 1;
+                    return;
+                }
+                DropPolicy::Block => {
+                    state = self
+                        .condvar
+                        .wait_while(state, |state| {
+                            state.frames.len() >= self.capacity
+                                && !state.closed
+                                && running.load(Ordering::Acquire)
+                        })
+                        .unwrap();
+                    if state.frames.len() >= self.capacity {
+                        state.dropped += 1;
+                        return;
+                    }
+                }
+            }
+        }
+        if !state.closed {
+            state.frames.push_back(frame);
+        }
+        self.condvar.notify_all();
+    }
+
+    /// Marks the queue as closed and wakes everyone waiting on it.
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.condvar.notify_all();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+}
+
+/// Receiving end of a subscription to the analyzer's frames.
+pub struct Subscription {
+    queue: Arc<FrameQueue>,
+}
+
+impl Subscription {
+    /// Blocks until a frame is available. Returns None once the analyzer has
+    /// stopped and every queued frame has been received.
+    pub fn recv(&self) -> Option<Arc<Frame>> {
+        let state = self.queue.state.lock().unwrap();
+        let mut state = self
+            .queue
+            .condvar
+            .wait_while(state, |state| state.frames.is_empty() && !state.closed)
+            .unwrap();
+        let frame = state.frames.pop_front();
+        self.queue.condvar.notify_all();
+        frame
+    }
+
+    /// Like recv but gives up after the timeout.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Arc<Frame>> {
+        let state = self.queue.state.lock().unwrap();
+        let (mut state, _) = self
+            .queue
+            .condvar
+            .wait_timeout_while(state, timeout, |state| {
+                state.frames.is_empty() && !state.closed
+            })
+            .unwrap();
+        let frame = state.frames.pop_front();
+        self.queue.condvar.notify_all();
+        frame
+    }
+
+    /// Returns a frame if one is queued without blocking.
+    pub fn try_recv(&self) -> Option<Arc<Frame>> {
+        let frame = self.queue.state.lock().unwrap().frames.pop_front();
+        self.queue.condvar.notify_all();
+        frame
+    }
+
+    /// Returns the number of frames dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.queue.state.lock().unwrap().dropped
+    }
+
+    /// Returns true once the analyzer has stopped.
+    pub fn is_closed(&self) -> bool {
+        self.queue.is_closed()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+/// State shared between the Analyzer handle and its thread.
+struct Shared {
+    running: AtomicBool,
+    subscribers: Mutex<Vec<Arc<FrameQueue>>>,
+    /// Notifier of the source, used to wake the thread on shutdown.
+    notifier: Mutex<Option<SampleNotifier>>,
+}
+
+impl Shared {
+    /// Sends a frame to every live subscriber, forgetting closed ones.
+    fn publish(&self, frame: &Arc<Frame>) {
+        let subscribers = {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            subscribers.retain(|queue| !queue.is_closed());
+            subscribers.clone()
+        };
+        for queue in subscribers {
+            queue.push(frame.clone(), &self.running);
+        }
+    }
+
+    /// Closes every subscriber queue so receivers stop waiting.
+    fn close_all(&self) {
+        for queue in self.subscribers.lock().unwrap().drain(..) {
+            queue.close();
+        }
+    }
+}
+
+/// Recently published frames. A frame is refilled once every subscriber has
+/// dropped it so publishing doesn't allocate when subscribers keep up.
+struct FramePool {
+    frames: Vec<Arc<Frame>>,
+}
+
+impl FramePool {
+    /// Number of published frames kept for reuse.
+    const LEN: usize = 4;
+
+    fn new() -> Self {
+        FramePool {
+            frames: Vec::with_capacity(Self::LEN),
+        }
+    }
+
+    /// Returns the current frame of `fft` taken at `instant`.
+    fn fill<T: RealtimeFftSrc>(&mut self, fft: &RealtimeFft<T>, instant: Instant) -> Arc<Frame> {
+        let unused = self
+            .frames
+            .iter()
+            .position(|frame| Arc::strong_count(frame) == 1);
+        let mut frame = match unused {
+            Some(index) => self.frames.remove(index),
+            None => Arc::new(Frame {
+                spectrum: Vec::new(),
+                samples: Vec::new(),
+                sample_index: 0,
+                sample_rate: 0,
+                instant,
+            }),
+        };
+
+        let contents = Arc::get_mut(&mut frame).expect("Pooled frame is still shared!");
+        contents.spectrum.clear();
+        contents.spectrum.extend_from_slice(&fft.dft().borrow());
+        contents.samples.clear();
+        contents.samples.extend_from_slice(&fft.frame().borrow());
+        contents.sample_index = fft.frame_sample_index();
+        contents.sample_rate = fft.sample_rate();
+        contents.instant = instant;
+
+        if self.frames.len() == Self::LEN {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame.clone());
+        frame
+    }
+}
+
+/// Runs a RealtimeFft on a background thread.
+pub struct Analyzer {
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Analyzer {
+    /// Spawns the analyzer thread. The RealtimeFft is built on the new thread
+    /// by `make_fft` since it cannot be moved between threads.
+    pub fn spawn<T, F>(make_fft: F) -> Analyzer
+    where
+        T: RealtimeFftSrc + 'static,
+        F: FnOnce() -> RealtimeFft<T> + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            running: AtomicBool::new(true),
+            subscribers: Mutex::new(Vec::new()),
+            notifier: Mutex::new(None),
+        });
+
+        let thread_shared = shared.clone();
+        let handle = thread::Builder::new()
+            .name("realtime_fft analyzer".to_string())
+            .spawn(move || {
+                let shared = thread_shared;
+                let mut fft = make_fft();
+                let notifier = fft.sample_notifier().clone();
+                *shared.notifier.lock().unwrap() = Some(notifier.clone());
+
+                // Wake up at least once per window in case the source stalls.
+                let timeout = fft.window_duration().max(Duration::from_millis(1));
+                let mut generation = notifier.generation();
+                let mut pool = FramePool::new();
+                while shared.running.load(Ordering::Acquire) {
+                    if let (true, Some(instant)) = (fft.update(), fft.frame_instant()) {
+                        shared.publish(&pool.fill(&fft, instant));
+                    }
+                    generation = notifier.wait_timeout(generation, timeout);
+                }
+                shared.close_all();
+            })
+            .expect("Failed to spawn analyzer thread!");
+
+        Analyzer {
+            shared,
+            handle: Some(handle),
+        }
+    }
+
+    /// Subscribes to frames published from now on. Each subscriber has its
+    /// own queue holding at most `capacity` frames.
+    pub fn subscribe(&self, capacity: usize, policy: DropPolicy) -> Subscription {
+        let queue = Arc::new(FrameQueue {
+            state: Mutex::new(QueueState {
+                frames: VecDeque::with_capacity(capacity),
+                dropped: 0,
+                closed: !self.is_running(),
+            }),
+            condvar: Condvar::new(),
+            capacity: capacity.max(1),
+            policy,
+        });
+        self.shared.subscribers.lock().unwrap().push(queue.clone());
+        Subscription { queue }
+    }
+
+    /// Returns true until the analyzer has been asked to stop.
+    pub fn is_running(&self) -> bool {
+        self.shared.running.load(Ordering::Acquire)
+    }
+
+    /// Asks the analyzer thread to stop without waiting for it.
+    pub fn stop(&self) {
+        self.shared.running.store(false, Ordering::Release);
+        if let Some(notifier) = self.shared.notifier.lock().unwrap().as_ref() {
+            notifier.notify();
+        }
+        // Unblock a publisher waiting on a full queue.
+        for queue in self.shared.subscribers.lock().unwrap().iter() {
+            let _state = queue.state.lock().unwrap();
+            queue.condvar.notify_all();
+        }
+    }
+
+    /// Stops the analyzer and waits for its thread to finish. Returns an
+    /// error if the thread panicked.
+    pub fn join(mut self) -> thread::Result<()> {
+        self.stop();
+        self.handle.take().map_or(Ok(()), |handle| handle.join())
+    }
+}
+
+impl Drop for Analyzer {
+    fn drop(&mut self) {
+        self.stop();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime_fft::realtime_fft_src::{ChannelSrc, SrcInfo};
+
+    fn frame(sample_index: u64) -> Arc<Frame> {
+        Arc::new(Frame {
+            spectrum: Vec::new(),
+            samples: Vec::new(),
+            sample_index,
+            sample_rate: 1000,
+            instant: Instant::now(),
+        })
+    }
+
+    fn queue(capacity: usize, policy: DropPolicy) -> FrameQueue {
+        FrameQueue {
+            state: Mutex::new(QueueState {
+                frames: VecDeque::new(),
+                dropped: 0,
+                closed: false,
+            }),
+            condvar: Condvar::new(),
+            capacity,
+            policy,
+        }
+    }
+
+    fn queued(queue: &FrameQueue) -> Vec<u64> {
+        let state = queue.state.lock().unwrap();
+        state
+            .frames
+            .iter()
+            .map(|frame| frame.sample_index)
+            .collect()
+    }
+
+    #[test]
+    fn full_queue_drops_by_policy() {
+        let running = AtomicBool::new(true);
+        let oldest = queue(2, DropPolicy::DropOldest);
+        let newest = queue(2, DropPolicy::DropNewest);
+        for sample_index in 0..5 {
+            oldest.push(frame(sample_index), &running);
+            newest.push(frame(sample_index), &running);
+        }
+        assert_eq!(queued(&oldest), [3, 4]);
+        assert_eq!(queued(&newest), [0, 1]);
+        assert_eq!(oldest.state.lock().unwrap().dropped, 3);
+        assert_eq!(newest.state.lock().unwrap().dropped, 3);
+    }
+
+    #[test]
+    fn blocked_push_gives_up_once_stopped() {
+        let running = AtomicBool::new(false);
+        let blocking = queue(1, DropPolicy::Block);
+        blocking.push(frame(0), &running);
+        blocking.push(frame(1), &running);
+        assert_eq!(queued(&blocking), [0]);
+        assert_eq!(blocking.state.lock().unwrap().dropped, 1);
+    }
+
+    #[test]
+    fn frames_are_reused_once_dropped() {
+        let src = ChannelSrc::new(SrcInfo::new(64), 1000);
+        let fft = RealtimeFft::new(src, Duration::from_millis(32));
+        let mut pool = FramePool::new();
+
+        let first = pool.fill(&fft, Instant::now());
+        let held = pool.fill(&fft, Instant::now());
+        assert!(!Arc::ptr_eq(&first, &held));
+        let first_ptr = Arc::as_ptr(&first);
+        drop(first);
+        let reused = pool.fill(&fft, Instant::now());
+        assert_eq!(Arc::as_ptr(&reused), first_ptr);
+        assert_eq!(reused.samples.len(), 32);
+
+        // Frames held by subscribers are never overwritten.
+        let held: Vec<_> = (0..2 * FramePool::LEN)
+            .map(|_| pool.fill(&fft, Instant::now()))
+            .collect();
+        for (i, a) in held.iter().enumerate() {
+            assert!(held[i + 1..].iter().all(|b| !Arc::ptr_eq(a, b)));
+        }
+        assert_eq!(pool.frames.len(), FramePool::LEN);
+    }
+
+    #[test]
+    fn publishes_frames_of_the_source() {
+        let src_info: SrcInfo = SrcInfo::new(4410);
+        let src = ChannelSrc::new(src_info.clone(), 44100);
+        let analyzer = Analyzer::spawn(move || RealtimeFft::new(src, Duration::from_millis(20)));
+        let frames = analyzer.subscribe(4, DropPolicy::DropOldest);
+
+        // Feed 10 ms of a constant signal every 10 ms like a sound card.
+        let feeding = Arc::new(AtomicBool::new(true));
+        let feeder = {
+            let mut src_info = src_info.clone();
+            let feeding = feeding.clone();
+            thread::spawn(move || {
+                while feeding.load(Ordering::Acquire) {
+                    src_info.push_callback_data(&[0.5; 441], 882);
+                    thread::sleep(Duration::from_millis(10));
+                }
+            })
+        };
+
+        let frame = frames.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(frame.sample_rate, 44100);
+        assert_eq!(frame.samples.len(), 882);
+        assert!((frame.spectrum[0].re - 441.0).abs() < 1e-2);
+        // Stamped with when the window started, not when it was analyzed.
+        assert!(frame.instant + Duration::from_millis(20) <= Instant::now());
+
+        analyzer.join().unwrap();
+        feeding.store(false, Ordering::Release);
+        feeder.join().unwrap();
+        while frames.try_recv().is_some() {}
+        assert!(frames.is_closed());
+        assert!(frames.recv().is_none());
+    }
+}
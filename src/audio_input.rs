@@ -1,23 +1,28 @@
-use crate::realtime_fft::realtime_fft_src::{LatencyInfo, RealtimeFftSrc, SrcInfo};
+use crate::error::ConfigError;
+use crate::realtime_fft::realtime_fft_src::{
+    ChannelSrc, DualSrcInfo, LatencyInfo, RealtimeFftSrc, Sample, SampleNotifier, SampleTap,
+    SrcInfo,
+};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::SampleRate;
 use ringbuf::Consumer;
 use std::sync::{Arc, Mutex};
 
-struct InputStreamInner {
+struct InputStreamInner<S: Sample> {
     stream: cpal::Stream,
-    src_info: SrcInfo,
+    src_info: SrcInfo<S>,
 }
 
-pub struct InputStream {
-    inner: Option<InputStreamInner>,
+/// Default input device. Samples are captured as f32 and buffered as S.
+pub struct InputStream<S: Sample = f32> {
+    inner: Option<InputStreamInner<S>>,
     sample_rate: cpal::SampleRate,
 }
 
 const DEFAULT_SAMPLE_RATE: SampleRate = SampleRate(44100);
 
-impl InputStream {
-    pub fn new() -> InputStream {
+impl<S: Sample> InputStream<S> {
+    pub fn new() -> InputStream<S> {
         // Find input device and input configs.
         let host = cpal::default_host();
         let input_device = host.default_input_device().expect("No input device found!");
@@ -37,7 +42,7 @@ impl InputStream {
     }
 }
 
-impl RealtimeFftSrc for InputStream {
+impl<S: Sample> RealtimeFftSrc<S> for InputStream<S> {
     fn init(&mut self, sample_buffer_size: usize) {
         // Find input device and input configs.
         let host = cpal::default_host();
@@ -80,11 +85,101 @@ impl RealtimeFftSrc for InputStream {
         self.sample_rate.0
     }
 
-    fn sample_cons(&self) -> &Arc<Mutex<Consumer<f32>>> {
+    fn sample_cons(&self) -> &Arc<Mutex<Consumer<S>>> {
         &self.inner.as_ref().unwrap().src_info.sample_cons()
     }
 
     fn latency_info(&self) -> &Arc<Mutex<LatencyInfo>> {
         &self.inner.as_ref().unwrap().src_info.latency_info()
     }
+
+    fn sample_notifier(&self) -> &SampleNotifier {
+        self.inner.as_ref().unwrap().src_info.sample_notifier()
+    }
+
+    fn add_sample_tap(&self, capacity: usize) -> SampleTap {
+        self.inner
+            .as_ref()
+            .unwrap()
+            .src_info
+            .add_sample_tap(capacity)
+    }
+
+    fn reserve(&self, sample_buffer_size: usize) {
+        self.inner
+            .as_ref()
+            .unwrap()
+            .src_info
+            .reserve(sample_buffer_size)
+    }
+}
+
+/// Input stream of a device with at least two channels, e.g. a reference
+/// and a measurement microphone. The channels are kept sample synchronized.
+pub struct DualInputStream {
+    _stream: cpal::Stream,
+    src_info: DualSrcInfo,
+    sample_rate: cpal::SampleRate,
+}
+
+impl DualInputStream {
+    /// Opens the default input device. Each channel buffers
+    /// `sample_buffer_size` samples. Returns an error if the device can't
+    /// capture two channels.
+    pub fn new(sample_buffer_size: usize) -> Result<DualInputStream, ConfigError> {
+        let host = cpal::default_host();
+        let input_device = host.default_input_device().expect("No input device found!");
+        let supported_config = input_device
+            .supported_input_configs()
+            .expect("Error while querying configs!")
+            .find(|config| config.channels() >= 2)
+            .ok_or_else(|| {
+                ConfigError::new("the default input device has no config with two channels")
+            })?;
+
+        let sample_rate = std::cmp::max(supported_config.min_sample_rate(), DEFAULT_SAMPLE_RATE);
+        let supported_config = supported_config.with_sample_rate(sample_rate);
+        let channels = supported_config.channels() as usize;
+
+        let src_info = DualSrcInfo::new(sample_buffer_size);
+        let mut src_info_clone = src_info.clone();
+
+        let input_stream = input_device
+            .build_input_stream(
+                &supported_config.into(),
+                move |data: &[f32], _: &cpal::InputCallbackInfo| {
+                    src_info_clone.push_interleaved(data, channels);
+                },
+                |err| eprintln!("An error occurred on the audio input stream!\n{}", err),
+            )
+            .unwrap();
+
+        input_stream.play().unwrap();
+
+        Ok(DualInputStream {
+            _stream: input_stream,
+            src_info,
+            sample_rate,
+        })
+    }
+
+    /// Returns the sample rate of both channels.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate.0
+    }
+
+    /// Returns the reference channel as a source for RealtimeFft.
+    pub fn reference(&self) -> ChannelSrc {
+        ChannelSrc::new(self.src_info.reference().clone(), self.sample_rate.0)
+    }
+
+    /// Returns the measurement channel as a source for RealtimeFft.
+    pub fn measurement(&self) -> ChannelSrc {
+        ChannelSrc::new(self.src_info.measurement().clone(), self.sample_rate.0)
+    }
+
+    /// Returns synchronized taps of the reference and measurement channels.
+    pub fn add_sample_taps(&self, capacity: usize) -> (SampleTap, SampleTap) {
+        self.src_info.add_sample_taps(capacity)
+    }
 }
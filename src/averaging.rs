@@ -0,0 +1,265 @@
+//! Module for averaging successive spectra produced by RealtimeFft.
+//! Time based modes use the instants of the frames rather than a frame count
+//! so their behaviour does not change with the frame rate.
+
+use rustfft::num_complex::Complex;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How successive spectra are combined.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AveragingMode {
+    /// Output is the latest spectrum.
+    None,
+    /// Exponential average. Reaches 63% of a step after `time_constant`.
+    Exponential { time_constant: Duration },
+    /// Mean of the magnitudes of the last `frames` spectra.
+    Linear { frames: usize },
+    /// Root mean square of the magnitudes of the last `frames` spectra.
+    Rms { frames: usize },
+    /// Holds the maximum for `hold` then decays by `decay_db_per_sec`.
+    PeakHold {
+        hold: Duration,
+        decay_db_per_sec: f32,
+    },
+    /// Holds the minimum since the last reset.
+    MinHold,
+}
+
+/// Averages the magnitudes of successive spectra.
+pub struct SpectrumAverager {
+    /// Current averaging mode.
+    mode: AveragingMode,
+    /// Averaged magnitudes.
+    output: Vec<f32>,
+    /// Previous frames used by the N-frame modes.
+    history: VecDeque<Vec<f32>>,
+    /// When each bin was last raised by peak hold.
+    held_at: Vec<Instant>,
+    /// Instant of the previous frame.
+    last_instant: Option<Instant>,
+    /// Scratch buffer for the magnitudes of a complex spectrum.
+    magnitudes: Vec<f32>,
+}
+
+impl SpectrumAverager {
+    /// Returns a new SpectrumAverager using the given mode.
+    pub fn new(mode: AveragingMode) -> Self {
+        SpectrumAverager {
+            mode,
+            output: Vec::new(),
+            history: VecDeque::new(),
+            held_at: Vec::new(),
+            last_instant: None,
+            magnitudes: Vec::new(),
+        }
+    }
+
+    /// Returns the averaging mode.
+    pub fn mode(&self) -> AveragingMode {
+        self.mode
+    }
+
+    /// Changes the averaging mode. The state is reset if the kind of
+    /// averaging changes, otherwise only the parameters change.
+    pub fn set_mode(&mut self, mode: AveragingMode) {
+        if std::mem::discriminant(&mode) != std::mem::discriminant(&self.mode) {
+            self.reset();
+        }
+        self.mode = mode;
+        if let AveragingMode::Linear { frames } | AveragingMode::Rms { frames } = mode {
+            while self.history.len() > frames.max(1) {
+                self.history.pop_front();
+            }
+        }
+    }
+
+    /// Forgets every previous spectrum.
+    pub fn reset(&mut self) {
+        self.output.clear();
+        self.history.clear();
+        self.held_at.clear();
+        self.last_instant = None;
+    }
+
+    /// Returns the averaged magnitudes.
+    pub fn output(&self) -> &[f32] {
+        &self.output
+    }
+
+    /// Averages the magnitudes of a complex spectrum.
+    pub fn process_spectrum(&mut self, spectrum: &[Complex<f32>], instant: Instant) -> &[f32] {
+        let mut magnitudes = std::mem::take(&mut self.magnitudes);
+        magnitudes.clear();
+        magnitudes.extend(spectrum.iter().map(|bin| bin.norm()));
+        self.process(&magnitudes, instant);
+        self.magnitudes = magnitudes;
+        &self.output
+    }
+
+    /// Adds a frame of magnitudes received at `instant` and returns the
+    /// averaged magnitudes.
+    pub fn process(&mut self, magnitudes: &[f32], instant: Instant) -> &[f32] {
+        // Bin count changed, the previous state is meaningless.
+        if self.output.len() != magnitudes.len() {
+            self.reset();
+        }
+
+        let elapsed = self
+            .last_instant
+            .map(|last| instant.saturating_duration_since(last));
+        self.last_instant = Some(instant);
+
+        // First frame initialises every mode.
+        let elapsed = match elapsed {
+            Some(elapsed) if !self.output.is_empty() => elapsed,
+            _ => {
+                self.output.clear();
+                self.output.extend_from_slice(magnitudes);
+                self.held_at.clear();
+                self.held_at.resize(magnitudes.len(), instant);
+                self.push_history(magnitudes);
+                return &self.output;
+            }
+        };
+
+        match self.mode {
+            AveragingMode::None => self.output.copy_from_slice(magnitudes),
+            AveragingMode::Exponential { time_constant } => {
+                let alpha = if time_constant.as_secs_f64() > 0.0 {
+                    1.0 - (-elapsed.as_secs_f64() / time_constant.as_secs_f64()).exp()
+                } else {
+                    1.0
+                } as f32;
+                for (out, &mag) in self.output.iter_mut().zip(magnitudes) {
+                    *out += alpha * (mag - *out);
+                }
+            }
+            AveragingMode::Linear { .. } => {
+                self.push_history(magnitudes);
+                let count = self.history.len() as f32;
+                for (i, out) in self.output.iter_mut().enumerate() {
+                    *out = self.history.iter().map(|frame| frame[i]).sum::<f32>() / count;
+                }
+            }
+            AveragingMode::Rms { .. } => {
+                self.push_history(magnitudes);
+                let count = self.history.len() as f32;
+                for (i, out) in self.output.iter_mut().enumerate() {
+                    let power = self.history.iter().map(|frame| frame[i] * frame[i]);
+                    *out = (power.sum::<f32>() / count).sqrt();
+                }
+            }
+            AveragingMode::PeakHold {
+                hold,
+                decay_db_per_sec,
+            } => {
+                for ((out, held_at), &mag) in self
+                    .output
+                    .iter_mut()
+                    .zip(self.held_at.iter_mut())
+                    .zip(magnitudes)
+                {
+                    if mag >= *out {
+                        *out = mag;
+                        *held_at = instant;
+                        continue;
+                    }
+                    // Only decay for the part of the interval past the hold time.
+                    let hold_end = *held_at + hold;
+                    let decay_time = instant.saturating_duration_since(hold_end).min(elapsed);
+                    let decay_db = decay_db_per_sec * decay_time.as_secs_f32();
+                    *out = (*out * 10f32.powf(-decay_db / 20.0)).max(mag);
+                }
+            }
+            AveragingMode::MinHold => {
+                for (out, &mag) in self.output.iter_mut().zip(magnitudes) {
+                    *out = out.min(mag);
+                }
+            }
+        }
+        &self.output
+    }
+
+    /// Stores a frame for the N-frame modes, dropping the oldest frames.
+    fn push_history(&mut self, magnitudes: &[f32]) {
+        let frames = match self.mode {
+            AveragingMode::Linear { frames } | AveragingMode::Rms { frames } => frames.max(1),
+            _ => return,
+        };
+        let mut frame = if self.history.len() >= frames {
+            self.history.pop_front().unwrap()
+        } else {
+            Vec::with_capacity(magnitudes.len())
+        };
+        frame.clear();
+        frame.extend_from_slice(magnitudes);
+        self.history.push_back(frame);
+        while self.history.len() > frames {
+            self.history.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn exponential_does_not_depend_on_frame_rate() {
+        for &frames_per_sec in &[10, 100] {
+            let mut averager = SpectrumAverager::new(AveragingMode::Exponential {
+                time_constant: Duration::from_secs(1),
+            });
+            let start = Instant::now();
+            averager.process(&[0.0], start);
+            for frame in 1..=frames_per_sec {
+                averager.process(&[1.0], at(start, 1000 * frame / frames_per_sec));
+            }
+            // One time constant reaches 1 - 1/e of a step.
+            assert!((averager.output()[0] - 0.632).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn linear_and_rms_average_the_last_frames() {
+        let start = Instant::now();
+        let mut linear = SpectrumAverager::new(AveragingMode::Linear { frames: 2 });
+        let mut rms = SpectrumAverager::new(AveragingMode::Rms { frames: 2 });
+        for (frame, &magnitude) in [5.0, 3.0, 4.0].iter().enumerate() {
+            linear.process(&[magnitude], at(start, frame as u64));
+            rms.process(&[magnitude], at(start, frame as u64));
+        }
+        assert!((linear.output()[0] - 3.5).abs() < 1e-6);
+        assert!((rms.output()[0] - 12.5f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn peak_hold_decays_after_the_hold_time() {
+        let mut averager = SpectrumAverager::new(AveragingMode::PeakHold {
+            hold: Duration::from_secs(1),
+            decay_db_per_sec: 20.0,
+        });
+        let start = Instant::now();
+        averager.process(&[1.0], start);
+        assert_eq!(averager.process(&[0.0], at(start, 900))[0], 1.0);
+        // 1 s past the hold time is 20 dB down.
+        let held = averager.process(&[0.0], at(start, 2000))[0];
+        assert!((held - 0.1).abs() < 1e-4);
+    }
+
+    #[test]
+    fn min_hold_keeps_the_minimum_until_reset() {
+        let mut averager = SpectrumAverager::new(AveragingMode::MinHold);
+        let start = Instant::now();
+        averager.process(&[2.0, 1.0], start);
+        averager.process(&[1.0, 3.0], at(start, 10));
+        assert_eq!(averager.process(&[4.0, 4.0], at(start, 20)), [1.0, 1.0]);
+        averager.reset();
+        assert_eq!(averager.process(&[4.0, 4.0], at(start, 30)), [4.0, 4.0]);
+    }
+}
@@ -0,0 +1,223 @@
+//! Second order IIR filter sections and the design helpers shared by the
+//! time domain analyses.
+
+use rustfft::num_complex::Complex;
+use std::f64::consts::PI;
+
+/// Second order section in transposed direct form II.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    /// Filter state.
+    z: [f64; 2],
+}
+
+impl Biquad {
+    /// Returns a section with numerator `b` and denominator `a`. The
+    /// coefficients are normalised so that a[0] is 1.
+    pub fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Biquad {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+            z: [0.0; 2],
+        }
+    }
+
+    /// Filters a single sample.
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+
+    /// Clears the filter state.
+    pub fn reset(&mut self) {
+        self.z = [0.0; 2];
+    }
+
+    /// Returns the complex response at `frequency` Hz.
+    pub fn response(&self, frequency: f64, sample_rate: f64) -> Complex<f64> {
+        let z1 = Complex::from_polar(1.0, -2.0 * PI * frequency / sample_rate);
+        let z2 = z1 * z1;
+        (self.b[0] + z1 * self.b[1] + z2 * self.b[2]) / (1.0 + z1 * self.a[0] + z2 * self.a[1])
+    }
+}
+
+/// Series of second order sections with an overall gain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cascade {
+    sections: Vec<Biquad>,
+    gain: f64,
+}
+
+impl Cascade {
+    /// Returns a cascade of the given sections.
+    pub fn new(sections: Vec<Biquad>, gain: f64) -> Self {
+        Cascade { sections, gain }
+    }
+
+    /// Filters a single sample.
+    pub fn process(&mut self, x: f64) -> f64 {
+        self.sections
+            .iter_mut()
+            .fold(x * self.gain, |x, section| section.process(x))
+    }
+
+    /// Clears the state of every section.
+    pub fn reset(&mut self) {
+        self.sections.iter_mut().for_each(Biquad::reset);
+    }
+
+    /// Returns the complex response at `frequency` Hz.
+    pub fn response(&self, frequency: f64, sample_rate: f64) -> Complex<f64> {
+        self.sections
+            .iter()
+            .fold(Complex::new(self.gain, 0.0), |h, section| {
+                h * section.response(frequency, sample_rate)
+            })
+    }
+
+    /// Scales the cascade so that its magnitude at `frequency` is 1.
+    pub fn normalize_at(&mut self, frequency: f64, sample_rate: f64) {
+        self.gain /= self.response(frequency, sample_rate).norm();
+    }
+}
+
+/// Maps an analog filter given by its zeros, poles and gain to a digital
+/// cascade with the bilinear transform. Frequencies should be prewarped by
+/// the caller where it matters.
+pub fn bilinear_zpk(
+    zeros: &[Complex<f64>],
+    poles: &[Complex<f64>],
+    gain: f64,
+    sample_rate: f64,
+) -> Cascade {
+    let fs2 = 2.0 * sample_rate;
+    let map = |s: &Complex<f64>| (fs2 + s) / (fs2 - s);
+
+    let mut digital_zeros: Vec<Complex<f64>> = zeros.iter().map(map).collect();
+    let digital_poles: Vec<Complex<f64>> = poles.iter().map(map).collect();
+    // Zeros at infinity end up at Nyquist.
+    while digital_zeros.len() < digital_poles.len() {
+        digital_zeros.push(Complex::new(-1.0, 0.0));
+    }
+
+    let numerator: Complex<f64> = zeros.iter().map(|z| fs2 - z).product();
+    let denominator: Complex<f64> = poles.iter().map(|p| fs2 - p).product();
+    let digital_gain = gain * (numerator / denominator).re;
+
+    let zero_pairs = pair_roots(&digital_zeros);
+    let pole_pairs = pair_roots(&digital_poles);
+    let sections = zero_pairs
+        .into_iter()
+        .zip(pole_pairs)
+        .map(|(b, a)| Biquad::new(b, a))
+        .collect();
+    Cascade::new(sections, digital_gain)
+}
+
+/// Groups roots into conjugate or real pairs and returns the quadratic
+/// polynomial of each pair.
+fn pair_roots(roots: &[Complex<f64>]) -> Vec<[f64; 3]> {
+    const TOLERANCE: f64 = 1e-9;
+    let mut polynomials = Vec::new();
+    let mut real = Vec::new();
+    for root in roots {
+        if root.im.abs() <= TOLERANCE {
+            real.push(root.re);
+        } else if root.im > 0.0 {
+            // Its conjugate completes the pair.
+            polynomials.push([1.0, -2.0 * root.re, root.norm_sqr()]);
+        }
+    }
+    for pair in real.chunks(2) {
+        polynomials.push(match *pair {
+            [a, b] => [1.0, -(a + b), a * b],
+            [a] => [1.0, -a, 0.0],
+            _ => unreachable!(),
+        });
+    }
+    polynomials
+}
+
+/// Returns the analog frequency in rad/s that the bilinear transform maps to
+/// `frequency` Hz.
+pub fn prewarp(frequency: f64, sample_rate: f64) -> f64 {
+    2.0 * sample_rate * (PI * frequency / sample_rate).tan()
+}
+
+/// Designs a Butterworth band-pass filter of `order` pole pairs between
+/// `lower` and `upper` Hz with unity gain at the centre.
+pub fn butterworth_bandpass(order: usize, lower: f64, upper: f64, sample_rate: f64) -> Cascade {
+    let low = prewarp(lower, sample_rate);
+    let high = prewarp(upper, sample_rate);
+    let bandwidth = high - low;
+    let centre_squared = low * high;
+
+    let mut poles = Vec::with_capacity(order * 2);
+    for k in 0..order {
+        let theta = PI * (2 * k + order + 1) as f64 / (2 * order) as f64;
+        let prototype = Complex::from_polar(1.0, theta);
+        // Each low-pass pole becomes the two roots of s^2 - p*B*s + w0^2.
+        let b = prototype * bandwidth;
+        let root = (b * b - 4.0 * centre_squared).sqrt();
+        poles.push((b + root) / 2.0);
+        poles.push((b - root) / 2.0);
+    }
+    let zeros = vec![Complex::new(0.0, 0.0); order];
+
+    let mut cascade = bilinear_zpk(&zeros, &poles, bandwidth.powi(order as i32), sample_rate);
+    let centre = (sample_rate / PI) * (centre_squared.sqrt() / (2.0 * sample_rate)).atan();
+    cascade.normalize_at(centre, sample_rate);
+    cascade
+}
+
+/// Designs a Butterworth low-pass filter of `order` poles with its -3 dB
+/// point at `cutoff` Hz and unity gain at DC.
+pub fn butterworth_lowpass(order: usize, cutoff: f64, sample_rate: f64) -> Cascade {
+    let corner = prewarp(cutoff, sample_rate);
+    let poles: Vec<Complex<f64>> = (0..order)
+        .map(|k| {
+            let theta = PI * (2 * k + order + 1) as f64 / (2 * order) as f64;
+            Complex::from_polar(corner, theta)
+        })
+        .collect();
+
+    let mut cascade = bilinear_zpk(&[], &poles, corner.powi(order as i32), sample_rate);
+    cascade.normalize_at(0.0, sample_rate);
+    cascade
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gain_db(cascade: &Cascade, frequency: f64) -> f64 {
+        20.0 * cascade.response(frequency, 48000.0).norm().log10()
+    }
+
+    #[test]
+    fn butterworth_lowpass_corner() {
+        let lowpass = butterworth_lowpass(4, 1000.0, 48000.0);
+        assert!(gain_db(&lowpass, 0.0).abs() < 1e-9);
+        assert!((gain_db(&lowpass, 1000.0) + 3.0103).abs() < 1e-3);
+        // At least 24 dB per octave above the corner.
+        assert!(gain_db(&lowpass, 4000.0) < -48.0);
+    }
+
+    #[test]
+    fn cascade_filters_like_its_response() {
+        let mut lowpass = butterworth_lowpass(2, 1000.0, 48000.0);
+        let mut peak: f64 = 0.0;
+        for n in 0..48000 {
+            let x = (2.0 * PI * 1000.0 * n as f64 / 48000.0).sin();
+            let y = lowpass.process(x);
+            if n >= 24000 {
+                peak = peak.max(y.abs());
+            }
+        }
+        assert!((peak - lowpass.response(1000.0, 48000.0).norm()).abs() < 1e-3);
+    }
+}
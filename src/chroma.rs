@@ -0,0 +1,398 @@
+//! Chromagram (pitch class profile) of the RealtimeFft spectrum with
+//! automatic tuning estimation and template based key and chord estimation.
+
+use crate::error::ConfigError;
+use crate::frequency_axis::FrequencyAxis;
+use crate::music::{Tuning, NOTE_NAMES};
+use crate::peaks::{Interpolation, PeakFinder};
+use rustfft::num_complex::Complex;
+use std::f32::consts::PI;
+use std::fmt;
+
+/// Krumhansl-Kessler major key profile starting from the tonic.
+const MAJOR_PROFILE: [f32; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+/// Krumhansl-Kessler minor key profile starting from the tonic.
+const MINOR_PROFILE: [f32; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+/// Parameters of the chroma analyzer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChromaConfig {
+    /// Number of pitch classes. A positive multiple of 12.
+    pub num_classes: usize,
+    /// Reference tuning before any estimation.
+    pub tuning: Tuning,
+    /// Whether to track the tuning of the input.
+    pub estimate_tuning: bool,
+    /// Time constant of the tuning estimate in frames.
+    pub tuning_frames: f32,
+    /// Frequency range folded into the chromagram.
+    pub min_hz: f32,
+    pub max_hz: f32,
+}
+
+impl Default for ChromaConfig {
+    fn default() -> Self {
+        ChromaConfig {
+            num_classes: 12,
+            tuning: Tuning::default(),
+            estimate_tuning: false,
+            tuning_frames: 50.0,
+            min_hz: 55.0,
+            max_hz: 5000.0,
+        }
+    }
+}
+
+impl ChromaConfig {
+    /// Checks that the classes split every semitone equally.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.num_classes == 0 || self.num_classes % 12 != 0 {
+            return Err(ConfigError::new(
+                "num_classes must be a positive multiple of 12",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Folds spectra into pitch classes.
+pub struct ChromaAnalyzer {
+    config: ChromaConfig,
+    axis: FrequencyAxis,
+    /// Smoothed mean of the deviation of spectral peaks from the nearest
+    /// semitone, as a vector on the unit circle.
+    deviation: Complex<f32>,
+    peak_finder: PeakFinder,
+}
+
+impl ChromaAnalyzer {
+    /// Returns a chroma analyzer for spectra on `axis`.
+    pub fn new(config: ChromaConfig, axis: &FrequencyAxis) -> Result<Self, ConfigError> {
+        config.validate()?;
+        Ok(ChromaAnalyzer {
+            config,
+            axis: *axis,
+            deviation: Complex::new(0.0, 0.0),
+            peak_finder: PeakFinder {
+                max_peaks: 20,
+                interpolation: Interpolation::Parabolic,
+                min_distance_hz: 0.0,
+                min_prominence_db: 6.0,
+            },
+        })
+    }
+
+    /// Returns the tuning currently used, including any estimated offset.
+    pub fn tuning(&self) -> Tuning {
+        let offset = if self.deviation.norm() > 0.0 {
+            self.deviation.arg() / (2.0 * PI)
+        } else {
+            0.0
+        };
+        Tuning {
+            a4_hz: self.config.tuning.a4_hz * 2f32.powf(offset / 12.0),
+        }
+    }
+
+    /// Forgets the tuning estimate.
+    pub fn reset(&mut self) {
+        self.deviation = Complex::new(0.0, 0.0);
+    }
+
+    /// Returns the energy in each pitch class normalised so the largest is 1.
+    /// Class 0 is C.
+    pub fn process(&mut self, spectrum: &[Complex<f32>]) -> Vec<f32> {
+        if self.config.estimate_tuning {
+            self.update_tuning(spectrum);
+        }
+        let tuning = self.tuning();
+        let classes = self.config.num_classes;
+        let mut chroma = vec![0.0; classes];
+        if spectrum.is_empty() {
+            return chroma;
+        }
+
+        let first = self.axis.hz_to_bin(self.config.min_hz).ceil().max(1.0) as usize;
+        let last = (self.axis.hz_to_bin(self.config.max_hz) as usize).min(spectrum.len() - 1);
+        for (bin, value) in spectrum.iter().enumerate().take(last + 1).skip(first) {
+            // Position in classes, C being 0. Energy is split linearly
+            // between the two closest classes.
+            let midi = tuning.hz_to_midi(self.axis.bin_to_hz(bin as f32));
+            let position = (midi * classes as f32 / 12.0).rem_euclid(classes as f32);
+            let lower = position.floor();
+            let fraction = position - lower;
+            let energy = value.norm_sqr();
+            chroma[lower as usize % classes] += energy * (1.0 - fraction);
+            chroma[(lower as usize + 1) % classes] += energy * fraction;
+        }
+
+        let max = chroma.iter().cloned().fold(0.0, f32::max);
+        if max > 0.0 {
+            chroma.iter_mut().for_each(|value| *value /= max);
+        }
+        chroma
+    }
+
+    /// Updates the tuning estimate from the deviation of the spectral peaks
+    /// from the reference semitones.
+    fn update_tuning(&mut self, spectrum: &[Complex<f32>]) {
+        let mut sum = Complex::new(0.0, 0.0);
+        for peak in self.peak_finder.find(spectrum, &self.axis) {
+            if peak.frequency < self.config.min_hz || peak.frequency > self.config.max_hz {
+                continue;
+            }
+            let midi = self.config.tuning.hz_to_midi(peak.frequency);
+            sum += Complex::from_polar(peak.amplitude, 2.0 * PI * midi);
+        }
+        if sum.norm() == 0.0 {
+            return;
+        }
+        let alpha = 1.0 / self.config.tuning_frames.max(1.0);
+        self.deviation = self.deviation * (1.0 - alpha) + sum / sum.norm() * alpha;
+    }
+}
+
+/// Mode of a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Major,
+    Minor,
+}
+
+/// Estimated key of a chromagram.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyEstimate {
+    /// Pitch class of the tonic, 0 being C.
+    pub tonic: usize,
+    pub mode: Mode,
+    /// Correlation with the key profile, in [-1, 1].
+    pub correlation: f32,
+}
+
+impl fmt::Display for KeyEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self.mode {
+            Mode::Major => "major",
+            Mode::Minor => "minor",
+        };
+        write!(f, "{} {}", NOTE_NAMES[self.tonic], mode)
+    }
+}
+
+/// Estimated chord of a chromagram.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChordEstimate {
+    /// Pitch class of the root, 0 being C.
+    pub root: usize,
+    pub mode: Mode,
+    /// Cosine similarity with the chord template, in [0, 1].
+    pub similarity: f32,
+}
+
+impl fmt::Display for ChordEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let suffix = match self.mode {
+            Mode::Major => "",
+            Mode::Minor => "m",
+        };
+        write!(f, "{}{}", NOTE_NAMES[self.root], suffix)
+    }
+}
+
+/// Estimates the key of a chromagram by correlating it with the rotated
+/// Krumhansl-Kessler profiles. Best used on a chromagram averaged over
+/// several seconds. None unless the chromagram has a positive multiple of
+/// 12 classes.
+pub fn estimate_key(chroma: &[f32]) -> Option<KeyEstimate> {
+    let chroma = fold_to_semitones(chroma)?;
+    let mut best = KeyEstimate {
+        tonic: 0,
+        mode: Mode::Major,
+        correlation: f32::NEG_INFINITY,
+    };
+    for (mode, profile) in [(Mode::Major, &MAJOR_PROFILE), (Mode::Minor, &MINOR_PROFILE)].iter() {
+        for tonic in 0..12 {
+            let rotated: Vec<f32> = (0..12).map(|i| profile[(i + 12 - tonic) % 12]).collect();
+            let correlation = pearson(&chroma, &rotated);
+            if correlation > best.correlation {
+                best = KeyEstimate {
+                    tonic,
+                    mode: *mode,
+                    correlation,
+                };
+            }
+        }
+    }
+    Some(best)
+}
+
+/// Estimates the major or minor triad best matching a chromagram. None
+/// unless the chromagram has a positive multiple of 12 classes.
+pub fn estimate_chord(chroma: &[f32]) -> Option<ChordEstimate> {
+    let chroma = fold_to_semitones(chroma)?;
+    let norm = chroma.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let mut best = ChordEstimate {
+        root: 0,
+        mode: Mode::Major,
+        similarity: 0.0,
+    };
+    if norm == 0.0 {
+        return Some(best);
+    }
+    for &(mode, third) in [(Mode::Major, 4), (Mode::Minor, 3)].iter() {
+        for root in 0..12 {
+            // Binary triad template has a norm of sqrt(3).
+            let energy = chroma[root] + chroma[(root + third) % 12] + chroma[(root + 7) % 12];
+            let similarity = energy / (norm * 3f32.sqrt());
+            if similarity > best.similarity {
+                best = ChordEstimate {
+                    root,
+                    mode,
+                    similarity,
+                };
+            }
+        }
+    }
+    Some(best)
+}
+
+/// Sums the classes of a chromagram with a multiple of 12 classes into
+/// semitones.
+fn fold_to_semitones(chroma: &[f32]) -> Option<Vec<f32>> {
+    if chroma.is_empty() || chroma.len() % 12 != 0 {
+        return None;
+    }
+    let per_semitone = chroma.len() / 12;
+    // Class 0 is centred on C so each semitone is centred on its first class.
+    Some(
+        (0..12)
+            .map(|semitone| {
+                (0..per_semitone)
+                    .map(|i| {
+                        let class = semitone * per_semitone + i + chroma.len() - per_semitone / 2;
+                        chroma[class % chroma.len()]
+                    })
+                    .sum()
+            })
+            .collect(),
+    )
+}
+
+/// Pearson correlation of two equally long slices.
+fn pearson(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len() as f32;
+    let (mean_a, mean_b) = (a.iter().sum::<f32>() / n, b.iter().sum::<f32>() / n);
+    let (mut covariance, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        covariance += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a) * (x - mean_a);
+        var_b += (y - mean_b) * (y - mean_b);
+    }
+    if var_a == 0.0 || var_b == 0.0 {
+        0.0
+    } else {
+        covariance / (var_a * var_b).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window::WindowFunction;
+    use realfft::RealFftPlanner;
+
+    /// Hann windowed spectrum of unit sines at `frequencies`.
+    fn spectrum(frequencies: &[f32]) -> Vec<Complex<f32>> {
+        let window = WindowFunction::Hann.coefficients(8192);
+        let mut samples: Vec<f32> = (0..8192)
+            .map(|n| {
+                let t = n as f32 / 8000.0;
+                let x: f32 = frequencies.iter().map(|f| (2.0 * PI * f * t).sin()).sum();
+                x * window[n]
+            })
+            .collect();
+        let fft = RealFftPlanner::<f32>::new().plan_fft_forward(8192);
+        let mut spectrum = fft.make_output_vec();
+        fft.process(&mut samples, &mut spectrum).unwrap();
+        spectrum
+    }
+
+    #[test]
+    fn tones_fold_into_their_classes() {
+        let axis = FrequencyAxis::new(8000, 8192);
+        let mut analyzer = ChromaAnalyzer::new(ChromaConfig::default(), &axis).unwrap();
+        // A3 and A4 both fold into A.
+        let chroma = analyzer.process(&spectrum(&[220.0, 440.0]));
+        assert_eq!(chroma[9], 1.0);
+        // The main lobe spills slightly into the neighbouring classes.
+        assert!(chroma
+            .iter()
+            .enumerate()
+            .all(|(class, &c)| class == 9 || c < 0.02));
+
+        // C major triad.
+        let chroma = analyzer.process(&spectrum(&[261.63, 329.63, 392.0]));
+        let chord = estimate_chord(&chroma).unwrap();
+        assert_eq!((chord.root, chord.mode), (0, Mode::Major));
+        assert_eq!(chord.to_string(), "C");
+        assert!(chord.similarity > 0.99);
+
+        // A minor triad with 36 classes.
+        let config = ChromaConfig {
+            num_classes: 36,
+            ..ChromaConfig::default()
+        };
+        let mut analyzer = ChromaAnalyzer::new(config, &axis).unwrap();
+        let chroma = analyzer.process(&spectrum(&[220.0, 261.63, 329.63]));
+        assert_eq!(estimate_chord(&chroma).unwrap().to_string(), "Am");
+    }
+
+    #[test]
+    fn estimates_tuning() {
+        let axis = FrequencyAxis::new(8000, 8192);
+        let config = ChromaConfig {
+            estimate_tuning: true,
+            tuning_frames: 5.0,
+            ..ChromaConfig::default()
+        };
+        let mut analyzer = ChromaAnalyzer::new(config, &axis).unwrap();
+        // A quarter of a semitone sharp of A440.
+        let a4 = 440.0 * 2f32.powf(0.25 / 12.0);
+        let spectrum = spectrum(&[a4 / 2.0, a4, a4 * 2f32.powf(4.0 / 12.0)]);
+        for _ in 0..100 {
+            analyzer.process(&spectrum);
+        }
+        assert!((analyzer.tuning().a4_hz - a4).abs() < 0.5);
+    }
+
+    #[test]
+    fn key_of_its_profile() {
+        // The minor profile rotated to E.
+        let chroma: Vec<f32> = (0..12).map(|i| MINOR_PROFILE[(i + 8) % 12]).collect();
+        let key = estimate_key(&chroma).unwrap();
+        assert_eq!((key.tonic, key.mode), (4, Mode::Minor));
+        assert!((key.correlation - 1.0).abs() < 1e-5);
+        assert_eq!(key.to_string(), "E minor");
+    }
+
+    #[test]
+    fn rejects_classes_not_splitting_semitones() {
+        let axis = FrequencyAxis::new(8000, 8192);
+        for &num_classes in &[0, 10, 30] {
+            let config = ChromaConfig {
+                num_classes,
+                ..ChromaConfig::default()
+            };
+            assert!(ChromaAnalyzer::new(config, &axis).is_err());
+        }
+        assert!(estimate_key(&[1.0; 10]).is_none());
+        assert!(estimate_chord(&[]).is_none());
+
+        let mut analyzer = ChromaAnalyzer::new(ChromaConfig::default(), &axis).unwrap();
+        assert_eq!(analyzer.process(&[]), [0.0; 12]);
+    }
+}
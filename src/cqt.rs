@@ -0,0 +1,304 @@
+//! Constant-Q transform using the spectral kernel method of Brown and
+//! Puckette. Each constant-Q bin is a sparse weighted sum of the bins of the
+//! RealtimeFft spectrum, so no extra fft is needed per frame. The lowest
+//! bins need windows far longer than the RealtimeFft default, see
+//! CqtConfig::analysis_config.
+
+use crate::error::ConfigError;
+use crate::frequency_axis::FrequencyAxis;
+use crate::realtime_fft::realtime_fft_src::{RealtimeFftSrc, Sample};
+use crate::realtime_fft::{AnalysisConfig, RealtimeFft};
+use crate::window::WindowFunction;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use std::f32::consts::PI;
+use std::time::Duration;
+
+/// Parameters of the constant-Q transform.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CqtConfig {
+    /// Centre frequency of the lowest bin in Hz.
+    pub min_hz: f32,
+    pub bins_per_octave: usize,
+    pub octaves: usize,
+    /// Kernel values below this fraction of the kernel's peak are dropped.
+    pub sparsity_threshold: f32,
+    /// Window of each temporal kernel.
+    pub window: WindowFunction,
+}
+
+impl CqtConfig {
+    /// Quality factor shared by every bin.
+    pub fn q(&self) -> f32 {
+        1.0 / (2f32.powf(1.0 / self.bins_per_octave as f32) - 1.0)
+    }
+
+    /// Returns the fft size needed to fit the kernel of the lowest bin.
+    pub fn required_fft_size(&self, sample_rate: u32) -> usize {
+        (self.q() * sample_rate as f32 / self.min_hz).ceil() as usize
+    }
+
+    /// Returns a RealtimeFft configuration whose spectra this transform can
+    /// use: unwindowed frames long enough for the lowest bin.
+    pub fn analysis_config(&self, sample_rate: u32) -> AnalysisConfig {
+        // RealtimeFft truncates the window to an even number of samples.
+        let samples = self.required_fft_size(sample_rate) + 2;
+        AnalysisConfig {
+            window_duration: Duration::from_secs_f64(samples as f64 / sample_rate as f64),
+            fft_size: None,
+            window: WindowFunction::Rectangular,
+            ..AnalysisConfig::default()
+        }
+    }
+
+    /// Checks that the bins are well defined and fit in frames of
+    /// `frame_len` samples.
+    fn validate(&self, sample_rate: u32, frame_len: usize) -> Result<(), ConfigError> {
+        if self.bins_per_octave == 0 || self.octaves == 0 {
+            return Err(ConfigError::new(
+                "bins_per_octave and octaves must be at least 1",
+            ));
+        }
+        if self.min_hz.is_nan() || self.min_hz <= 0.0 {
+            return Err(ConfigError::new("min_hz must be positive"));
+        }
+        let required = self.required_fft_size(sample_rate);
+        if required > frame_len {
+            return Err(ConfigError::new(format!(
+                "frames of {} samples are too short for a constant-Q transform from {} Hz, \
+                 {} are needed",
+                frame_len, self.min_hz, required
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Default for CqtConfig {
+    fn default() -> Self {
+        CqtConfig {
+            // A0
+            min_hz: 27.5,
+            bins_per_octave: 36,
+            octaves: 8,
+            sparsity_threshold: 0.0054,
+            window: WindowFunction::Hann,
+        }
+    }
+}
+
+/// Precomputed spectral kernels of a constant-Q transform.
+pub struct ConstantQ {
+    /// Non-zero conjugated kernel values of each bin.
+    kernels: Vec<Vec<(usize, Complex<f32>)>>,
+    /// Centre frequency of each bin in Hz.
+    frequencies: Vec<f32>,
+}
+
+impl ConstantQ {
+    /// Builds the kernels for spectra on `axis` of frames filling the whole
+    /// fft. The spectra must be of unwindowed frames as each kernel applies
+    /// its own window. Fails if the fft is too short for the lowest bin.
+    pub fn new(config: CqtConfig, axis: &FrequencyAxis) -> Result<Self, ConfigError> {
+        Self::with_frame_len(config, axis, axis.fft_size())
+    }
+
+    /// Builds the kernels for the spectra of `fft`, which must not apply a
+    /// window. Zero padded frames are supported.
+    pub fn for_realtime_fft<T, S>(
+        config: CqtConfig,
+        fft: &RealtimeFft<T, S>,
+    ) -> Result<Self, ConfigError>
+    where
+        T: RealtimeFftSrc<S>,
+        S: Sample,
+    {
+        if fft.config().window != WindowFunction::Rectangular {
+            return Err(ConfigError::new(
+                "the constant-Q transform needs unwindowed spectra",
+            ));
+        }
+        Self::with_frame_len(config, &fft.frequency_axis(), fft.window_size())
+    }
+
+    /// Builds the kernels for spectra of `frame_len` samples zero padded to
+    /// the fft size of `axis`.
+    fn with_frame_len(
+        config: CqtConfig,
+        axis: &FrequencyAxis,
+        frame_len: usize,
+    ) -> Result<Self, ConfigError> {
+        let fft_size = axis.fft_size();
+        let sample_rate = axis.sample_rate() as f32;
+        let frame_len = frame_len.min(fft_size);
+        config.validate(axis.sample_rate(), frame_len)?;
+
+        let q = config.q();
+        let num_bins = config.bins_per_octave * config.octaves;
+        let fft = FftPlanner::new().plan_fft_forward(fft_size);
+        let mut kernels = Vec::with_capacity(num_bins);
+        let mut frequencies = Vec::with_capacity(num_bins);
+        let mut buffer = vec![Complex::new(0.0, 0.0); fft_size];
+
+        for k in 0..num_bins {
+            let frequency = config.min_hz * 2f32.powf(k as f32 / config.bins_per_octave as f32);
+            if frequency >= axis.nyquist() {
+                break;
+            }
+            let len = ((q * sample_rate / frequency).ceil() as usize).min(frame_len);
+
+            // Temporal kernel centred in the frame, scaled so a sinusoid of
+            // amplitude A gives a magnitude of A.
+            let window = config.window.coefficients(len);
+            let gain = 2.0 / window.iter().sum::<f32>();
+            let start = (frame_len - len) / 2;
+            buffer.iter_mut().for_each(|x| *x = Complex::new(0.0, 0.0));
+            for (n, w) in window.iter().enumerate() {
+                let phase = 2.0 * PI * q * n as f32 / len as f32;
+                buffer[start + n] = Complex::from_polar(gain * w, phase);
+            }
+            fft.process(&mut buffer);
+
+            // Parseval: sum(x * conj(t)) = sum(X * conj(K)) / N. Only the
+            // positive frequencies matter for a real frame.
+            let peak = buffer.iter().map(|x| x.norm()).fold(0.0, f32::max);
+            let kernel = buffer[..axis.num_bins()]
+                .iter()
+                .enumerate()
+                .filter(|(_, value)| value.norm() >= config.sparsity_threshold * peak)
+                .map(|(bin, value)| (bin, value.conj() / fft_size as f32))
+                .collect();
+            kernels.push(kernel);
+            frequencies.push(frequency);
+        }
+
+        Ok(ConstantQ {
+            kernels,
+            frequencies,
+        })
+    }
+
+    /// Returns the centre frequency of each bin in Hz.
+    pub fn frequencies(&self) -> &[f32] {
+        &self.frequencies
+    }
+
+    /// Returns the complex constant-Q coefficients of a spectrum.
+    pub fn process_complex(&self, spectrum: &[Complex<f32>]) -> Vec<Complex<f32>> {
+        self.kernels
+            .iter()
+            .map(|kernel| {
+                kernel
+                    .iter()
+                    .filter(|(bin, _)| *bin < spectrum.len())
+                    .map(|&(bin, value)| spectrum[bin] * value)
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Returns the magnitude of each constant-Q bin of a spectrum.
+    pub fn process(&self, spectrum: &[Complex<f32>]) -> Vec<f32> {
+        self.process_complex(spectrum)
+            .into_iter()
+            .map(|value| value.norm())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime_fft::realtime_fft_src::{ChannelSrc, SrcInfo};
+    use realfft::RealFftPlanner;
+
+    fn config() -> CqtConfig {
+        CqtConfig {
+            min_hz: 110.0,
+            bins_per_octave: 12,
+            octaves: 4,
+            ..CqtConfig::default()
+        }
+    }
+
+    /// Spectrum of `len` samples of a unit sine zero padded to `fft_size`.
+    fn sine_spectrum(frequency: f32, len: usize, fft_size: usize) -> Vec<Complex<f32>> {
+        let mut samples: Vec<f32> = (0..fft_size)
+            .map(|n| {
+                if n < len {
+                    (2.0 * PI * frequency * n as f32 / 8000.0).sin()
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+        let fft = RealFftPlanner::<f32>::new().plan_fft_forward(fft_size);
+        let mut spectrum = fft.make_output_vec();
+        fft.process(&mut samples, &mut spectrum).unwrap();
+        spectrum
+    }
+
+    #[test]
+    fn bins_are_geometrically_spaced() {
+        let axis = FrequencyAxis::new(8000, 2048);
+        let cqt = ConstantQ::new(config(), &axis).unwrap();
+        let frequencies = cqt.frequencies();
+        assert_eq!(frequencies.len(), 48);
+        assert_eq!(frequencies[0], 110.0);
+        assert!((frequencies[12] - 220.0).abs() < 1e-3);
+        assert!((frequencies[21] - 440.0 * 2f32.powf(9.0 / 12.0) / 2.0).abs() < 1e-3);
+
+        // Bins at or above Nyquist are left out.
+        let axis = FrequencyAxis::new(1000, 2048);
+        let cqt = ConstantQ::new(config(), &axis).unwrap();
+        assert!(cqt.frequencies().iter().all(|&f| f < 500.0));
+    }
+
+    #[test]
+    fn sine_has_its_amplitude_in_its_bin() {
+        let axis = FrequencyAxis::new(8000, 2048);
+        let cqt = ConstantQ::new(config(), &axis).unwrap();
+        for &bin in &[0, 17, 40] {
+            let frequency = cqt.frequencies()[bin];
+            let magnitudes = cqt.process(&sine_spectrum(frequency, 2048, 2048));
+            assert!((magnitudes[bin] - 1.0).abs() < 0.02);
+            assert!(magnitudes[bin + 2] < 0.2);
+            if bin >= 2 {
+                assert!(magnitudes[bin - 2] < 0.2);
+            }
+        }
+    }
+
+    #[test]
+    fn zero_padded_frames() {
+        let src = ChannelSrc::new(SrcInfo::new(4096), 8000);
+        let mut analysis = config().analysis_config(8000);
+        analysis.fft_size = Some(4096);
+        let fft: RealtimeFft<_> = RealtimeFft::with_config(src, analysis);
+        let cqt = ConstantQ::for_realtime_fft(config(), &fft).unwrap();
+
+        let spectrum = sine_spectrum(220.0, fft.window_size(), 4096);
+        let magnitudes = cqt.process(&spectrum);
+        assert!((magnitudes[12] - 1.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn rejects_unusable_configurations() {
+        let axis = FrequencyAxis::new(8000, 1024);
+        assert!(ConstantQ::new(config(), &axis).is_err());
+        let empty = CqtConfig {
+            octaves: 0,
+            ..config()
+        };
+        assert!(ConstantQ::new(empty, &FrequencyAxis::new(8000, 2048)).is_err());
+
+        // The default RealtimeFft window is far too short for the defaults.
+        let src = ChannelSrc::new(SrcInfo::new(1024), 8000);
+        let mut fft: RealtimeFft<_> = RealtimeFft::new(src, Duration::from_millis(20));
+        assert!(ConstantQ::for_realtime_fft(config(), &fft).is_err());
+        fft.reconfigure(config().analysis_config(8000));
+        assert!(ConstantQ::for_realtime_fft(config(), &fft).is_ok());
+        fft.set_window_function(WindowFunction::Hann);
+        assert!(ConstantQ::for_realtime_fft(config(), &fft).is_err());
+    }
+}
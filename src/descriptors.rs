@@ -0,0 +1,230 @@
+//! Compact per-frame features summarising the shape of a spectrum. The
+//! spectral descriptors are computed from the RealtimeFft spectrum and the
+//! temporal ones from the frame it was computed from.
+
+use crate::frequency_axis::FrequencyAxis;
+use rustfft::num_complex::Complex;
+
+/// Descriptors of a single frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Descriptors {
+    /// Magnitude weighted mean frequency in Hz.
+    pub centroid: f32,
+    /// Standard deviation of frequency around the centroid in Hz.
+    pub spread: f32,
+    /// Asymmetry of the spectrum around the centroid.
+    pub skewness: f32,
+    /// Peakedness of the spectrum around the centroid. 3 for a gaussian.
+    pub kurtosis: f32,
+    /// Frequency below which the configured fraction of the energy lies.
+    pub rolloff: f32,
+    /// Geometric over arithmetic mean of the power spectrum, in [0, 1].
+    /// 1 for white noise and near 0 for a pure tone.
+    pub flatness: f32,
+    /// Largest magnitude over the mean magnitude.
+    pub crest: f32,
+    /// Euclidean distance between this and the previous magnitude spectrum.
+    pub flux: f32,
+    /// Slope of the linear regression of magnitude against frequency,
+    /// normalised by the sum of magnitudes, in 1/Hz.
+    pub slope: f32,
+    /// Fraction of successive samples that change sign.
+    pub zero_crossing_rate: f32,
+    /// Root mean square of the frame.
+    pub rms: f32,
+}
+
+/// Computes descriptors from successive frames.
+pub struct DescriptorExtractor {
+    axis: FrequencyAxis,
+    /// Fraction of the energy below the rolloff frequency.
+    rolloff_fraction: f32,
+    /// Magnitudes of the previous frame for the flux.
+    previous_magnitude: Vec<f32>,
+}
+
+impl DescriptorExtractor {
+    /// Returns an extractor for spectra on `axis` measuring the rolloff at
+    /// `rolloff_percent` percent of the energy, typically 85 or 95.
+    pub fn new(rolloff_percent: f32, axis: &FrequencyAxis) -> Self {
+        DescriptorExtractor {
+            axis: *axis,
+            rolloff_fraction: (rolloff_percent / 100.0).clamp(0.0, 1.0),
+            previous_magnitude: Vec::new(),
+        }
+    }
+
+    /// Forgets the previous frame.
+    pub fn reset(&mut self) {
+        self.previous_magnitude.clear();
+    }
+
+    /// Returns the descriptors of a spectrum and the frame it was computed
+    /// from, i.e. RealtimeFft::dft and RealtimeFft::frame.
+    pub fn process(&mut self, spectrum: &[Complex<f32>], frame: &[f32]) -> Descriptors {
+        let magnitude: Vec<f32> = spectrum.iter().map(|bin| bin.norm()).collect();
+        let mut descriptors = spectral_shape(&magnitude, &self.axis);
+        descriptors.rolloff = rolloff(&magnitude, &self.axis, self.rolloff_fraction);
+
+        descriptors.flux = if self.previous_magnitude.len() == magnitude.len() {
+            magnitude
+                .iter()
+                .zip(&self.previous_magnitude)
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>()
+                .sqrt()
+        } else {
+            0.0
+        };
+        self.previous_magnitude = magnitude;
+
+        descriptors.zero_crossing_rate = zero_crossing_rate(frame);
+        descriptors.rms = rms(frame);
+        descriptors
+    }
+}
+
+/// Computes the moments, flatness, crest and slope of a magnitude spectrum.
+fn spectral_shape(magnitude: &[f32], axis: &FrequencyAxis) -> Descriptors {
+    let mut descriptors = Descriptors::default();
+    let total: f32 = magnitude.iter().sum();
+    if total <= 0.0 {
+        return descriptors;
+    }
+    let n = magnitude.len() as f32;
+
+    let frequency = |k: usize| axis.bin_to_hz(k as f32);
+    let moment = |centre: f32, order: i32| {
+        magnitude
+            .iter()
+            .enumerate()
+            .map(|(k, m)| (frequency(k) - centre).powi(order) * m)
+            .sum::<f32>()
+            / total
+    };
+    descriptors.centroid = moment(0.0, 1);
+    descriptors.spread = moment(descriptors.centroid, 2).sqrt();
+    if descriptors.spread > 0.0 {
+        descriptors.skewness = moment(descriptors.centroid, 3) / descriptors.spread.powi(3);
+        descriptors.kurtosis = moment(descriptors.centroid, 4) / descriptors.spread.powi(4);
+    }
+
+    // Flatness in the log domain to avoid underflow of the product.
+    let mean_power = magnitude.iter().map(|m| m * m).sum::<f32>() / n;
+    let mean_log_power = magnitude
+        .iter()
+        .map(|m| (m * m).max(f32::MIN_POSITIVE).ln())
+        .sum::<f32>()
+        / n;
+    descriptors.flatness = (mean_log_power.exp() / mean_power).min(1.0);
+
+    let max = magnitude.iter().cloned().fold(0.0, f32::max);
+    descriptors.crest = max * n / total;
+
+    let mean_frequency = frequency(magnitude.len() - 1) / 2.0;
+    let mean_magnitude = total / n;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for (k, m) in magnitude.iter().enumerate() {
+        let df = frequency(k) - mean_frequency;
+        covariance += df * (m - mean_magnitude);
+        variance += df * df;
+    }
+    if variance > 0.0 {
+        descriptors.slope = covariance / variance / total;
+    }
+    descriptors
+}
+
+/// Returns the frequency below which `fraction` of the energy lies.
+fn rolloff(magnitude: &[f32], axis: &FrequencyAxis, fraction: f32) -> f32 {
+    let total: f32 = magnitude.iter().map(|m| m * m).sum();
+    let target = fraction * total;
+    let mut cumulative = 0.0;
+    for (k, m) in magnitude.iter().enumerate() {
+        cumulative += m * m;
+        if cumulative >= target {
+            return axis.bin_to_hz(k as f32);
+        }
+    }
+    axis.bin_to_hz(magnitude.len().saturating_sub(1) as f32)
+}
+
+/// Returns the fraction of successive samples that change sign.
+pub fn zero_crossing_rate(frame: &[f32]) -> f32 {
+    if frame.len() < 2 {
+        return 0.0;
+    }
+    let crossings = frame
+        .windows(2)
+        .filter(|pair| (pair[0] >= 0.0) != (pair[1] >= 0.0))
+        .count();
+    crossings as f32 / (frame.len() - 1) as f32
+}
+
+/// Returns the root mean square of a frame.
+pub fn rms(frame: &[f32]) -> f32 {
+    if frame.is_empty() {
+        return 0.0;
+    }
+    (frame.iter().map(|x| x * x).sum::<f32>() / frame.len() as f32).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn shape_of_a_single_bin() {
+        let axis = FrequencyAxis::new(1000, 64);
+        let mut spectrum = vec![Complex::new(0.0, 0.0); 33];
+        spectrum[8] = Complex::new(0.0, 2.0);
+        let descriptors = DescriptorExtractor::new(85.0, &axis).process(&spectrum, &[]);
+        assert_eq!(descriptors.centroid, 125.0);
+        assert_eq!(descriptors.spread, 0.0);
+        assert_eq!(descriptors.rolloff, 125.0);
+        assert!(descriptors.flatness < 1e-6);
+        assert_eq!(descriptors.crest, 33.0);
+    }
+
+    #[test]
+    fn shape_of_a_flat_spectrum() {
+        let axis = FrequencyAxis::new(1000, 64);
+        let spectrum = vec![Complex::new(1.0, 0.0); 33];
+        let descriptors = DescriptorExtractor::new(85.0, &axis).process(&spectrum, &[]);
+        assert!((descriptors.centroid - 250.0).abs() < 1e-3);
+        assert!(descriptors.skewness.abs() < 1e-5);
+        assert!((descriptors.flatness - 1.0).abs() < 1e-5);
+        assert!((descriptors.crest - 1.0).abs() < 1e-5);
+        assert!(descriptors.slope.abs() < 1e-9);
+        // 85% of the energy of 33 equal bins needs 29 of them.
+        assert_eq!(descriptors.rolloff, axis.bin_to_hz(28.0));
+        // Standard deviation of 33 equally spaced values.
+        let spread = axis.bin_width() * ((33.0f32 * 33.0 - 1.0) / 12.0).sqrt();
+        assert!((descriptors.spread - spread).abs() < 1e-3);
+    }
+
+    #[test]
+    fn flux_between_frames() {
+        let axis = FrequencyAxis::new(1000, 64);
+        let mut extractor = DescriptorExtractor::new(85.0, &axis);
+        let quiet = vec![Complex::new(1.0, 0.0); 33];
+        let loud = vec![Complex::new(0.0, 2.0); 33];
+        assert_eq!(extractor.process(&quiet, &[]).flux, 0.0);
+        assert!((extractor.process(&loud, &[]).flux - 33f32.sqrt()).abs() < 1e-5);
+        extractor.reset();
+        assert_eq!(extractor.process(&quiet, &[]).flux, 0.0);
+    }
+
+    #[test]
+    fn temporal_descriptors() {
+        let sine: Vec<f32> = (0..1000)
+            .map(|n| (2.0 * PI * 50.0 * n as f32 / 1000.0 + 0.1).sin())
+            .collect();
+        assert!((rms(&sine) - 0.5f32.sqrt()).abs() < 1e-4);
+        // Two crossings per period less the one at the end of the last.
+        assert!((zero_crossing_rate(&sine) - 99.0 / 999.0).abs() < 1e-6);
+        assert_eq!(zero_crossing_rate(&[1.0, -1.0, 1.0]), 1.0);
+        assert_eq!(rms(&[]), 0.0);
+    }
+}
@@ -0,0 +1,399 @@
+//! Harmonic distortion and noise measurements of a sine stimulus. The
+//! fundamental and each harmonic are measured by summing the power over the
+//! main lobe of the window so the result doesn't depend on where the tone
+//! falls between bins.
+
+use crate::frequency_axis::FrequencyAxis;
+use crate::realtime_fft::realtime_fft_src::SampleTap;
+use crate::sample_window::SampleWindow;
+use crate::window::{self, WindowFunction};
+use realfft::{RealFftPlanner, RealToComplex};
+use rustfft::num_complex::Complex;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Parameters of a distortion measurement.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DistortionConfig {
+    /// Number of samples analysed per measurement.
+    pub fft_size: usize,
+    /// Window applied before the fft. Its sidelobes must lie below the
+    /// distortion being measured.
+    pub window: WindowFunction,
+    /// Frequency of the stimulus. Found from the largest peak if None.
+    pub fundamental: Option<f32>,
+    /// Highest harmonic order included in the THD.
+    pub harmonics: usize,
+    /// Measurement bandwidth. Harmonics and noise outside it are ignored.
+    pub min_hz: f32,
+    pub max_hz: f32,
+}
+
+impl Default for DistortionConfig {
+    fn default() -> Self {
+        DistortionConfig {
+            fft_size: 16384,
+            window: WindowFunction::BlackmanHarris,
+            fundamental: None,
+            harmonics: 10,
+            min_hz: 20.0,
+            max_hz: 20000.0,
+        }
+    }
+}
+
+/// Level of a single harmonic.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Harmonic {
+    /// Harmonic number, 2 being the first overtone.
+    pub order: usize,
+    /// Nominal frequency in Hz.
+    pub frequency: f32,
+    /// Peak amplitude.
+    pub amplitude: f32,
+    /// Level relative to the fundamental in dB.
+    pub level_dbc: f32,
+}
+
+/// Result of a distortion measurement. Ratios are amplitude ratios.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DistortionMeasurement {
+    /// Measured frequency of the fundamental in Hz.
+    pub fundamental: f32,
+    /// Peak amplitude of the fundamental.
+    pub amplitude: f32,
+    /// Harmonics within the measurement bandwidth.
+    pub harmonics: Vec<Harmonic>,
+    /// Total harmonic distortion.
+    pub thd: f32,
+    /// Total harmonic distortion plus noise.
+    pub thd_n: f32,
+    /// Signal to noise ratio excluding harmonics in dB.
+    pub snr_db: f32,
+    /// Signal to noise and distortion ratio in dB.
+    pub sinad_db: f32,
+    /// Effective number of bits derived from the SINAD.
+    pub enob: f32,
+}
+
+impl DistortionMeasurement {
+    /// Returns the THD in dB.
+    pub fn thd_db(&self) -> f32 {
+        20.0 * self.thd.log10()
+    }
+
+    /// Returns the THD+N in dB.
+    pub fn thd_n_db(&self) -> f32 {
+        20.0 * self.thd_n.log10()
+    }
+}
+
+/// Measures the distortion of a sine stimulus.
+pub struct DistortionAnalyzer {
+    config: DistortionConfig,
+    axis: FrequencyAxis,
+    /// Most recent fft_size samples.
+    frame: SampleWindow<f32>,
+    real_to_complex: Arc<dyn RealToComplex<f32>>,
+    window: Vec<f32>,
+    indata: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    /// Power of each bin, scaled so a sinusoid's main lobe sums to its
+    /// mean square.
+    power: Vec<f64>,
+}
+
+impl DistortionAnalyzer {
+    /// Returns an analyzer for a signal at `sample_rate`, planning its fft
+    /// with the given planner.
+    pub fn new(
+        fft_planner: &Rc<RefCell<RealFftPlanner<f32>>>,
+        sample_rate: u32,
+        config: DistortionConfig,
+    ) -> Self {
+        let real_to_complex = fft_planner.borrow_mut().plan_fft_forward(config.fft_size);
+        let spectrum = real_to_complex.make_output_vec();
+        DistortionAnalyzer {
+            config,
+            axis: FrequencyAxis::new(sample_rate, config.fft_size),
+            frame: SampleWindow::new(config.fft_size),
+            window: config.window.coefficients(config.fft_size),
+            indata: real_to_complex.make_input_vec(),
+            scratch: real_to_complex.make_scratch_vec(),
+            power: vec![0.0; spectrum.len()],
+            spectrum,
+            real_to_complex,
+        }
+    }
+
+    /// Returns the configuration of the analyzer.
+    pub fn config(&self) -> &DistortionConfig {
+        &self.config
+    }
+
+    /// Adds samples, keeping only the most recent frame.
+    pub fn push(&mut self, samples: &[f32]) {
+        self.frame.push(samples);
+    }
+
+    /// Adds every sample available in a tap of the audio source.
+    pub fn push_from(&mut self, tap: &mut SampleTap) {
+        tap.drain(|samples| self.frame.push(samples));
+    }
+
+    /// Forgets the buffered samples.
+    pub fn reset(&mut self) {
+        self.frame.reset();
+    }
+
+    /// Measures the most recent frame. Returns None until a full frame has
+    /// been received or if no fundamental is found in the bandwidth.
+    pub fn measure(&mut self) -> Option<DistortionMeasurement> {
+        if !self.frame.is_full() {
+            return None;
+        }
+        self.compute_power();
+
+        // Bins in the bandwidth, excluding the main lobe of DC.
+        let half_width = self.config.window.main_lobe_half_width();
+        let first = (self.axis.hz_to_bin(self.config.min_hz).ceil() as usize).max(half_width + 1);
+        let last =
+            (self.axis.hz_to_bin(self.config.max_hz).floor() as usize).min(self.power.len() - 1);
+        if first + 2 * half_width > last {
+            return None;
+        }
+        let mut used = vec![false; self.power.len()];
+
+        let peak = match self.config.fundamental {
+            Some(hz) => {
+                let centre = self.axis.nearest_bin(hz).clamp(first, last);
+                self.largest_bin(
+                    centre.saturating_sub(half_width).max(first),
+                    (centre + half_width).min(last),
+                )
+            }
+            None => self.largest_bin(first, last),
+        };
+        let (fundamental_power, centroid) =
+            self.lobe_power(peak, half_width, first, last, &mut used);
+        if fundamental_power <= 0.0 {
+            return None;
+        }
+        let fundamental = self.axis.bin_to_hz(centroid as f32);
+
+        let mut harmonics = Vec::new();
+        let mut harmonic_power = 0.0;
+        for order in 2..=self.config.harmonics {
+            let frequency = order as f32 * fundamental;
+            let bin = self.axis.hz_to_bin(frequency).round() as usize;
+            if bin > last {
+                break;
+            }
+            let (power, _) = self.lobe_power(bin, half_width, first, last, &mut used);
+            harmonic_power += power;
+            harmonics.push(Harmonic {
+                order,
+                frequency,
+                amplitude: (2.0 * power).sqrt() as f32,
+                level_dbc: (10.0 * (power / fundamental_power).log10()) as f32,
+            });
+        }
+
+        let total: f64 = self.power[first..=last].iter().sum();
+        let noise_and_distortion = (total - fundamental_power).max(f64::MIN_POSITIVE);
+        let noise = (noise_and_distortion - harmonic_power).max(f64::MIN_POSITIVE);
+        let sinad_db = (10.0 * (total / noise_and_distortion).log10()) as f32;
+        Some(DistortionMeasurement {
+            fundamental,
+            amplitude: (2.0 * fundamental_power).sqrt() as f32,
+            harmonics,
+            thd: (harmonic_power / fundamental_power).sqrt() as f32,
+            thd_n: (noise_and_distortion / fundamental_power).sqrt() as f32,
+            snr_db: (10.0 * (fundamental_power / noise).log10()) as f32,
+            sinad_db,
+            enob: (sinad_db - 1.76) / 6.02,
+        })
+    }
+
+    /// Computes the scaled power spectrum of the windowed frame.
+    fn compute_power(&mut self) {
+        for ((input, &sample), &w) in self
+            .indata
+            .iter_mut()
+            .zip(self.frame.samples())
+            .zip(&self.window)
+        {
+            *input = sample * w;
+        }
+        self.real_to_complex
+            .process_with_scratch(&mut self.indata, &mut self.spectrum, &mut self.scratch)
+            .unwrap();
+
+        // By Parseval, dividing by the window's power gain makes the power
+        // of a sinusoid's lobe and of broadband noise both come out as mean
+        // squares. Every bin but DC and Nyquist is doubled.
+        let len = self.frame.capacity();
+        let norm = 1.0 / (len as f64 * window::power_gain(&self.window) as f64);
+        for (k, (power, bin)) in self.power.iter_mut().zip(&self.spectrum).enumerate() {
+            let one_sided = if k == 0 || 2 * k == len { 1.0 } else { 2.0 };
+            *power = one_sided * norm * bin.norm_sqr() as f64;
+        }
+    }
+
+    /// Returns the bin with the most power in [first, last].
+    fn largest_bin(&self, first: usize, last: usize) -> usize {
+        (first..=last)
+            .max_by(|&a, &b| self.power[a].total_cmp(&self.power[b]))
+            .unwrap_or(first)
+    }
+
+    /// Sums the power of the main lobe centred on `centre`, skipping bins
+    /// already attributed to another component. Returns the power and its
+    /// power weighted centroid in bins.
+    fn lobe_power(
+        &self,
+        centre: usize,
+        half_width: usize,
+        first: usize,
+        last: usize,
+        used: &mut [bool],
+    ) -> (f64, f64) {
+        let (mut power, mut moment) = (0.0, 0.0);
+        let lower = centre.saturating_sub(half_width).max(first);
+        let upper = (centre + half_width).min(last);
+        for (k, used) in used.iter_mut().enumerate().take(upper + 1).skip(lower) {
+            if !*used {
+                *used = true;
+                power += self.power[k];
+                moment += k as f64 * self.power[k];
+            }
+        }
+        let centroid = if power > 0.0 {
+            moment / power
+        } else {
+            centre as f64
+        };
+        (power, centroid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::f32::consts::PI;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    /// Returns a tone with the given amplitude of each harmonic, starting
+    /// with the fundamental.
+    fn tone(frequency: f32, amplitudes: &[f32], len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| {
+                let t = n as f32 / SAMPLE_RATE as f32;
+                amplitudes
+                    .iter()
+                    .enumerate()
+                    .map(|(h, a)| a * (2.0 * PI * (h + 1) as f32 * frequency * t).sin())
+                    .sum()
+            })
+            .collect()
+    }
+
+    fn measure(samples: &[f32], config: DistortionConfig) -> DistortionMeasurement {
+        let planner = Rc::new(RefCell::new(RealFftPlanner::new()));
+        let mut analyzer = DistortionAnalyzer::new(&planner, SAMPLE_RATE, config);
+        analyzer.push(samples);
+        analyzer.measure().unwrap()
+    }
+
+    #[test]
+    fn harmonic_levels() {
+        // -40 dBc second and -60 dBc third harmonic, between bins.
+        let config = DistortionConfig::default();
+        let samples = tone(997.0, &[0.5, 0.005, 0.0005], config.fft_size);
+        let measurement = measure(&samples, config);
+
+        assert_abs_diff_eq!(measurement.fundamental, 997.0, epsilon = 0.1);
+        assert_abs_diff_eq!(measurement.amplitude, 0.5, epsilon = 0.0005);
+        assert_abs_diff_eq!(measurement.harmonics[0].level_dbc, -40.0, epsilon = 0.1);
+        assert_abs_diff_eq!(measurement.harmonics[1].level_dbc, -60.0, epsilon = 0.1);
+        assert_abs_diff_eq!(measurement.thd, 0.01005, epsilon = 0.0001);
+        // No noise so the THD+N is the THD.
+        assert_abs_diff_eq!(measurement.thd_n_db(), measurement.thd_db(), epsilon = 0.1);
+    }
+
+    #[test]
+    fn given_fundamental() {
+        // A louder interferer mustn't be taken for the fundamental.
+        let config = DistortionConfig {
+            fundamental: Some(1000.0),
+            ..DistortionConfig::default()
+        };
+        let mut samples = tone(1000.0, &[0.1, 0.001], config.fft_size);
+        for (sample, interferer) in samples
+            .iter_mut()
+            .zip(tone(5500.0, &[0.5], config.fft_size))
+        {
+            *sample += interferer;
+        }
+        let measurement = measure(&samples, config);
+        assert_abs_diff_eq!(measurement.fundamental, 1000.0, epsilon = 0.1);
+        assert_abs_diff_eq!(measurement.harmonics[0].level_dbc, -40.0, epsilon = 0.1);
+    }
+
+    #[test]
+    fn signal_to_noise() {
+        // Uniform noise in [-a, a] has a mean square of a^2 / 3.
+        let config = DistortionConfig {
+            max_hz: SAMPLE_RATE as f32 / 2.0,
+            harmonics: 5,
+            ..DistortionConfig::default()
+        };
+        let mut rng = StdRng::seed_from_u64(1);
+        let noise_amplitude = 0.01;
+        let mut samples = tone(1234.5, &[0.5], config.fft_size);
+        for sample in samples.iter_mut() {
+            *sample += rng.gen_range(-noise_amplitude..noise_amplitude);
+        }
+        let measurement = measure(&samples, config);
+        let expected =
+            10.0 * ((0.5f32 * 0.5 / 2.0) / (noise_amplitude * noise_amplitude / 3.0)).log10();
+        assert_abs_diff_eq!(measurement.snr_db, expected, epsilon = 0.3);
+        assert_abs_diff_eq!(measurement.sinad_db, expected, epsilon = 0.3);
+    }
+
+    #[test]
+    fn effective_number_of_bits() {
+        // A full scale sine quantised to 12 bits.
+        let config = DistortionConfig {
+            max_hz: SAMPLE_RATE as f32 / 2.0,
+            ..DistortionConfig::default()
+        };
+        let step = 2.0 / 4096.0;
+        let samples: Vec<f32> = tone(1234.5, &[1.0 - step], config.fft_size)
+            .iter()
+            .map(|x| (x / step).round() * step)
+            .collect();
+        let measurement = measure(&samples, config);
+        assert_abs_diff_eq!(measurement.enob, 12.0, epsilon = 0.2);
+    }
+
+    #[test]
+    fn nan_samples_do_not_panic() {
+        let planner = Rc::new(RefCell::new(RealFftPlanner::new()));
+        let config = DistortionConfig {
+            fft_size: 1024,
+            ..DistortionConfig::default()
+        };
+        let mut analyzer = DistortionAnalyzer::new(&planner, SAMPLE_RATE, config);
+        let mut samples = tone(997.0, &[0.5], 1024);
+        samples[100] = f32::NAN;
+        analyzer.push(&samples);
+        let _ = analyzer.measure();
+    }
+}
@@ -0,0 +1,396 @@
+//! DTMF decoding with a Goertzel bank. Each block is checked for one tone
+//! of each group with the level, twist and purity required by ITU-T Q.24,
+//! and key presses are reported once the tone and the pause after it have
+//! lasted long enough.
+
+use crate::error::ConfigError;
+use crate::goertzel::{GoertzelBank, GoertzelBlock, GoertzelConfig};
+use crate::realtime_fft::realtime_fft_src::SampleTap;
+use crate::window::WindowFunction;
+use std::time::Duration;
+
+/// Frequencies of the low (row) group in Hz.
+pub const ROW_FREQUENCIES: [f32; 4] = [697.0, 770.0, 852.0, 941.0];
+/// Frequencies of the high (column) group in Hz.
+pub const COLUMN_FREQUENCIES: [f32; 4] = [1209.0, 1336.0, 1477.0, 1633.0];
+
+/// Frequency deviation that must be tolerated (Q.24). Each tone is also
+/// measured this far either side of its nominal frequency.
+const FREQUENCY_TOLERANCE: f32 = 0.015;
+
+const KEYS: [[char; 4]; 4] = [
+    ['1', '2', '3', 'A'],
+    ['4', '5', '6', 'B'],
+    ['7', '8', '9', 'C'],
+    ['*', '0', '#', 'D'],
+];
+
+/// Parameters of the DTMF decoder.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DtmfConfig {
+    /// Length of the blocks analysed. Must be long enough to separate
+    /// neighbouring tones 73 Hz apart.
+    pub block: Duration,
+    /// Time between the start of two blocks.
+    pub hop: Duration,
+    /// Lowest accepted amplitude of each tone relative to full scale in dB.
+    pub min_level_db: f32,
+    /// Largest excess of the high group over the low group in dB.
+    pub max_forward_twist_db: f32,
+    /// Largest excess of the low group over the high group in dB.
+    pub max_reverse_twist_db: f32,
+    /// Margin by which each tone must exceed the other tones of its group
+    /// in dB.
+    pub min_group_margin_db: f32,
+    /// Smallest fraction of the block's power the two tones must hold.
+    pub min_tone_ratio: f32,
+    /// Shortest tone reported. With the defaults, tones of 40 ms are always
+    /// accepted and tones of 20 ms or less rejected.
+    pub min_tone: Duration,
+    /// Shortest pause ending a key press. Shorter dropouts are bridged.
+    pub min_pause: Duration,
+}
+
+impl Default for DtmfConfig {
+    fn default() -> Self {
+        DtmfConfig {
+            block: Duration::from_millis(20),
+            hop: Duration::from_millis(10),
+            min_level_db: -36.0,
+            max_forward_twist_db: 4.0,
+            max_reverse_twist_db: 8.0,
+            min_group_margin_db: 6.0,
+            min_tone_ratio: 0.6,
+            min_tone: Duration::from_millis(40),
+            min_pause: Duration::from_millis(20),
+        }
+    }
+}
+
+/// Start or end of a key press.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DtmfEvent {
+    Pressed {
+        key: char,
+        /// Index in the stream of the first sample of the first block
+        /// holding the tone.
+        sample_index: u64,
+    },
+    Released {
+        key: char,
+        /// Index in the stream just after the last block holding the tone.
+        sample_index: u64,
+        /// Estimated length of the tone.
+        duration: Duration,
+    },
+}
+
+/// A key being held.
+struct Press {
+    key: char,
+    start: u64,
+    end: u64,
+    /// Consecutive blocks without the key.
+    missing: usize,
+}
+
+/// A key not yet held long enough to be reported.
+struct Candidate {
+    key: char,
+    start: u64,
+    blocks: usize,
+}
+
+/// Applies the timing rules to the key found in each block.
+struct KeyTracker {
+    sample_rate: u32,
+    block_len: usize,
+    /// Consecutive blocks needed to accept a tone and to end a press.
+    tone_blocks: usize,
+    pause_blocks: usize,
+    press: Option<Press>,
+    candidate: Option<Candidate>,
+}
+
+impl KeyTracker {
+    fn update<F: FnMut(DtmfEvent)>(
+        &mut self,
+        block: &GoertzelBlock,
+        key: Option<char>,
+        on_event: &mut F,
+    ) {
+        let block_end = block.sample_index + self.block_len as u64;
+        if let Some(press) = &mut self.press {
+            if key == Some(press.key) {
+                press.end = block_end;
+                press.missing = 0;
+                self.candidate = None;
+                return;
+            }
+            press.missing += 1;
+            if press.missing >= self.pause_blocks {
+                on_event(DtmfEvent::Released {
+                    key: press.key,
+                    sample_index: press.end,
+                    duration: Duration::from_secs_f64(
+                        (press.end - press.start) as f64 / self.sample_rate as f64,
+                    ),
+                });
+                self.press = None;
+            }
+        }
+
+        self.candidate = match (self.candidate.take(), key) {
+            (Some(candidate), Some(key)) if candidate.key == key => Some(Candidate {
+                blocks: candidate.blocks + 1,
+                ..candidate
+            }),
+            (_, Some(key)) => Some(Candidate {
+                key,
+                start: block.sample_index,
+                blocks: 1,
+            }),
+            (_, None) => None,
+        };
+        if self.press.is_none() {
+            if let Some(candidate) = &self.candidate {
+                if candidate.blocks >= self.tone_blocks {
+                    on_event(DtmfEvent::Pressed {
+                        key: candidate.key,
+                        sample_index: candidate.start,
+                    });
+                    self.press = Some(Press {
+                        key: candidate.key,
+                        start: candidate.start,
+                        end: block_end,
+                        missing: 0,
+                    });
+                    self.candidate = None;
+                }
+            }
+        }
+    }
+}
+
+/// Decodes DTMF key presses from a sample stream.
+pub struct DtmfDecoder {
+    config: DtmfConfig,
+    bank: GoertzelBank,
+    tracker: KeyTracker,
+}
+
+impl DtmfDecoder {
+    /// Returns a decoder for a signal at `sample_rate`. The block and hop
+    /// must be at least a sample long and the hop no longer than the block.
+    pub fn new(sample_rate: u32, config: DtmfConfig) -> Result<Self, ConfigError> {
+        let to_samples = |duration: Duration| duration.as_secs_f64() * sample_rate as f64;
+        let block_len = to_samples(config.block).round() as usize;
+        let hop = to_samples(config.hop).round() as usize;
+        let frequencies: Vec<f32> = ROW_FREQUENCIES
+            .iter()
+            .chain(&COLUMN_FREQUENCIES)
+            .flat_map(|&hz| {
+                [
+                    hz * (1.0 - FREQUENCY_TOLERANCE),
+                    hz,
+                    hz * (1.0 + FREQUENCY_TOLERANCE),
+                ]
+            })
+            .collect();
+        let bank = GoertzelBank::new(
+            sample_rate,
+            &frequencies,
+            GoertzelConfig {
+                block_len,
+                hop,
+                window: WindowFunction::Rectangular,
+            },
+        )?;
+
+        // A tone spanning n blocks lasts (n - 1) * hop + block_len.
+        let tone = to_samples(config.min_tone) - block_len as f64;
+        let tone_blocks = (tone / hop as f64).ceil().max(0.0) as usize + 1;
+        let pause_blocks = ((to_samples(config.min_pause) / hop as f64).ceil() as usize).max(1);
+        Ok(DtmfDecoder {
+            config,
+            bank,
+            tracker: KeyTracker {
+                sample_rate,
+                block_len,
+                tone_blocks,
+                pause_blocks,
+                press: None,
+                candidate: None,
+            },
+        })
+    }
+
+    /// Returns the configuration of the decoder.
+    pub fn config(&self) -> &DtmfConfig {
+        &self.config
+    }
+
+    /// Adds samples, calling `on_event` with every key pressed or released.
+    pub fn push<F: FnMut(DtmfEvent)>(&mut self, samples: &[f32], mut on_event: F) {
+        let config = &self.config;
+        let tracker = &mut self.tracker;
+        self.bank.push(samples, |block| {
+            tracker.update(block, classify(config, block), &mut on_event)
+        });
+    }
+
+    /// Adds every sample available in a tap of the audio source.
+    pub fn push_from<F: FnMut(DtmfEvent)>(&mut self, tap: &mut SampleTap, mut on_event: F) {
+        tap.drain(|samples| self.push(samples, &mut on_event));
+    }
+
+    /// Forgets any key in progress without reporting it.
+    pub fn reset(&mut self) {
+        self.bank.reset();
+        self.tracker.press = None;
+        self.tracker.candidate = None;
+    }
+}
+
+/// Returns the key whose tones a block holds, if the tones pass the level,
+/// twist and purity checks.
+fn classify(config: &DtmfConfig, block: &GoertzelBlock) -> Option<char> {
+    // Strongest of the measurements around each tone.
+    let mut amplitudes = [0.0; 8];
+    for (amplitude, probes) in amplitudes.iter_mut().zip(block.amplitudes.chunks(3)) {
+        *amplitude = probes.iter().cloned().fold(0.0, f32::max);
+    }
+    let (rows, columns) = amplitudes.split_at(ROW_FREQUENCIES.len());
+    let (row, row_amplitude) = strongest(rows, config.min_group_margin_db)?;
+    let (column, column_amplitude) = strongest(columns, config.min_group_margin_db)?;
+
+    let min_amplitude = 10f32.powf(config.min_level_db / 20.0);
+    if row_amplitude < min_amplitude || column_amplitude < min_amplitude {
+        return None;
+    }
+    let twist = 20.0 * (column_amplitude / row_amplitude).log10();
+    if twist > config.max_forward_twist_db || -twist > config.max_reverse_twist_db {
+        return None;
+    }
+    // Each tone holds half its squared amplitude.
+    let tone_power = (row_amplitude * row_amplitude + column_amplitude * column_amplitude) / 2.0;
+    if tone_power < config.min_tone_ratio * block.mean_square {
+        return None;
+    }
+    Some(KEYS[row][column])
+}
+
+/// Returns the index and amplitude of the largest amplitude if it exceeds
+/// every other one by `margin_db`.
+fn strongest(amplitudes: &[f32], margin_db: f32) -> Option<(usize, f32)> {
+    let (index, &largest) = amplitudes
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+    let ceiling = largest * 10f32.powf(-margin_db / 20.0);
+    let clear = amplitudes
+        .iter()
+        .enumerate()
+        .all(|(i, &amplitude)| i == index || amplitude <= ceiling);
+    if clear {
+        Some((index, largest))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    /// Returns `ms` milliseconds of the tones of `key` at 8 kHz with the
+    /// given amplitudes relative to full scale, or silence for a space.
+    fn tone(key: char, ms: usize, row_db: f32, column_db: f32) -> Vec<f32> {
+        let len = ms * 8;
+        if key == ' ' {
+            return vec![0.0; len];
+        }
+        let (row, column) = (0..16)
+            .map(|i| (i / 4, i % 4))
+            .find(|&(row, column)| KEYS[row][column] == key)
+            .unwrap();
+        let row_amplitude = 10f32.powf(row_db / 20.0);
+        let column_amplitude = 10f32.powf(column_db / 20.0);
+        (0..len)
+            .map(|n| {
+                let t = n as f32 / 8000.0;
+                row_amplitude * (2.0 * PI * ROW_FREQUENCIES[row] * t).sin()
+                    + column_amplitude * (2.0 * PI * COLUMN_FREQUENCIES[column] * t).sin()
+            })
+            .collect()
+    }
+
+    fn decode(samples: &[f32]) -> Vec<DtmfEvent> {
+        let mut decoder = DtmfDecoder::new(8000, DtmfConfig::default()).unwrap();
+        let mut events = Vec::new();
+        decoder.push(samples, |event| events.push(event));
+        events
+    }
+
+    fn pressed_keys(events: &[DtmfEvent]) -> String {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                DtmfEvent::Pressed { key, .. } => Some(*key),
+                DtmfEvent::Released { .. } => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decodes_every_key() {
+        let mut samples = Vec::new();
+        for key in "123A456B789C*0#D".chars() {
+            samples.extend(tone(key, 50, -10.0, -10.0));
+            samples.extend(tone(' ', 50, 0.0, 0.0));
+        }
+        let events = decode(&samples);
+        assert_eq!(pressed_keys(&events), "123A456B789C*0#D");
+        assert_eq!(events.len(), 32);
+
+        match events[1] {
+            DtmfEvent::Released { key, duration, .. } => {
+                assert_eq!(key, '1');
+                assert!((duration.as_secs_f32() - 0.05).abs() <= 0.01);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn short_tones_are_rejected() {
+        let mut samples = tone('5', 40, -10.0, -10.0);
+        samples.extend(tone(' ', 50, 0.0, 0.0));
+        samples.extend(tone('6', 20, -10.0, -10.0));
+        samples.extend(tone(' ', 50, 0.0, 0.0));
+        assert_eq!(pressed_keys(&decode(&samples)), "5");
+    }
+
+    #[test]
+    fn twist_and_level_limits_are_applied() {
+        let mut samples = tone('7', 60, -10.0, -4.0);
+        samples.extend(tone(' ', 50, 0.0, 0.0));
+        samples.extend(tone('8', 60, -4.0, -14.0));
+        samples.extend(tone(' ', 50, 0.0, 0.0));
+        samples.extend(tone('9', 60, -40.0, -40.0));
+        samples.extend(tone(' ', 50, 0.0, 0.0));
+        samples.extend(tone('0', 60, -10.0, -7.0));
+        samples.extend(tone(' ', 50, 0.0, 0.0));
+        assert_eq!(pressed_keys(&decode(&samples)), "0");
+    }
+
+    #[test]
+    fn zero_hop_is_rejected() {
+        let config = DtmfConfig {
+            hop: Duration::ZERO,
+            ..DtmfConfig::default()
+        };
+        assert!(DtmfDecoder::new(8000, config).is_err());
+    }
+}
@@ -0,0 +1,33 @@
+//! Error returned when an analysis is created with an invalid
+//! configuration.
+
+use std::error::Error;
+use std::fmt;
+
+/// Describes which parameter of a configuration is invalid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    message: String,
+}
+
+impl ConfigError {
+    /// Returns a new ConfigError with the given description.
+    pub fn new<M: Into<String>>(message: M) -> Self {
+        ConfigError {
+            message: message.into(),
+        }
+    }
+
+    /// Returns the description of the error.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration: {}", self.message)
+    }
+}
+
+impl Error for ConfigError {}
@@ -0,0 +1,82 @@
+//! Mapping between the bins of a real fft and frequencies in Hz.
+
+/// Frequency axis of a real fft of `fft_size` samples.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrequencyAxis {
+    sample_rate: u32,
+    fft_size: usize,
+}
+
+impl FrequencyAxis {
+    /// Returns the axis of an fft of `fft_size` samples taken at `sample_rate`.
+    pub fn new(sample_rate: u32, fft_size: usize) -> Self {
+        FrequencyAxis {
+            sample_rate,
+            fft_size,
+        }
+    }
+
+    /// Returns the sample rate of the transformed signal.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Returns the length of the fft.
+    pub fn fft_size(&self) -> usize {
+        self.fft_size
+    }
+
+    /// Returns the number of bins in the spectrum of a real signal.
+    pub fn num_bins(&self) -> usize {
+        self.fft_size / 2 + 1
+    }
+
+    /// Returns the spacing between bins in Hz.
+    pub fn bin_width(&self) -> f32 {
+        self.sample_rate as f32 / self.fft_size as f32
+    }
+
+    /// Returns the highest representable frequency in Hz.
+    pub fn nyquist(&self) -> f32 {
+        self.sample_rate as f32 / 2.0
+    }
+
+    /// Returns the frequency of a (possibly fractional) bin in Hz.
+    pub fn bin_to_hz(&self, bin: f32) -> f32 {
+        bin * self.bin_width()
+    }
+
+    /// Returns the fractional bin of a frequency in Hz.
+    pub fn hz_to_bin(&self, hz: f32) -> f32 {
+        hz / self.bin_width()
+    }
+
+    /// Returns the bin closest to a frequency in Hz, clamped to the spectrum.
+    pub fn nearest_bin(&self, hz: f32) -> usize {
+        (self.hz_to_bin(hz).round().max(0.0) as usize).min(self.num_bins() - 1)
+    }
+
+    /// Returns the frequency of every bin in Hz.
+    pub fn frequencies(&self) -> impl Iterator<Item = f32> {
+        let axis = *self;
+        (0..self.num_bins()).map(move |bin| axis.bin_to_hz(bin as f32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_bins_to_hz() {
+        let axis = FrequencyAxis::new(48000, 1024);
+        assert_eq!(axis.num_bins(), 513);
+        assert_eq!(axis.bin_width(), 46.875);
+        assert_eq!(axis.bin_to_hz(512.0), axis.nyquist());
+        assert_eq!(axis.hz_to_bin(1000.0), 1000.0 / 46.875);
+        assert_eq!(axis.nearest_bin(1000.0), 21);
+        assert_eq!(axis.nearest_bin(-5.0), 0);
+        assert_eq!(axis.nearest_bin(30000.0), 512);
+        assert_eq!(axis.frequencies().nth(2), Some(93.75));
+    }
+}
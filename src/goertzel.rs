@@ -0,0 +1,233 @@
+//! Bank of Goertzel filters measuring a handful of frequencies on blocks of
+//! the time signal. Much cheaper than an fft when only a few frequencies
+//! matter. Samples come from a tap of a RealtimeFftSrc.
+
+use crate::error::ConfigError;
+use crate::realtime_fft::realtime_fft_src::SampleTap;
+use crate::window::{self, WindowFunction};
+use std::f64::consts::PI;
+
+/// Parameters of a Goertzel bank.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GoertzelConfig {
+    /// Number of samples per block. Frequencies closer than about
+    /// sample_rate / block_len can't be told apart.
+    pub block_len: usize,
+    /// Number of samples between the start of two blocks.
+    pub hop: usize,
+    pub window: WindowFunction,
+}
+
+impl GoertzelConfig {
+    /// Checks that the hop is between 1 and the block length.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.hop == 0 || self.hop > self.block_len {
+            return Err(ConfigError::new("hop must be between 1 and block_len"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for GoertzelConfig {
+    fn default() -> Self {
+        GoertzelConfig {
+            block_len: 1024,
+            hop: 1024,
+            window: WindowFunction::Rectangular,
+        }
+    }
+}
+
+/// Result of a block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GoertzelBlock {
+    /// Index in the stream of the first sample of the block.
+    pub sample_index: u64,
+    /// Peak amplitude of a sinusoid at each frequency of the bank.
+    pub amplitudes: Vec<f32>,
+    /// Window weighted mean square of the block. A sinusoid of amplitude A
+    /// gives A * A / 2.
+    pub mean_square: f32,
+}
+
+/// Measures the amplitude of fixed frequencies block by block.
+pub struct GoertzelBank {
+    config: GoertzelConfig,
+    frequencies: Vec<f32>,
+    /// 2 cos(w) of each frequency.
+    coefficients: Vec<f64>,
+    window: Vec<f32>,
+    /// Scales |X| to the amplitude of a sinusoid.
+    norm: f64,
+    /// Sum of the squared window.
+    power_gain: f64,
+    /// Samples not yet part of a complete block.
+    pending: Vec<f32>,
+    /// Index in the stream of the first pending sample.
+    pending_index: u64,
+    block: GoertzelBlock,
+}
+
+impl GoertzelBank {
+    /// Returns a bank measuring `frequencies` in Hz of a signal at
+    /// `sample_rate`.
+    pub fn new(
+        sample_rate: u32,
+        frequencies: &[f32],
+        config: GoertzelConfig,
+    ) -> Result<Self, ConfigError> {
+        config.validate()?;
+        let coefficients = frequencies
+            .iter()
+            .map(|&hz| 2.0 * (2.0 * PI * hz as f64 / sample_rate as f64).cos())
+            .collect();
+        let window = config.window.coefficients(config.block_len);
+        let norm = 2.0 / window::coherent_gain(&window) as f64;
+        let power_gain = window::power_gain(&window) as f64;
+        Ok(GoertzelBank {
+            frequencies: frequencies.to_vec(),
+            coefficients,
+            window,
+            norm,
+            power_gain,
+            pending: Vec::with_capacity(config.block_len * 2),
+            pending_index: 0,
+            block: GoertzelBlock {
+                sample_index: 0,
+                amplitudes: vec![0.0; frequencies.len()],
+                mean_square: 0.0,
+            },
+            config,
+        })
+    }
+
+    /// Returns the configuration of the bank.
+    pub fn config(&self) -> &GoertzelConfig {
+        &self.config
+    }
+
+    /// Returns the frequencies measured in Hz.
+    pub fn frequencies(&self) -> &[f32] {
+        &self.frequencies
+    }
+
+    /// Adds samples, calling `on_block` with every block that becomes
+    /// complete.
+    pub fn push<F: FnMut(&GoertzelBlock)>(&mut self, samples: &[f32], mut on_block: F) {
+        let block_len = self.config.block_len;
+        for chunk in samples.chunks(block_len) {
+            self.pending.extend_from_slice(chunk);
+            while self.pending.len() >= block_len {
+                self.process_block();
+                on_block(&self.block);
+                self.pending.drain(..self.config.hop);
+                self.pending_index += self.config.hop as u64;
+            }
+        }
+    }
+
+    /// Adds every sample available in a tap of the audio source.
+    pub fn push_from<F: FnMut(&GoertzelBlock)>(&mut self, tap: &mut SampleTap, mut on_block: F) {
+        tap.drain(|samples| self.push(samples, &mut on_block));
+    }
+
+    /// Forgets the pending samples. Sample indices restart from 0.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+        self.pending_index = 0;
+    }
+
+    /// Runs the filters over the first block_len pending samples.
+    fn process_block(&mut self) {
+        let block = &self.pending[..self.config.block_len];
+        for (amplitude, &coefficient) in self.block.amplitudes.iter_mut().zip(&self.coefficients) {
+            let (mut s1, mut s2) = (0.0, 0.0);
+            for (&sample, &w) in block.iter().zip(&self.window) {
+                let s = (sample * w) as f64 + coefficient * s1 - s2;
+                s2 = s1;
+                s1 = s;
+            }
+            let power = s1 * s1 + s2 * s2 - coefficient * s1 * s2;
+            *amplitude = (self.norm * power.max(0.0).sqrt()) as f32;
+        }
+
+        let energy: f64 = block
+            .iter()
+            .zip(&self.window)
+            .map(|(&sample, &w)| ((sample * w) as f64).powi(2))
+            .sum();
+        self.block.mean_square = (energy / self.power_gain) as f32;
+        self.block.sample_index = self.pending_index;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime_fft::realtime_fft_src::SrcInfo;
+
+    fn sine(hz: f64, amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| amplitude * (2.0 * PI * hz * n as f64 / 8000.0).sin() as f32)
+            .collect()
+    }
+
+    #[test]
+    fn measures_sine_amplitudes() {
+        // 1000 Hz is a whole number of cycles per block, 1210 Hz isn't.
+        let config = GoertzelConfig {
+            block_len: 800,
+            hop: 800,
+            window: WindowFunction::Hann,
+        };
+        let mut bank = GoertzelBank::new(8000, &[1000.0, 1210.0, 3000.0], config).unwrap();
+        let mut blocks = Vec::new();
+        let samples: Vec<f32> = sine(1000.0, 0.5, 1600)
+            .iter()
+            .zip(sine(1210.0, 0.25, 1600))
+            .map(|(a, b)| a + b)
+            .collect();
+        bank.push(&samples, |block| blocks.push(block.clone()));
+
+        assert_eq!(blocks.len(), 2);
+        for block in &blocks {
+            assert!((block.amplitudes[0] - 0.5).abs() < 0.005);
+            assert!((block.amplitudes[1] - 0.25).abs() < 0.005);
+            assert!(block.amplitudes[2] < 1e-3);
+            let expected = (0.5 * 0.5 + 0.25 * 0.25) / 2.0;
+            assert!((block.mean_square / expected - 1.0).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    fn overlapping_blocks_are_indexed() {
+        let config = GoertzelConfig {
+            block_len: 100,
+            hop: 40,
+            ..GoertzelConfig::default()
+        };
+        let mut bank = GoertzelBank::new(8000, &[1000.0], config).unwrap();
+        let mut src_info: SrcInfo = SrcInfo::new(0);
+        let mut tap = src_info.add_sample_tap(512);
+        src_info.push_callback_data(&sine(1000.0, 1.0, 300), 0);
+
+        let mut indices = Vec::new();
+        bank.push_from(&mut tap, |block| indices.push(block.sample_index));
+        assert!(tap.is_empty());
+        assert_eq!(indices, [0, 40, 80, 120, 160, 200]);
+
+        bank.reset();
+        indices.clear();
+        bank.push(&[0.0; 140], |block| indices.push(block.sample_index));
+        assert_eq!(indices, [0, 40]);
+    }
+
+    #[test]
+    fn invalid_hop_is_rejected() {
+        let config = GoertzelConfig {
+            hop: 2048,
+            ..GoertzelConfig::default()
+        };
+        assert!(GoertzelBank::new(8000, &[1000.0], config).is_err());
+    }
+}
@@ -0,0 +1,414 @@
+//! Bounded history of past spectra for waterfall displays and offline
+//! inspection. Frames are stored as magnitudes with their timestamp and the
+//! index of their first sample.
+
+use crate::analyzer::Frame;
+use crate::frequency_axis::FrequencyAxis;
+use rustfft::num_complex::Complex;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Identifies snapshot files.
+const SNAPSHOT_MAGIC: &[u8; 4] = b"RTFH";
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// How much history is kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryLimit {
+    /// Keep at most this many frames.
+    Frames(usize),
+    /// Keep frames up to this much older than the newest one.
+    Duration(Duration),
+}
+
+/// How several values are combined when decimating.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reduction {
+    /// Largest value. Keeps short or narrow peaks visible.
+    Max,
+    Mean,
+}
+
+/// A past spectrum.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryFrame {
+    /// When the first sample of the frame was recorded, e.g.
+    /// RealtimeFft::frame_instant.
+    pub instant: Instant,
+    /// Index since the source started of the first sample of the frame.
+    pub sample_index: u64,
+    /// Magnitude of each bin.
+    pub magnitudes: Vec<f32>,
+}
+
+/// Ring of past spectra, oldest first.
+pub struct SpectrogramHistory {
+    limit: HistoryLimit,
+    axis: FrequencyAxis,
+    frames: VecDeque<HistoryFrame>,
+}
+
+impl SpectrogramHistory {
+    /// Returns an empty history of spectra on `axis`.
+    pub fn new(limit: HistoryLimit, axis: &FrequencyAxis) -> Self {
+        SpectrogramHistory {
+            limit,
+            axis: *axis,
+            frames: VecDeque::new(),
+        }
+    }
+
+    /// Returns the frequency axis of the stored spectra.
+    pub fn axis(&self) -> &FrequencyAxis {
+        &self.axis
+    }
+
+    /// Returns the number of frames stored.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns true if no frame is stored.
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Forgets every frame.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /// Adds a spectrum, dropping frames beyond the limit. Frames must be
+    /// added in time order. A spectrum with a different number of bins, e.g.
+    /// after RealtimeFft::reconfigure, clears the history and moves it to
+    /// the axis of the new spectrum.
+    pub fn push(&mut self, instant: Instant, sample_index: u64, spectrum: &[Complex<f32>]) {
+        if spectrum.len() != self.axis.num_bins() {
+            let fft_size = spectrum.len().saturating_sub(1) * 2;
+            self.set_axis(FrequencyAxis::new(self.axis.sample_rate(), fft_size));
+        }
+
+        // Reuse the storage of the frame about to be dropped if possible.
+        let mut magnitudes = match self.limit {
+            HistoryLimit::Frames(limit) if limit > 0 && self.frames.len() >= limit => {
+                self.frames.pop_front().unwrap().magnitudes
+            }
+            _ => Vec::with_capacity(spectrum.len()),
+        };
+        magnitudes.clear();
+        magnitudes.extend(spectrum.iter().map(|bin| bin.norm()));
+        self.frames.push_back(HistoryFrame {
+            instant,
+            sample_index,
+            magnitudes,
+        });
+
+        match self.limit {
+            HistoryLimit::Frames(limit) => {
+                while self.frames.len() > limit {
+                    self.frames.pop_front();
+                }
+            }
+            HistoryLimit::Duration(duration) => {
+                while let Some(oldest) = self.frames.front() {
+                    if instant.saturating_duration_since(oldest.instant) > duration {
+                        self.frames.pop_front();
+                    } else {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds a frame published by the Analyzer. A change of sample rate
+    /// clears the history.
+    pub fn push_frame(&mut self, frame: &Frame) {
+        if frame.sample_rate != self.axis.sample_rate() {
+            self.set_axis(FrequencyAxis::new(frame.sample_rate, self.axis.fft_size()));
+        }
+        self.push(frame.instant, frame.sample_index, &frame.spectrum);
+    }
+
+    /// Forgets every frame, which were on another axis.
+    fn set_axis(&mut self, axis: FrequencyAxis) {
+        self.axis = axis;
+        self.frames.clear();
+    }
+
+    /// Returns every frame, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &HistoryFrame> {
+        self.frames.iter()
+    }
+
+    /// Returns the newest frame.
+    pub fn latest(&self) -> Option<&HistoryFrame> {
+        self.frames.back()
+    }
+
+    /// Returns the frames recorded in [start, end), oldest first.
+    pub fn range(&self, start: Instant, end: Instant) -> impl Iterator<Item = &HistoryFrame> {
+        let first = self.frames.partition_point(|frame| frame.instant < start);
+        let last = self.frames.partition_point(|frame| frame.instant < end);
+        self.frames.range(first..last.max(first))
+    }
+
+    /// Returns the frames starting at sample indices in [start, end), oldest
+    /// first.
+    pub fn range_samples(&self, start: u64, end: u64) -> impl Iterator<Item = &HistoryFrame> {
+        let first = self
+            .frames
+            .partition_point(|frame| frame.sample_index < start);
+        let last = self
+            .frames
+            .partition_point(|frame| frame.sample_index < end);
+        self.frames.range(first..last.max(first))
+    }
+
+    /// Reduces the frames in [start, end) to at most `columns` columns of at
+    /// most `rows` bins each, e.g. to fit a display. Each column combines
+    /// consecutive frames and each row consecutive bins.
+    pub fn decimate(
+        &self,
+        start: Instant,
+        end: Instant,
+        columns: usize,
+        rows: usize,
+        reduction: Reduction,
+    ) -> Vec<Vec<f32>> {
+        let frames: Vec<&HistoryFrame> = self.range(start, end).collect();
+        if frames.is_empty() || columns == 0 || rows == 0 {
+            return Vec::new();
+        }
+        let num_bins = frames[0].magnitudes.len();
+        let frames_per_column = frames.len().div_ceil(columns);
+        let bins_per_row = num_bins.div_ceil(rows);
+
+        frames
+            .chunks(frames_per_column)
+            .map(|group| {
+                (0..num_bins)
+                    .step_by(bins_per_row)
+                    .map(|first_bin| {
+                        let last_bin = (first_bin + bins_per_row).min(num_bins);
+                        let values = group
+                            .iter()
+                            .flat_map(|frame| frame.magnitudes[first_bin..last_bin].iter());
+                        match reduction {
+                            Reduction::Max => values.cloned().fold(0.0, f32::max),
+                            Reduction::Mean => {
+                                let count = group.len() * (last_bin - first_bin);
+                                values.sum::<f32>() / count as f32
+                            }
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Writes every frame to a file that load_snapshot reads back. Times are
+    /// stored in seconds relative to the oldest frame.
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        writer.write_all(&self.axis.sample_rate().to_le_bytes())?;
+        writer.write_all(&(self.axis.fft_size() as u64).to_le_bytes())?;
+        writer.write_all(&(self.frames.len() as u64).to_le_bytes())?;
+        let origin = self.frames.front().map(|frame| frame.instant);
+        for frame in &self.frames {
+            let time = frame.instant - origin.unwrap();
+            writer.write_all(&time.as_secs_f64().to_le_bytes())?;
+            writer.write_all(&frame.sample_index.to_le_bytes())?;
+            writer.write_all(&(frame.magnitudes.len() as u64).to_le_bytes())?;
+            for magnitude in &frame.magnitudes {
+                writer.write_all(&magnitude.to_le_bytes())?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+/// A frame read back from a snapshot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotFrame {
+    /// Seconds since the oldest frame of the snapshot.
+    pub time: f64,
+    pub sample_index: u64,
+    pub magnitudes: Vec<f32>,
+}
+
+/// Contents of a snapshot file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub axis: FrequencyAxis,
+    pub frames: Vec<SnapshotFrame>,
+}
+
+/// Reads a file written by SpectrogramHistory::save_snapshot.
+pub fn load_snapshot<P: AsRef<Path>>(path: P) -> io::Result<Snapshot> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != SNAPSHOT_MAGIC || read_u32(&mut reader)? != SNAPSHOT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Not a spectrogram snapshot!",
+        ));
+    }
+    let sample_rate = read_u32(&mut reader)?;
+    let fft_size = read_u64(&mut reader)? as usize;
+    let num_frames = read_u64(&mut reader)?;
+
+    let mut frames = Vec::new();
+    for _ in 0..num_frames {
+        let time = f64::from_bits(read_u64(&mut reader)?);
+        let sample_index = read_u64(&mut reader)?;
+        let num_bins = read_u64(&mut reader)?;
+        // The count comes from the file, so grow as bins are read.
+        let mut magnitudes = Vec::new();
+        for _ in 0..num_bins {
+            magnitudes.push(f32::from_bits(read_u32(&mut reader)?));
+        }
+        frames.push(SnapshotFrame {
+            time,
+            sample_index,
+            magnitudes,
+        });
+    }
+    Ok(Snapshot {
+        axis: FrequencyAxis::new(sample_rate, fft_size),
+        frames,
+    })
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Returns a spectrum of `num_bins` bins all of magnitude `value`.
+    fn flat(num_bins: usize, value: f32) -> Vec<Complex<f32>> {
+        vec![Complex::new(0.0, value); num_bins]
+    }
+
+    #[test]
+    fn limits_drop_the_oldest_frames() {
+        let axis = FrequencyAxis::new(8000, 8);
+        let start = Instant::now();
+        let mut by_count = SpectrogramHistory::new(HistoryLimit::Frames(3), &axis);
+        let mut by_duration =
+            SpectrogramHistory::new(HistoryLimit::Duration(Duration::from_millis(25)), &axis);
+        for n in 0..5 {
+            let instant = start + Duration::from_millis(10 * n);
+            by_count.push(instant, 8 * n, &flat(5, n as f32));
+            by_duration.push(instant, 8 * n, &flat(5, n as f32));
+        }
+
+        let indices = |history: &SpectrogramHistory| -> Vec<u64> {
+            history.iter().map(|frame| frame.sample_index).collect()
+        };
+        assert_eq!(indices(&by_count), [16, 24, 32]);
+        assert_eq!(indices(&by_duration), [16, 24, 32]);
+        assert_eq!(by_count.latest().unwrap().magnitudes, [4.0; 5]);
+
+        let range = by_count.range(
+            start + Duration::from_millis(25),
+            start + Duration::from_secs(1),
+        );
+        assert_eq!(range.count(), 2);
+        let samples: Vec<u64> = by_count
+            .range_samples(20, 32)
+            .map(|f| f.sample_index)
+            .collect();
+        assert_eq!(samples, [24]);
+    }
+
+    #[test]
+    fn decimation_reduces_frames_and_bins() {
+        let axis = FrequencyAxis::new(8000, 6);
+        let start = Instant::now();
+        let mut history = SpectrogramHistory::new(HistoryLimit::Frames(10), &axis);
+        for n in 0..4 {
+            let spectrum: Vec<Complex<f32>> = (0..4)
+                .map(|bin| Complex::new((n * 4 + bin) as f32, 0.0))
+                .collect();
+            history.push(start + Duration::from_millis(n), n, &spectrum);
+        }
+
+        let end = start + Duration::from_secs(1);
+        let max = history.decimate(start, end, 2, 2, Reduction::Max);
+        assert_eq!(max, [[5.0, 7.0], [13.0, 15.0]]);
+        let mean = history.decimate(start, end, 2, 2, Reduction::Mean);
+        assert_eq!(mean, [[2.5, 4.5], [10.5, 12.5]]);
+        assert!(history.decimate(end, end, 2, 2, Reduction::Max).is_empty());
+    }
+
+    #[test]
+    fn reconfigured_spectra_clear_the_history() {
+        let axis = FrequencyAxis::new(8000, 8);
+        let start = Instant::now();
+        let mut history = SpectrogramHistory::new(HistoryLimit::Frames(10), &axis);
+        history.push(start, 0, &flat(5, 1.0));
+        history.push(start, 8, &flat(9, 1.0));
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.axis().fft_size(), 16);
+        let rows = history.decimate(start, start + Duration::from_secs(1), 4, 4, Reduction::Max);
+        assert_eq!(rows.len(), 1);
+
+        let frame = Frame {
+            spectrum: flat(9, 2.0),
+            samples: Vec::new(),
+            sample_index: 16,
+            sample_rate: 48000,
+            instant: start,
+        };
+        history.push_frame(&frame);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.axis(), &FrequencyAxis::new(48000, 16));
+        assert_eq!(history.latest().unwrap().instant, frame.instant);
+    }
+
+    #[test]
+    fn snapshots_are_read_back() {
+        let path = std::env::temp_dir().join(format!("realtime_fft_{}.rtfh", std::process::id()));
+        let axis = FrequencyAxis::new(8000, 8);
+        let start = Instant::now();
+        let mut history = SpectrogramHistory::new(HistoryLimit::Frames(10), &axis);
+        history.push(start, 0, &flat(5, 1.0));
+        history.push(start + Duration::from_millis(500), 8, &flat(5, 2.0));
+        history.save_snapshot(&path).unwrap();
+        let snapshot = load_snapshot(&path);
+
+        // A bin count far larger than the file must not be preallocated.
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[44..52].copy_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+        let corrupt = load_snapshot(&path);
+        fs::remove_file(&path).unwrap();
+
+        let snapshot = snapshot.unwrap();
+        assert_eq!(snapshot.axis, axis);
+        assert_eq!(snapshot.frames.len(), 2);
+        assert_eq!(snapshot.frames[1].time, 0.5);
+        assert_eq!(snapshot.frames[1].sample_index, 8);
+        assert_eq!(snapshot.frames[1].magnitudes, [2.0; 5]);
+        assert_eq!(corrupt.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,490 @@
+//! Sound level meter following IEC 61672-1. The signal is frequency
+//! weighted (A, C or Z) and time weighted (Fast, Slow or Impulse), and each
+//! measurement interval reports Leq, Lmax, Lmin and statistical levels. The
+//! frequency weightings can also be applied to RealtimeFft spectra.
+
+use crate::biquad::{self, Cascade};
+use crate::frequency_axis::FrequencyAxis;
+use crate::octave::power_to_db;
+use crate::realtime_fft::realtime_fft_src::SampleTap;
+use rustfft::num_complex::Complex;
+use std::f64::consts::PI;
+use std::time::Duration;
+
+/// Pole frequencies of the weightings in Hz (IEC 61672-1 Annex E).
+const F1: f64 = 20.598997;
+const F2: f64 = 107.65265;
+const F3: f64 = 737.86223;
+const F4: f64 = 12194.217;
+
+/// Time between the levels sampled for the percentiles.
+const STATISTICS_PERIOD: Duration = Duration::from_millis(10);
+
+/// Frequency weighting of the levels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrequencyWeighting {
+    /// Follows the sensitivity of the ear at low levels.
+    A,
+    /// Nearly flat, rolling off below 31.5 Hz and above 8 kHz.
+    C,
+    /// No weighting.
+    Z,
+}
+
+impl FrequencyWeighting {
+    /// Returns the weighting at `hz` in dB, 0 dB at 1 kHz.
+    pub fn gain_db(&self, hz: f32) -> f32 {
+        (20.0 * (self.response(hz as f64) / self.response(1000.0)).log10()) as f32
+    }
+
+    /// Returns the amplitude gain of the weighting at every bin of `axis`.
+    pub fn spectrum_gains(&self, axis: &FrequencyAxis) -> Vec<f32> {
+        let reference = self.response(1000.0);
+        axis.frequencies()
+            .map(|hz| (self.response(hz as f64) / reference) as f32)
+            .collect()
+    }
+
+    /// Designs a filter applying the weighting to a signal at `sample_rate`,
+    /// with unity gain at 1 kHz.
+    pub fn filter(&self, sample_rate: u32) -> Cascade {
+        let fs = sample_rate as f64;
+        // Without prewarping the response is exact up to a few kHz and rolls
+        // off early near Nyquist, within the class 1 tolerances at 44.1 kHz
+        // and above.
+        let pole = |hz: f64| Complex::new(-2.0 * PI * hz, 0.0);
+        let (zeros, poles) = match self {
+            FrequencyWeighting::A => (
+                vec![Complex::new(0.0, 0.0); 4],
+                vec![pole(F1), pole(F1), pole(F2), pole(F3), pole(F4), pole(F4)],
+            ),
+            FrequencyWeighting::C => (
+                vec![Complex::new(0.0, 0.0); 2],
+                vec![pole(F1), pole(F1), pole(F4), pole(F4)],
+            ),
+            FrequencyWeighting::Z => return Cascade::new(Vec::new(), 1.0),
+        };
+        let mut cascade = biquad::bilinear_zpk(&zeros, &poles, 1.0, fs);
+        cascade.normalize_at(1000.0, fs);
+        cascade
+    }
+
+    /// Returns the magnitude of the analog weighting at `hz`.
+    fn response(&self, hz: f64) -> f64 {
+        let f2 = hz * hz;
+        match self {
+            FrequencyWeighting::A => {
+                F4 * F4 * f2 * f2
+                    / ((f2 + F1 * F1)
+                        * (f2 + F2 * F2).sqrt()
+                        * (f2 + F3 * F3).sqrt()
+                        * (f2 + F4 * F4))
+            }
+            FrequencyWeighting::C => F4 * F4 * f2 / ((f2 + F1 * F1) * (f2 + F4 * F4)),
+            FrequencyWeighting::Z => 1.0,
+        }
+    }
+}
+
+/// Applies a frequency weighting to the spectra of a RealtimeFft.
+pub struct SpectrumWeighting {
+    weighting: FrequencyWeighting,
+    gains: Vec<f32>,
+}
+
+impl SpectrumWeighting {
+    /// Returns the weighting of spectra on `axis`.
+    pub fn new(weighting: FrequencyWeighting, axis: &FrequencyAxis) -> Self {
+        SpectrumWeighting {
+            weighting,
+            gains: weighting.spectrum_gains(axis),
+        }
+    }
+
+    /// Returns the weighting applied.
+    pub fn weighting(&self) -> FrequencyWeighting {
+        self.weighting
+    }
+
+    /// Weights the bins of a spectrum.
+    pub fn apply(&self, spectrum: &mut [Complex<f32>]) {
+        for (bin, &gain) in spectrum.iter_mut().zip(&self.gains) {
+            *bin *= gain;
+        }
+    }
+
+    /// Weights the magnitudes of a spectrum, e.g. the averaged output of a
+    /// RealtimeFft.
+    pub fn apply_magnitudes(&self, magnitudes: &mut [f32]) {
+        for (magnitude, &gain) in magnitudes.iter_mut().zip(&self.gains) {
+            *magnitude *= gain;
+        }
+    }
+}
+
+/// Time weighting of the levels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeWeighting {
+    /// 125 ms time constant.
+    Fast,
+    /// 1 s time constant.
+    Slow,
+    /// 35 ms time constant, followed by a detector holding peaks that
+    /// decays with a 1.5 s time constant.
+    Impulse,
+}
+
+impl TimeWeighting {
+    /// Returns the time constant of the exponential average and that of
+    /// the decay of the peak detector following it, if any.
+    pub fn time_constants(&self) -> (Duration, Option<Duration>) {
+        match self {
+            TimeWeighting::Fast => (Duration::from_millis(125), None),
+            TimeWeighting::Slow => (Duration::from_secs(1), None),
+            TimeWeighting::Impulse => {
+                (Duration::from_millis(35), Some(Duration::from_millis(1500)))
+            }
+        }
+    }
+}
+
+/// Unit of the levels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LevelUnit {
+    /// dB relative to a mean square of 1, so a full scale sine reads
+    /// -3 dBFS.
+    Dbfs,
+    /// dB SPL, `offset_db` above dBFS.
+    Spl { offset_db: f32 },
+}
+
+impl LevelUnit {
+    /// Returns the calibration given by a reference source, e.g. a 94 dB SPL
+    /// calibrator, that read `measured_dbfs` Z-weighted.
+    pub fn calibrated(measured_dbfs: f32, reference_db_spl: f32) -> Self {
+        LevelUnit::Spl {
+            offset_db: reference_db_spl - measured_dbfs,
+        }
+    }
+
+    /// Returns the offset added to levels in dBFS.
+    pub fn offset_db(&self) -> f32 {
+        match self {
+            LevelUnit::Dbfs => 0.0,
+            LevelUnit::Spl { offset_db } => *offset_db,
+        }
+    }
+}
+
+/// Parameters of the level meter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LevelMeterConfig {
+    pub frequency_weighting: FrequencyWeighting,
+    pub time_weighting: TimeWeighting,
+    /// Length of each measurement interval.
+    pub interval: Duration,
+    pub unit: LevelUnit,
+}
+
+impl Default for LevelMeterConfig {
+    fn default() -> Self {
+        LevelMeterConfig {
+            frequency_weighting: FrequencyWeighting::A,
+            time_weighting: TimeWeighting::Fast,
+            interval: Duration::from_secs(1),
+            unit: LevelUnit::Dbfs,
+        }
+    }
+}
+
+/// Levels of a measurement interval. Lmax, Lmin and the percentiles are
+/// those of the time weighted level, Leq is the energy average.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LevelReport {
+    /// Index in the stream of the first sample of the interval.
+    pub sample_index: u64,
+    pub duration: Duration,
+    pub leq: f32,
+    pub lmax: f32,
+    pub lmin: f32,
+    /// Level exceeded 10% of the time.
+    pub l10: f32,
+    /// Level exceeded 90% of the time.
+    pub l90: f32,
+}
+
+/// Measures frequency and time weighted levels interval by interval.
+pub struct LevelMeter {
+    config: LevelMeterConfig,
+    sample_rate: u32,
+    filter: Cascade,
+    /// Smoothing of the exponential average and of the decay of the peak
+    /// detector.
+    smoothing: f64,
+    decay: Option<f64>,
+    /// Exponentially averaged mean square.
+    averaged: f64,
+    /// Time weighted mean square, the average after the peak detector.
+    weighted: f64,
+    /// Number of samples per interval and in the current interval.
+    interval_len: usize,
+    interval_filled: usize,
+    /// Index in the stream of the first sample of the current interval.
+    interval_start: u64,
+    /// Sum of squares of the frequency weighted signal in the interval.
+    energy: f64,
+    /// Extremes of the time weighted mean square in the interval.
+    max: f64,
+    min: f64,
+    /// Samples between two levels kept for the percentiles.
+    statistics_period: usize,
+    statistics_countdown: usize,
+    /// Time weighted levels sampled in the interval in dB.
+    levels: Vec<f32>,
+}
+
+impl LevelMeter {
+    /// Returns a meter for a signal at `sample_rate`.
+    pub fn new(sample_rate: u32, config: LevelMeterConfig) -> Self {
+        let to_samples = |duration: Duration| duration.as_secs_f64() * sample_rate as f64;
+        let smoothing = |time_constant: Duration| 1.0 - (-1.0 / to_samples(time_constant)).exp();
+        let (average, decay) = config.time_weighting.time_constants();
+        let interval_len = (to_samples(config.interval).round() as usize).max(1);
+        let statistics_period = (to_samples(STATISTICS_PERIOD).round() as usize).max(1);
+        LevelMeter {
+            sample_rate,
+            filter: config.frequency_weighting.filter(sample_rate),
+            smoothing: smoothing(average),
+            decay: decay.map(smoothing),
+            averaged: 0.0,
+            weighted: 0.0,
+            interval_len,
+            interval_filled: 0,
+            interval_start: 0,
+            energy: 0.0,
+            max: 0.0,
+            min: f64::INFINITY,
+            statistics_period,
+            statistics_countdown: statistics_period,
+            levels: Vec::with_capacity(interval_len / statistics_period + 1),
+            config,
+        }
+    }
+
+    /// Returns the configuration of the meter.
+    pub fn config(&self) -> &LevelMeterConfig {
+        &self.config
+    }
+
+    /// Returns the current time weighted level. The time weighting starts
+    /// from silence so the level rises at first.
+    pub fn level(&self) -> f32 {
+        self.to_db(self.weighted)
+    }
+
+    /// Adds samples, calling `on_interval` with every interval that becomes
+    /// complete.
+    pub fn push<F: FnMut(&LevelReport)>(&mut self, samples: &[f32], mut on_interval: F) {
+        for &sample in samples {
+            let x = self.filter.process(sample as f64);
+            let square = x * x;
+            self.averaged += (square - self.averaged) * self.smoothing;
+            self.weighted = match self.decay {
+                Some(decay) if self.averaged < self.weighted => {
+                    self.weighted + (self.averaged - self.weighted) * decay
+                }
+                _ => self.averaged,
+            };
+
+            self.energy += square;
+            self.max = self.max.max(self.weighted);
+            self.min = self.min.min(self.weighted);
+            self.statistics_countdown -= 1;
+            if self.statistics_countdown == 0 {
+                self.statistics_countdown = self.statistics_period;
+                self.levels.push(self.to_db(self.weighted));
+            }
+
+            self.interval_filled += 1;
+            if self.interval_filled == self.interval_len {
+                on_interval(&self.take_report());
+            }
+        }
+    }
+
+    /// Adds every sample available in a tap of the audio source.
+    pub fn push_from<F: FnMut(&LevelReport)>(&mut self, tap: &mut SampleTap, mut on_interval: F) {
+        tap.drain(|samples| self.push(samples, &mut on_interval));
+    }
+
+    /// Clears the filter, the time weighting and the current interval.
+    /// Sample indices restart from 0.
+    pub fn reset(&mut self) {
+        self.filter.reset();
+        self.averaged = 0.0;
+        self.weighted = 0.0;
+        self.interval_start = 0;
+        self.start_interval();
+    }
+
+    /// Returns the levels of the current interval and starts a new one.
+    fn take_report(&mut self) -> LevelReport {
+        // Sampled levels, quietest first.
+        self.levels.sort_by(f32::total_cmp);
+        let percentile = |levels: &[f32], exceeded: f32| match levels.len() {
+            0 => f32::NEG_INFINITY,
+            len => levels[((1.0 - exceeded) * (len - 1) as f32).round() as usize],
+        };
+        let report = LevelReport {
+            sample_index: self.interval_start,
+            duration: Duration::from_secs_f64(
+                self.interval_filled as f64 / self.sample_rate as f64,
+            ),
+            leq: self.to_db(self.energy / self.interval_filled.max(1) as f64),
+            lmax: self.to_db(self.max),
+            lmin: self.to_db(self.min),
+            l10: percentile(&self.levels, 0.1),
+            l90: percentile(&self.levels, 0.9),
+        };
+        self.interval_start += self.interval_filled as u64;
+        self.start_interval();
+        report
+    }
+
+    fn start_interval(&mut self) {
+        self.interval_filled = 0;
+        self.energy = 0.0;
+        self.max = 0.0;
+        self.min = f64::INFINITY;
+        self.statistics_countdown = self.statistics_period;
+        self.levels.clear();
+    }
+
+    /// Converts a mean square to a level in the configured unit.
+    fn to_db(&self, mean_square: f64) -> f32 {
+        power_to_db(mean_square as f32) + self.config.unit.offset_db()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime_fft::realtime_fft_src::SrcInfo;
+
+    fn sine(hz: f64, amplitude: f64, len: usize, sample_rate: u32) -> Vec<f32> {
+        (0..len)
+            .map(|n| (amplitude * (2.0 * PI * hz * n as f64 / sample_rate as f64).sin()) as f32)
+            .collect()
+    }
+
+    #[test]
+    fn a_weighting_is_within_class_1_tolerances() {
+        // IEC 61672-1 Table 3 at the exact base 10 frequencies: A-weighting
+        // and the class 1 tolerance above and below.
+        let table = [
+            (-15, -39.4, 1.5, 1.5),
+            (-12, -26.2, 1.0, 1.0),
+            (-9, -16.1, 1.0, 1.0),
+            (-6, -8.6, 1.0, 1.0),
+            (-3, -3.2, 1.0, 1.0),
+            (0, 0.0, 0.7, 0.7),
+            (3, 1.2, 1.0, 1.0),
+            (6, 1.0, 1.0, 1.0),
+            (9, -1.1, 1.5, 2.5),
+            (12, -6.6, 2.5, 16.0),
+        ];
+        for sample_rate in [44100, 48000] {
+            let filter = FrequencyWeighting::A.filter(sample_rate);
+            for (n, expected, above, below) in table {
+                let hz = 1000.0 * 10f64.powf(0.1 * n as f64);
+                assert!((FrequencyWeighting::A.gain_db(hz as f32) - expected).abs() < 0.1);
+                let gain = 20.0 * filter.response(hz, sample_rate as f64).norm().log10() as f32;
+                assert!(
+                    gain < expected + above && gain > expected - below,
+                    "{} Hz at {} Hz",
+                    hz,
+                    sample_rate
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn spectrum_weighting_follows_the_analog_response() {
+        let axis = FrequencyAxis::new(48000, 4800);
+        let weighting = SpectrumWeighting::new(FrequencyWeighting::C, &axis);
+        let mut magnitudes = vec![1.0; axis.num_bins()];
+        weighting.apply_magnitudes(&mut magnitudes);
+        assert!((magnitudes[100] - 1.0).abs() < 1e-6);
+        let expected = 10f32.powf(FrequencyWeighting::C.gain_db(31.5 * 2.0) / 20.0);
+        assert!((magnitudes[6] - expected).abs() < 0.01);
+        assert_eq!(magnitudes[0], 0.0);
+    }
+
+    #[test]
+    fn time_weightings_decay_at_their_rates() {
+        // Decay rates after the signal stops in dB/s.
+        for (time_weighting, rate) in [
+            (TimeWeighting::Fast, 34.7),
+            (TimeWeighting::Slow, 4.3),
+            (TimeWeighting::Impulse, 2.9),
+        ] {
+            let config = LevelMeterConfig {
+                frequency_weighting: FrequencyWeighting::Z,
+                time_weighting,
+                interval: Duration::from_secs(100),
+                ..LevelMeterConfig::default()
+            };
+            let mut meter = LevelMeter::new(8000, config);
+            meter.push(&sine(1000.0, 1.0, 80000, 8000), |_| {});
+            assert!((meter.level() + 3.01).abs() < 0.05);
+            meter.push(&[0.0; 800], |_| {});
+            let start = meter.level();
+            meter.push(&[0.0; 4000], |_| {});
+            let decay = (start - meter.level()) * 2.0;
+            assert!(
+                (decay - rate).abs() < 0.1,
+                "{:?} decays {}",
+                time_weighting,
+                decay
+            );
+        }
+    }
+
+    #[test]
+    fn intervals_report_equivalent_and_extreme_levels() {
+        let config = LevelMeterConfig {
+            frequency_weighting: FrequencyWeighting::Z,
+            interval: Duration::from_secs(2),
+            unit: LevelUnit::Spl { offset_db: 100.0 },
+            ..LevelMeterConfig::default()
+        };
+        let mut meter = LevelMeter::new(8000, config);
+        let mut src_info: SrcInfo = SrcInfo::new(0);
+        let mut tap = src_info.add_sample_tap(32000);
+
+        // Full scale for a second, then 20 dB quieter. The level settles
+        // within a second.
+        let mut samples = sine(1000.0, 1.0, 8000, 8000);
+        samples.extend(sine(1000.0, 0.1, 24000, 8000));
+        src_info.push_callback_data(&samples, 0);
+        let mut reports = Vec::new();
+        meter.push_from(&mut tap, |report| reports.push(*report));
+        assert!(tap.is_empty());
+
+        assert_eq!(reports.len(), 2);
+        let first = reports[0];
+        assert_eq!(first.sample_index, 0);
+        assert_eq!(first.duration, Duration::from_secs(2));
+        let leq = 100.0 + 10.0 * (0.5 * 0.5 + 0.5 * 0.005f32).log10();
+        assert!((first.leq - leq).abs() < 0.05);
+        assert!((first.lmax - 96.99).abs() < 0.05);
+        assert!((first.l10 - 96.99).abs() < 0.05);
+        assert!((first.l90 - 76.99).abs() < 1.0);
+
+        let second = reports[1];
+        assert_eq!(second.sample_index, 16000);
+        assert!((second.leq - 76.99).abs() < 0.05);
+        assert!((second.lmax - second.lmin).abs() < 0.5);
+    }
+}
@@ -0,0 +1,37 @@
+//! Realtime spectral analysis of audio sources. RealtimeFft computes the
+//! spectrum of a live source; the other modules analyze its spectra or tap
+//! its raw samples.
+
+pub mod analyzer;
+pub mod audio_input;
+pub mod averaging;
+pub mod biquad;
+pub mod chroma;
+pub mod cqt;
+pub mod descriptors;
+pub mod distortion;
+pub mod dtmf;
+pub mod error;
+pub mod frequency_axis;
+pub mod goertzel;
+pub mod history;
+pub mod level_meter;
+pub mod loudness;
+pub mod mel;
+pub mod multires;
+pub mod music;
+pub mod octave;
+pub mod onset;
+pub mod peaks;
+pub mod pitch;
+pub mod realtime_fft;
+pub mod reassignment;
+pub mod resynthesis;
+pub mod sample_window;
+pub mod sweep;
+pub mod tempo;
+pub mod transfer;
+pub mod wav;
+pub mod welch;
+pub mod window;
+pub mod zoom;
@@ -0,0 +1,671 @@
+//! Loudness meter following ITU-R BS.1770 and EBU R128. Each channel is
+//! K-weighted and its mean square gathered in 100 ms steps, from which the
+//! momentary, short-term and gated integrated loudness and the loudness
+//! range are derived. True-peak is measured on the signal oversampled 4x.
+
+use crate::biquad::{Biquad, Cascade};
+use crate::error::ConfigError;
+use crate::realtime_fft::realtime_fft_src::SampleTap;
+use crate::window::WindowFunction;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// Weight of the surround channels (BS.1770).
+pub const SURROUND_WEIGHT: f32 = 1.41;
+
+/// Blocks quieter than this never count towards the integrated loudness or
+/// the loudness range.
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+/// Gate below the ungated loudness for the integrated loudness in LU.
+const INTEGRATED_RELATIVE_GATE: f64 = -10.0;
+/// Gate below the ungated loudness for the loudness range in LU.
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+
+/// Loudness covered by the histograms of gating blocks and their
+/// resolution in LU. Louder blocks are counted in the top bin.
+const HISTOGRAM_MAX_LUFS: f64 = 10.0;
+const HISTOGRAM_RESOLUTION: f64 = 0.01;
+
+/// Number of 100 ms steps in the momentary and short-term windows.
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+
+/// Oversampling factor of the true-peak detector.
+const OVERSAMPLING: usize = 4;
+/// Input samples spanned by each phase of the interpolation filter. Even so
+/// that the first phase passes the input through.
+const TAPS_PER_PHASE: usize = 32;
+
+/// Parameters of the loudness meter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoudnessConfig {
+    /// Weight of each channel, in the order they are pushed. 1.0 for left,
+    /// right and centre, SURROUND_WEIGHT for the surround channels and 0.0
+    /// for the LFE channel.
+    pub channel_weights: Vec<f32>,
+}
+
+impl LoudnessConfig {
+    /// Single channel.
+    pub fn mono() -> Self {
+        LoudnessConfig {
+            channel_weights: vec![1.0],
+        }
+    }
+
+    /// Left and right.
+    pub fn stereo() -> Self {
+        LoudnessConfig {
+            channel_weights: vec![1.0, 1.0],
+        }
+    }
+
+    /// Left, right, centre, LFE, left surround and right surround.
+    pub fn surround_5_1() -> Self {
+        LoudnessConfig {
+            channel_weights: vec![1.0, 1.0, 1.0, 0.0, SURROUND_WEIGHT, SURROUND_WEIGHT],
+        }
+    }
+}
+
+impl LoudnessConfig {
+    /// Checks that there is at least one channel and that the weights are
+    /// finite and not negative.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.channel_weights.is_empty() {
+            return Err(ConfigError::new("at least one channel is needed"));
+        }
+        if !self
+            .channel_weights
+            .iter()
+            .all(|weight| weight.is_finite() && *weight >= 0.0)
+        {
+            return Err(ConfigError::new(
+                "channel weights must be finite and not negative",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for LoudnessConfig {
+    fn default() -> Self {
+        LoudnessConfig::mono()
+    }
+}
+
+/// Processing of a single channel.
+struct Channel {
+    weight: f64,
+    k_weighting: Cascade,
+    true_peak: TruePeakDetector,
+}
+
+/// Measures the loudness of a multi-channel signal.
+pub struct LoudnessMeter {
+    config: LoudnessConfig,
+    channels: Vec<Channel>,
+    /// Number of samples per 100 ms step.
+    step_len: usize,
+    /// Weighted sum of squares of the current step and the samples in it.
+    step_energy: f64,
+    step_filled: usize,
+    /// Weighted sum of squares of the most recent steps, oldest first.
+    steps: VecDeque<f64>,
+    /// Every 400 ms gating block above the absolute gate.
+    blocks: BlockHistogram,
+    /// Every short-term window above the absolute gate.
+    short_term_blocks: BlockHistogram,
+    /// Samples of each channel popped from the taps.
+    tap_samples: Vec<Vec<f32>>,
+}
+
+impl LoudnessMeter {
+    /// Returns a meter for a signal at `sample_rate`.
+    pub fn new(sample_rate: u32, config: LoudnessConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        let channels = config
+            .channel_weights
+            .iter()
+            .map(|&weight| Channel {
+                weight: weight as f64,
+                k_weighting: k_weighting(sample_rate as f64),
+                true_peak: TruePeakDetector::new(),
+            })
+            .collect();
+        Ok(LoudnessMeter {
+            channels,
+            step_len: ((sample_rate as f64 / 10.0).round() as usize).max(1),
+            step_energy: 0.0,
+            step_filled: 0,
+            steps: VecDeque::with_capacity(SHORT_TERM_STEPS),
+            blocks: BlockHistogram::new(),
+            short_term_blocks: BlockHistogram::new(),
+            tap_samples: vec![Vec::new(); config.channel_weights.len()],
+            config,
+        })
+    }
+
+    /// Returns the configuration of the meter.
+    pub fn config(&self) -> &LoudnessConfig {
+        &self.config
+    }
+
+    /// Adds the same span of samples of every channel.
+    pub fn push(&mut self, channels: &[&[f32]]) {
+        assert_eq!(
+            channels.len(),
+            self.channels.len(),
+            "Samples must be given for every channel!"
+        );
+        let len = channels[0].len();
+        assert!(
+            channels.iter().all(|samples| samples.len() == len),
+            "Every channel must receive the same number of samples!"
+        );
+        for n in 0..len {
+            for (channel, samples) in self.channels.iter_mut().zip(channels) {
+                self.step_energy += channel.process(samples[n]);
+            }
+            self.end_sample();
+        }
+    }
+
+    /// Adds samples interleaved channel by channel.
+    pub fn push_interleaved(&mut self, data: &[f32]) {
+        assert!(
+            data.len() % self.channels.len() == 0,
+            "Every channel must receive the same number of samples!"
+        );
+        for frame in data.chunks_exact(self.channels.len()) {
+            for (channel, &sample) in self.channels.iter_mut().zip(frame) {
+                self.step_energy += channel.process(sample);
+            }
+            self.end_sample();
+        }
+    }
+
+    /// Adds every sample available in the taps of each channel. Only as many
+    /// samples as the shortest tap holds are taken so the channels stay
+    /// aligned.
+    pub fn push_from(&mut self, taps: &mut [SampleTap]) {
+        assert_eq!(
+            taps.len(),
+            self.channels.len(),
+            "A tap must be given for every channel!"
+        );
+        let len = taps.iter().map(|tap| tap.len()).min().unwrap_or(0);
+        for (samples, tap) in self.tap_samples.iter_mut().zip(taps.iter_mut()) {
+            samples.resize(len, 0.0);
+            tap.pop_slice(samples);
+        }
+        for n in 0..len {
+            for (channel, samples) in self.channels.iter_mut().zip(&self.tap_samples) {
+                self.step_energy += channel.process(samples[n]);
+            }
+            self.end_sample();
+        }
+    }
+
+    /// Forgets everything measured so far.
+    pub fn reset(&mut self) {
+        for channel in &mut self.channels {
+            channel.k_weighting.reset();
+            channel.true_peak = TruePeakDetector::new();
+        }
+        self.step_energy = 0.0;
+        self.step_filled = 0;
+        self.steps.clear();
+        self.blocks.clear();
+        self.short_term_blocks.clear();
+    }
+
+    /// Returns the loudness of the last 400 ms in LUFS, None until that much
+    /// has been received.
+    pub fn momentary(&self) -> Option<f32> {
+        self.window_mean_square(MOMENTARY_STEPS)
+            .map(|mean_square| loudness(mean_square) as f32)
+    }
+
+    /// Returns the loudness of the last 3 s in LUFS, None until that much has
+    /// been received.
+    pub fn short_term(&self) -> Option<f32> {
+        self.window_mean_square(SHORT_TERM_STEPS)
+            .map(|mean_square| loudness(mean_square) as f32)
+    }
+
+    /// Returns the gated loudness since the last reset in LUFS, None until a
+    /// block passes the absolute gate.
+    pub fn integrated(&self) -> Option<f32> {
+        let gate = self.blocks.relative_gate(INTEGRATED_RELATIVE_GATE)?;
+        self.blocks
+            .mean_square_above(gate)
+            .map(|mean_square| loudness(mean_square) as f32)
+    }
+
+    /// Returns the loudness range since the last reset in LU, the spread
+    /// between the 10th and 95th percentiles of the gated short-term
+    /// loudness. None until a short-term window passes the absolute gate.
+    pub fn loudness_range(&self) -> Option<f32> {
+        let histogram = &self.short_term_blocks;
+        let gate = histogram.relative_gate(RANGE_RELATIVE_GATE)?;
+        let low = histogram.percentile_above(gate, 0.10)?;
+        let high = histogram.percentile_above(gate, 0.95)?;
+        Some((high - low) as f32)
+    }
+
+    /// Returns the highest true-peak of any channel since the last reset in
+    /// dBTP.
+    pub fn true_peak(&self) -> f32 {
+        (0..self.channels.len())
+            .map(|channel| self.channel_true_peak(channel))
+            .fold(f32::NEG_INFINITY, f32::max)
+    }
+
+    /// Returns the true-peak of `channel` since the last reset in dBTP.
+    pub fn channel_true_peak(&self, channel: usize) -> f32 {
+        20.0 * self.channels[channel].true_peak.peak.log10()
+    }
+
+    /// Completes a sample of every channel, closing the step when it's full.
+    fn end_sample(&mut self) {
+        self.step_filled += 1;
+        if self.step_filled < self.step_len {
+            return;
+        }
+        if self.steps.len() == SHORT_TERM_STEPS {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(self.step_energy);
+        self.step_energy = 0.0;
+        self.step_filled = 0;
+
+        // Gating blocks overlap by 75% so one ends at every step.
+        let absolute_gate = mean_square(ABSOLUTE_GATE_LUFS);
+        if let Some(mean_square) = self.window_mean_square(MOMENTARY_STEPS) {
+            if mean_square > absolute_gate {
+                self.blocks.add(mean_square);
+            }
+        }
+        if let Some(mean_square) = self.window_mean_square(SHORT_TERM_STEPS) {
+            if mean_square > absolute_gate {
+                self.short_term_blocks.add(mean_square);
+            }
+        }
+    }
+
+    /// Returns the weighted mean square of the last `steps` steps.
+    fn window_mean_square(&self, steps: usize) -> Option<f64> {
+        if self.steps.len() < steps {
+            return None;
+        }
+        let energy: f64 = self.steps.iter().rev().take(steps).sum();
+        Some(energy / (steps * self.step_len) as f64)
+    }
+}
+
+/// Gating blocks counted by loudness in bins of HISTOGRAM_RESOLUTION from
+/// the absolute gate up, so the memory used doesn't grow with the length of
+/// the programme. The mean square of the blocks in each bin is summed
+/// exactly, only the gates are rounded to a bin.
+struct BlockHistogram {
+    counts: Vec<u64>,
+    mean_squares: Vec<f64>,
+    /// Number and summed mean square of every block.
+    total_count: u64,
+    total_mean_square: f64,
+}
+
+impl BlockHistogram {
+    fn new() -> Self {
+        let len = ((HISTOGRAM_MAX_LUFS - ABSOLUTE_GATE_LUFS) / HISTOGRAM_RESOLUTION).round();
+        BlockHistogram {
+            counts: vec![0; len as usize],
+            mean_squares: vec![0.0; len as usize],
+            total_count: 0,
+            total_mean_square: 0.0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.counts.iter_mut().for_each(|count| *count = 0);
+        self.mean_squares.iter_mut().for_each(|sum| *sum = 0.0);
+        self.total_count = 0;
+        self.total_mean_square = 0.0;
+    }
+
+    /// Counts a block of the given mean square.
+    fn add(&mut self, mean_square: f64) {
+        let bin = self.bin(loudness(mean_square));
+        self.counts[bin] += 1;
+        self.mean_squares[bin] += mean_square;
+        self.total_count += 1;
+        self.total_mean_square += mean_square;
+    }
+
+    /// Returns the bin holding blocks of `loudness` in LUFS.
+    fn bin(&self, loudness: f64) -> usize {
+        let bin = ((loudness - ABSOLUTE_GATE_LUFS) / HISTOGRAM_RESOLUTION).floor();
+        (bin.max(0.0) as usize).min(self.counts.len() - 1)
+    }
+
+    /// Returns the loudness at the centre of `bin` in LUFS.
+    fn bin_loudness(bin: usize) -> f64 {
+        ABSOLUTE_GATE_LUFS + (bin as f64 + 0.5) * HISTOGRAM_RESOLUTION
+    }
+
+    /// Returns the first bin `offset` LU below the loudness of the mean of
+    /// every block, None without any block.
+    fn relative_gate(&self, offset: f64) -> Option<usize> {
+        if self.total_count == 0 {
+            return None;
+        }
+        let mean_square = self.total_mean_square / self.total_count as f64;
+        let gate = loudness(mean_square) + offset;
+        // Blocks must lie above the gate, which their bin's centre stands
+        // for.
+        let bin = self.bin(gate);
+        Some(if Self::bin_loudness(bin) > gate {
+            bin
+        } else {
+            bin + 1
+        })
+    }
+
+    /// Returns the mean of the mean squares of the blocks from `first_bin`
+    /// up.
+    fn mean_square_above(&self, first_bin: usize) -> Option<f64> {
+        let first_bin = first_bin.min(self.counts.len());
+        let count: u64 = self.counts[first_bin..].iter().sum();
+        let sum: f64 = self.mean_squares[first_bin..].iter().sum();
+        if count == 0 {
+            None
+        } else {
+            Some(sum / count as f64)
+        }
+    }
+
+    /// Returns the loudness in LUFS below which the fraction `p` of the
+    /// blocks from `first_bin` up lie.
+    fn percentile_above(&self, first_bin: usize, p: f64) -> Option<f64> {
+        let first_bin = first_bin.min(self.counts.len());
+        let counts = &self.counts[first_bin..];
+        let count: u64 = counts.iter().sum();
+        if count == 0 {
+            return None;
+        }
+        // Rank of the block when sorted by loudness, counted from 0.
+        let rank = ((count - 1) as f64 * p).round() as u64;
+        let mut seen = 0;
+        for (bin, &bin_count) in counts.iter().enumerate() {
+            seen += bin_count;
+            if seen > rank {
+                return Some(Self::bin_loudness(first_bin + bin));
+            }
+        }
+        None
+    }
+}
+
+impl Channel {
+    /// Filters a sample, returning its weighted contribution to the energy.
+    fn process(&mut self, sample: f32) -> f64 {
+        self.true_peak.process(sample);
+        let weighted = self.k_weighting.process(sample as f64);
+        self.weight * weighted * weighted
+    }
+}
+
+/// Finds the peak of a signal oversampled with a polyphase windowed sinc.
+struct TruePeakDetector {
+    /// Coefficients of each phase, ordered to match the history.
+    phases: [Vec<f32>; OVERSAMPLING],
+    /// Most recent samples, stored twice so that the last TAPS_PER_PHASE + 1
+    /// are always contiguous.
+    history: Vec<f32>,
+    position: usize,
+    /// Largest absolute value so far.
+    peak: f32,
+}
+
+impl TruePeakDetector {
+    fn new() -> Self {
+        let len = OVERSAMPLING * TAPS_PER_PHASE;
+        let centre = len / 2;
+        // Symmetric window of len + 1 samples. The sinc is cut off at the
+        // original Nyquist frequency.
+        let filter: Vec<f64> = (0..=len)
+            .map(|n| {
+                let x = (n as f64 - centre as f64) / OVERSAMPLING as f64;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * x).sin() / (PI * x)
+                };
+                sinc * WindowFunction::Blackman.value(n, len) as f64
+            })
+            .collect();
+
+        let taps = TAPS_PER_PHASE + 1;
+        let phases = [0, 1, 2, 3].map(|phase| {
+            (0..taps)
+                .map(|j| {
+                    // The newest sample is last in the history.
+                    let n = phase + OVERSAMPLING * (taps - 1 - j);
+                    filter.get(n).cloned().unwrap_or(0.0) as f32
+                })
+                .collect()
+        });
+        TruePeakDetector {
+            phases,
+            history: vec![0.0; taps * 2],
+            position: 0,
+            peak: 0.0,
+        }
+    }
+
+    fn process(&mut self, sample: f32) {
+        let taps = TAPS_PER_PHASE + 1;
+        self.history[self.position] = sample;
+        self.history[self.position + taps] = sample;
+        self.position = (self.position + 1) % taps;
+        let recent = &self.history[self.position..self.position + taps];
+        for phase in &self.phases {
+            let value: f32 = phase.iter().zip(recent).map(|(h, x)| h * x).sum();
+            self.peak = self.peak.max(value.abs());
+        }
+    }
+}
+
+/// Designs the K-weighting filter, a high shelf modelling the head followed
+/// by the RLB high-pass. The analog prototypes are those of the 48 kHz
+/// coefficients given by BS.1770, so any sample rate is supported.
+fn k_weighting(sample_rate: f64) -> Cascade {
+    let shelf = {
+        let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        Biquad::new(
+            [
+                vh + vb * k / q + k * k,
+                2.0 * (k * k - vh),
+                vh - vb * k / q + k * k,
+            ],
+            [
+                1.0 + k / q + k * k,
+                2.0 * (k * k - 1.0),
+                1.0 - k / q + k * k,
+            ],
+        )
+    };
+    let high_pass = {
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        Biquad::new(
+            [a0, -2.0 * a0, a0],
+            [a0, 2.0 * (k * k - 1.0), 1.0 - k / q + k * k],
+        )
+    };
+    Cascade::new(vec![shelf, high_pass], 1.0)
+}
+
+/// Returns the loudness in LUFS of a weighted mean square.
+fn loudness(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+/// Returns the weighted mean square of a loudness in LUFS.
+fn mean_square(loudness: f64) -> f64 {
+    10f64.powf((loudness + 0.691) / 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime_fft::realtime_fft_src::SrcInfo;
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    /// Returns `seconds` of a 997 Hz sine at `level_db` relative to full
+    /// scale.
+    fn sine(level_db: f64, seconds: f64) -> Vec<f32> {
+        let amplitude = 10f64.powf(level_db / 20.0);
+        let len = (seconds * SAMPLE_RATE as f64) as usize;
+        (0..len)
+            .map(|n| (amplitude * (2.0 * PI * 997.0 * n as f64 / SAMPLE_RATE as f64).sin()) as f32)
+            .collect()
+    }
+
+    /// Returns a stereo meter fed with the same `segments` of sine, given
+    /// as level and duration, on both channels.
+    fn stereo_meter(segments: &[(f64, f64)]) -> LoudnessMeter {
+        let mut meter = LoudnessMeter::new(SAMPLE_RATE, LoudnessConfig::stereo()).unwrap();
+        for &(level_db, seconds) in segments {
+            let samples = sine(level_db, seconds);
+            meter.push(&[&samples, &samples]);
+        }
+        meter
+    }
+
+    #[test]
+    fn full_scale_sine_in_one_channel_reads_minus_3_01() {
+        let samples = sine(0.0, 3.0);
+        let mut mono = LoudnessMeter::new(SAMPLE_RATE, LoudnessConfig::mono()).unwrap();
+        mono.push(&[&samples]);
+        let mut stereo = LoudnessMeter::new(SAMPLE_RATE, LoudnessConfig::stereo()).unwrap();
+        stereo.push(&[&samples, &vec![0.0; samples.len()]]);
+
+        for meter in [&mono, &stereo] {
+            assert!((meter.momentary().unwrap() + 3.01).abs() < 0.05);
+            assert!((meter.short_term().unwrap() + 3.01).abs() < 0.05);
+            assert!((meter.integrated().unwrap() + 3.01).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn steady_tones_read_their_level() {
+        // EBU Tech 3341 cases 1 and 2, shortened.
+        for level in [-23.0, -33.0] {
+            let meter = stereo_meter(&[(level, 5.0)]);
+            let expected = level as f32;
+            assert!((meter.momentary().unwrap() - expected).abs() < 0.1);
+            assert!((meter.short_term().unwrap() - expected).abs() < 0.1);
+            assert!((meter.integrated().unwrap() - expected).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn quiet_passages_are_gated() {
+        // EBU Tech 3341 cases 3 and 4 with the durations divided by 5.
+        let meter = stereo_meter(&[(-36.0, 2.0), (-23.0, 12.0), (-36.0, 2.0)]);
+        assert!((meter.integrated().unwrap() + 23.0).abs() < 0.1);
+        let meter = stereo_meter(&[
+            (-72.0, 2.0),
+            (-36.0, 2.0),
+            (-23.0, 12.0),
+            (-36.0, 2.0),
+            (-72.0, 2.0),
+        ]);
+        assert!((meter.integrated().unwrap() + 23.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn loudness_range_spans_the_levels() {
+        // EBU Tech 3342 cases 1 and 2 with the durations halved.
+        let meter = stereo_meter(&[(-20.0, 10.0), (-30.0, 10.0)]);
+        assert!((meter.loudness_range().unwrap() - 10.0).abs() < 1.0);
+        let meter = stereo_meter(&[(-20.0, 10.0), (-15.0, 10.0)]);
+        assert!((meter.loudness_range().unwrap() - 5.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let meter = stereo_meter(&[(-80.0, 5.0)]);
+        assert!(meter.momentary().unwrap() < -70.0);
+        assert_eq!(meter.integrated(), None);
+        assert_eq!(meter.loudness_range(), None);
+    }
+
+    #[test]
+    fn true_peak_finds_peaks_between_samples() {
+        // A sine at a quarter of the sample rate sampled 45 degrees off its
+        // peaks.
+        let samples: Vec<f32> = (0..4000)
+            .map(|n| (PI / 2.0 * n as f64 + PI / 4.0).sin() as f32)
+            .collect();
+        let mut meter = LoudnessMeter::new(SAMPLE_RATE, LoudnessConfig::mono()).unwrap();
+        meter.push(&[&samples]);
+        assert!(meter.true_peak().abs() < 0.2);
+    }
+
+    #[test]
+    fn taps_and_interleaved_samples_match() {
+        let left = sine(-20.0, 1.0);
+        let right = sine(-26.0, 1.0);
+        let interleaved: Vec<f32> = left
+            .iter()
+            .zip(&right)
+            .flat_map(|(&l, &r)| [l, r])
+            .collect();
+        let mut from_interleaved =
+            LoudnessMeter::new(SAMPLE_RATE, LoudnessConfig::stereo()).unwrap();
+        from_interleaved.push_interleaved(&interleaved);
+
+        let mut sources = [SrcInfo::<f32>::new(0), SrcInfo::<f32>::new(0)];
+        let mut taps = [
+            sources[0].add_sample_tap(8000),
+            sources[1].add_sample_tap(8000),
+        ];
+        sources[0].push_callback_data(&left, 0);
+        sources[1].push_callback_data(&right[..4000], 0);
+        let mut from_taps = LoudnessMeter::new(SAMPLE_RATE, LoudnessConfig::stereo()).unwrap();
+        from_taps.push_from(&mut taps);
+        assert_eq!(taps[0].len(), 4000);
+        sources[1].push_callback_data(&right[4000..], 0);
+        from_taps.push_from(&mut taps);
+
+        assert_eq!(from_taps.momentary(), from_interleaved.momentary());
+        assert_eq!(from_taps.true_peak(), from_interleaved.true_peak());
+    }
+
+    #[test]
+    #[should_panic]
+    fn partial_interleaved_frames_are_rejected() {
+        let mut meter = LoudnessMeter::new(SAMPLE_RATE, LoudnessConfig::stereo()).unwrap();
+        meter.push_interleaved(&[0.0; 3]);
+    }
+
+    #[test]
+    fn invalid_weights_are_rejected() {
+        let config = LoudnessConfig {
+            channel_weights: vec![1.0, -1.0],
+        };
+        assert!(LoudnessMeter::new(SAMPLE_RATE, config).is_err());
+        let config = LoudnessConfig {
+            channel_weights: Vec::new(),
+        };
+        assert!(LoudnessMeter::new(SAMPLE_RATE, config).is_err());
+    }
+}
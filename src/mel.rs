@@ -0,0 +1,416 @@
+//! Mel filterbank, log-mel spectra and MFCC features computed from the
+//! spectrum of each RealtimeFft frame.
+
+use crate::frequency_axis::FrequencyAxis;
+use rustfft::num_complex::Complex;
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+/// Smallest mel energy passed to the logarithm.
+const LOG_FLOOR: f32 = 1e-10;
+
+/// Definition of the mel scale.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MelScale {
+    /// 2595 * log10(1 + f / 700) with unnormalised triangular filters.
+    Htk,
+    /// Auditory toolbox scale, linear below 1 kHz. Filters are normalised to
+    /// equal area.
+    Slaney,
+}
+
+impl MelScale {
+    /// Converts a frequency in Hz to mels.
+    pub fn hz_to_mel(&self, hz: f32) -> f32 {
+        match self {
+            MelScale::Htk => 2595.0 * (1.0 + hz / 700.0).log10(),
+            MelScale::Slaney => {
+                if hz < 1000.0 {
+                    3.0 * hz / 200.0
+                } else {
+                    15.0 + 27.0 * (hz / 1000.0).ln() / 6.4f32.ln()
+                }
+            }
+        }
+    }
+
+    /// Converts mels to a frequency in Hz.
+    pub fn mel_to_hz(&self, mel: f32) -> f32 {
+        match self {
+            MelScale::Htk => 700.0 * (10f32.powf(mel / 2595.0) - 1.0),
+            MelScale::Slaney => {
+                if mel < 15.0 {
+                    200.0 * mel / 3.0
+                } else {
+                    1000.0 * (6.4f32.ln() * (mel - 15.0) / 27.0).exp()
+                }
+            }
+        }
+    }
+}
+
+/// Triangular filters equally spaced on the mel scale.
+pub struct MelFilterbank {
+    /// Bins and weights of each filter.
+    filters: Vec<Vec<(usize, f32)>>,
+    /// Centre frequency of each filter in Hz.
+    centres: Vec<f32>,
+}
+
+impl MelFilterbank {
+    /// Returns `num_bands` filters spanning `min_hz` to `max_hz` for spectra
+    /// on `axis`.
+    pub fn new(
+        scale: MelScale,
+        num_bands: usize,
+        min_hz: f32,
+        max_hz: f32,
+        axis: &FrequencyAxis,
+    ) -> Self {
+        let max_hz = max_hz.min(axis.nyquist());
+        let (min_mel, max_mel) = (scale.hz_to_mel(min_hz), scale.hz_to_mel(max_hz));
+        let edges: Vec<f32> = (0..num_bands + 2)
+            .map(|i| {
+                scale.mel_to_hz(min_mel + (max_mel - min_mel) * i as f32 / (num_bands + 1) as f32)
+            })
+            .collect();
+
+        let filters = edges
+            .windows(3)
+            .map(|edges| {
+                let (lower, centre, upper) = (edges[0], edges[1], edges[2]);
+                let norm = match scale {
+                    MelScale::Htk => 1.0,
+                    MelScale::Slaney => 2.0 / (upper - lower),
+                };
+                let first = axis.hz_to_bin(lower).ceil().max(0.0) as usize;
+                let last = (axis.hz_to_bin(upper).floor() as usize).min(axis.num_bins() - 1);
+                (first..=last)
+                    .filter_map(|bin| {
+                        let f = axis.bin_to_hz(bin as f32);
+                        let weight =
+                            ((f - lower) / (centre - lower)).min((upper - f) / (upper - centre));
+                        if weight > 0.0 {
+                            Some((bin, weight * norm))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        MelFilterbank {
+            filters,
+            centres: edges[1..=num_bands].to_vec(),
+        }
+    }
+
+    /// Returns the number of mel bands.
+    pub fn num_bands(&self) -> usize {
+        self.filters.len()
+    }
+
+    /// Returns the centre frequency of each band in Hz.
+    pub fn centres(&self) -> &[f32] {
+        &self.centres
+    }
+
+    /// Applies the filters to a power spectrum.
+    pub fn apply(&self, power: &[f32]) -> Vec<f32> {
+        self.filters
+            .iter()
+            .map(|filter| {
+                filter
+                    .iter()
+                    .filter(|(bin, _)| *bin < power.len())
+                    .map(|&(bin, weight)| weight * power[bin])
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Returns the mel energies of a complex spectrum.
+    pub fn process(&self, spectrum: &[Complex<f32>]) -> Vec<f32> {
+        let power: Vec<f32> = spectrum.iter().map(|bin| bin.norm_sqr()).collect();
+        self.apply(&power)
+    }
+
+    /// Returns the natural logarithm of the mel energies of a spectrum.
+    pub fn log_mel(&self, spectrum: &[Complex<f32>]) -> Vec<f32> {
+        self.process(spectrum)
+            .into_iter()
+            .map(|energy| energy.max(LOG_FLOOR).ln())
+            .collect()
+    }
+}
+
+/// Parameters of the MFCC extractor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MfccConfig {
+    pub scale: MelScale,
+    /// Number of mel bands.
+    pub num_bands: usize,
+    /// Number of cepstral coefficients kept, including c0.
+    pub num_coefficients: usize,
+    pub min_hz: f32,
+    pub max_hz: f32,
+    /// Sinusoidal lifter parameter. No liftering if None.
+    pub lifter: Option<f32>,
+    /// 0 for MFCCs only, 1 to add deltas, 2 to add delta-deltas.
+    pub delta_order: usize,
+    /// Number of frames either side used by the delta regression.
+    pub delta_width: usize,
+}
+
+impl Default for MfccConfig {
+    fn default() -> Self {
+        MfccConfig {
+            scale: MelScale::Htk,
+            num_bands: 40,
+            num_coefficients: 13,
+            min_hz: 0.0,
+            max_hz: 8000.0,
+            lifter: Some(22.0),
+            delta_order: 0,
+            delta_width: 2,
+        }
+    }
+}
+
+/// Features of a single frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MfccFrame {
+    /// Natural log of the mel energies.
+    pub log_mel: Vec<f32>,
+    /// Cepstral coefficients.
+    pub mfcc: Vec<f32>,
+    /// First order regression of the coefficients over time.
+    pub delta: Option<Vec<f32>>,
+    /// Second order regression of the coefficients over time.
+    pub delta_delta: Option<Vec<f32>>,
+}
+
+/// Computes MFCCs from successive spectra. When deltas are requested the
+/// output lags the input by delta_width frames per delta order.
+pub struct MfccExtractor {
+    config: MfccConfig,
+    filterbank: MelFilterbank,
+    /// Orthonormal DCT-II basis, one row per coefficient.
+    dct: Vec<Vec<f32>>,
+    /// Lifter weight of each coefficient.
+    lifter: Vec<f32>,
+    /// Frames waiting for enough neighbours to compute their deltas.
+    pending: VecDeque<MfccFrame>,
+    /// Frames waiting for enough neighbours to compute their delta-deltas.
+    pending_delta: VecDeque<MfccFrame>,
+}
+
+impl MfccExtractor {
+    /// Returns an extractor for spectra on `axis`.
+    pub fn new(config: MfccConfig, axis: &FrequencyAxis) -> Self {
+        let filterbank = MelFilterbank::new(
+            config.scale,
+            config.num_bands,
+            config.min_hz,
+            config.max_hz,
+            axis,
+        );
+        let m = config.num_bands as f32;
+        let dct = (0..config.num_coefficients)
+            .map(|k| {
+                let scale = if k == 0 {
+                    (1.0 / m).sqrt()
+                } else {
+                    (2.0 / m).sqrt()
+                };
+                (0..config.num_bands)
+                    .map(|n| scale * (PI * k as f32 * (n as f32 + 0.5) / m).cos())
+                    .collect()
+            })
+            .collect();
+        let lifter = (0..config.num_coefficients)
+            .map(|k| match config.lifter {
+                Some(l) if l > 0.0 => 1.0 + l / 2.0 * (PI * k as f32 / l).sin(),
+                _ => 1.0,
+            })
+            .collect();
+        MfccExtractor {
+            config,
+            filterbank,
+            dct,
+            lifter,
+            pending: VecDeque::new(),
+            pending_delta: VecDeque::new(),
+        }
+    }
+
+    /// Returns the mel filterbank.
+    pub fn filterbank(&self) -> &MelFilterbank {
+        &self.filterbank
+    }
+
+    /// Forgets the frames buffered for the delta computation.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+        self.pending_delta.clear();
+    }
+
+    /// Adds the spectrum of a new frame. Returns the features of the oldest
+    /// frame whose deltas could be completed, if any.
+    pub fn process(&mut self, spectrum: &[Complex<f32>]) -> Option<MfccFrame> {
+        let log_mel = self.filterbank.log_mel(spectrum);
+        let mfcc = self
+            .dct
+            .iter()
+            .zip(&self.lifter)
+            .map(|(basis, lifter)| {
+                lifter * basis.iter().zip(&log_mel).map(|(b, x)| b * x).sum::<f32>()
+            })
+            .collect();
+        let frame = MfccFrame {
+            log_mel,
+            mfcc,
+            delta: None,
+            delta_delta: None,
+        };
+        if self.config.delta_order == 0 {
+            return Some(frame);
+        }
+
+        let width = self.config.delta_width.max(1);
+        let mut frame = push_window(&mut self.pending, frame, width)?;
+        frame.delta = Some(frame_delta(&self.pending, width, |frame| &frame.mfcc));
+        if self.config.delta_order == 1 {
+            return Some(frame);
+        }
+
+        let mut frame = push_window(&mut self.pending_delta, frame, width)?;
+        frame.delta_delta = Some(frame_delta(&self.pending_delta, width, |frame| {
+            frame.delta.as_ref().unwrap()
+        }));
+        Some(frame)
+    }
+}
+
+/// Pushes a frame into a regression window of 2 * width + 1 frames,
+/// replicating the first frame to pad the start. Returns a copy of the
+/// centre frame once the window is full.
+fn push_window(
+    window: &mut VecDeque<MfccFrame>,
+    frame: MfccFrame,
+    width: usize,
+) -> Option<MfccFrame> {
+    if window.is_empty() {
+        for _ in 0..width {
+            window.push_back(frame.clone());
+        }
+    }
+    window.push_back(frame);
+    while window.len() > 2 * width + 1 {
+        window.pop_front();
+    }
+    if window.len() < 2 * width + 1 {
+        return None;
+    }
+    Some(window[width].clone())
+}
+
+/// Regression of the values over the frames of a full window, evaluated at
+/// the centre frame.
+fn frame_delta<F>(window: &VecDeque<MfccFrame>, width: usize, values: F) -> Vec<f32>
+where
+    F: Fn(&MfccFrame) -> &Vec<f32>,
+{
+    let norm = 2.0 * (1..=width).map(|n| (n * n) as f32).sum::<f32>();
+    let len = values(&window[width]).len();
+    (0..len)
+        .map(|i| {
+            (1..=width)
+                .map(|n| n as f32 * (values(&window[width + n])[i] - values(&window[width - n])[i]))
+                .sum::<f32>()
+                / norm
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mel_scales() {
+        assert!((MelScale::Htk.hz_to_mel(1000.0) - 1000.0).abs() < 0.1);
+        assert!((MelScale::Slaney.hz_to_mel(1000.0) - 15.0).abs() < 1e-4);
+        assert!((MelScale::Slaney.hz_to_mel(6400.0) - 42.0).abs() < 1e-4);
+        for &scale in &[MelScale::Htk, MelScale::Slaney] {
+            for &hz in &[0.0, 440.0, 999.0, 1001.0, 8000.0] {
+                assert!((scale.mel_to_hz(scale.hz_to_mel(hz)) - hz).abs() < 0.01);
+            }
+        }
+    }
+
+    #[test]
+    fn centres_are_evenly_spaced_in_mels() {
+        let axis = FrequencyAxis::new(16000, 512);
+        let filterbank = MelFilterbank::new(MelScale::Htk, 10, 300.0, 8000.0, &axis);
+        assert_eq!(filterbank.num_bands(), 10);
+        let step = (MelScale::Htk.hz_to_mel(8000.0) - MelScale::Htk.hz_to_mel(300.0)) / 11.0;
+        for (i, &centre) in filterbank.centres().iter().enumerate() {
+            let mel = MelScale::Htk.hz_to_mel(300.0) + step * (i + 1) as f32;
+            assert!((MelScale::Htk.hz_to_mel(centre) - mel).abs() < 0.01);
+        }
+
+        // A tone on a centre only reaches its own filter.
+        let mut power = vec![0.0; axis.num_bins()];
+        let bin = axis.nearest_bin(filterbank.centres()[4]);
+        power[bin] = 1.0;
+        let energies = filterbank.apply(&power);
+        assert!(energies[4] > 0.9);
+        assert!(energies[3] < 0.1 && energies[5] < 0.1);
+    }
+
+    #[test]
+    fn flat_spectrum_has_flat_cepstrum() {
+        // Slaney filters have equal area so white noise gives equal energies.
+        let axis = FrequencyAxis::new(16000, 4096);
+        let config = MfccConfig {
+            scale: MelScale::Slaney,
+            lifter: None,
+            ..MfccConfig::default()
+        };
+        let mut extractor = MfccExtractor::new(config, &axis);
+        let spectrum = vec![Complex::new(1.0, 0.0); axis.num_bins()];
+        let frame = extractor.process(&spectrum).unwrap();
+
+        let energy = 1.0 / axis.bin_width();
+        assert!((frame.mfcc[0] / (40f32.sqrt() * energy.ln()) - 1.0).abs() < 0.01);
+        assert!(frame.mfcc[1..].iter().all(|c| c.abs() < 0.05));
+    }
+
+    #[test]
+    fn deltas_of_a_rising_level() {
+        let axis = FrequencyAxis::new(16000, 512);
+        let config = MfccConfig {
+            delta_order: 2,
+            ..MfccConfig::default()
+        };
+        let mut extractor = MfccExtractor::new(config, &axis);
+        // Power grows by e every frame so every log-mel energy rises by 1.
+        let frames: Vec<MfccFrame> = (0..12)
+            .filter_map(|t| {
+                let amplitude = (t as f32 / 2.0).exp();
+                extractor.process(&vec![Complex::new(amplitude, 0.0); axis.num_bins()])
+            })
+            .collect();
+        // Each delta order delays the output by delta_width frames.
+        assert_eq!(frames.len(), 8);
+
+        let last = frames.last().unwrap();
+        let delta = last.delta.as_ref().unwrap();
+        let delta_delta = last.delta_delta.as_ref().unwrap();
+        assert!((delta[0] - 40f32.sqrt()).abs() < 1e-3);
+        assert!(delta[1..].iter().all(|d| d.abs() < 1e-3));
+        assert!(delta_delta.iter().all(|d| d.abs() < 1e-3));
+    }
+}
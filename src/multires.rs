@@ -0,0 +1,361 @@
+//! Multi-resolution spectrum. Several fft sizes run over the same samples,
+//! long windows resolving the bass and short ones following the treble, and
+//! are stitched into a single spectrum on the grid of the longest fft.
+
+use crate::error::ConfigError;
+use crate::frequency_axis::FrequencyAxis;
+use crate::realtime_fft::realtime_fft_src::SampleTap;
+use crate::sample_window::SampleWindow;
+use crate::window::{self, WindowFunction};
+use realfft::{RealFftPlanner, RealToComplex};
+use rustfft::num_complex::Complex;
+use std::cell::RefCell;
+use std::f32::consts::PI;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// One fft size and the frequencies it covers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Resolution {
+    pub fft_size: usize,
+    /// Crossover to the next, shorter resolution in Hz. Ignored for the
+    /// last resolution which covers up to Nyquist.
+    pub max_hz: f32,
+}
+
+/// Units of the stitched spectrum. Both are independent of the fft size so
+/// the resolutions agree at the crossovers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scaling {
+    /// Peak amplitude of a sinusoid.
+    Amplitude,
+    /// One-sided power spectral density, for broadband signals.
+    PowerDensity,
+}
+
+/// Parameters of the multi-resolution analyzer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MultiResolutionConfig {
+    /// Resolutions from the longest to the shortest fft.
+    pub resolutions: Vec<Resolution>,
+    pub window: WindowFunction,
+    /// Width of the crossfade around each crossover in octaves.
+    pub crossover_octaves: f32,
+    pub scaling: Scaling,
+}
+
+impl MultiResolutionConfig {
+    /// Checks that there is at least one resolution, that the fft sizes
+    /// shrink and the crossovers rise from one resolution to the next.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let first = self
+            .resolutions
+            .first()
+            .ok_or_else(|| ConfigError::new("at least one resolution is needed"))?;
+        if first.fft_size < 2 {
+            return Err(ConfigError::new("fft sizes must be at least 2"));
+        }
+        for pair in self.resolutions.windows(2) {
+            if pair[1].fft_size >= pair[0].fft_size || pair[1].fft_size < 2 {
+                return Err(ConfigError::new(
+                    "fft sizes must shrink from the longest to the shortest, down to 2",
+                ));
+            }
+            if pair[0].max_hz.is_nan() || pair[0].max_hz <= 0.0 {
+                return Err(ConfigError::new("crossovers must be positive"));
+            }
+        }
+        let crossovers = &self.resolutions[..self.resolutions.len() - 1];
+        if crossovers
+            .windows(2)
+            .any(|pair| pair[1].max_hz <= pair[0].max_hz)
+        {
+            return Err(ConfigError::new("crossovers must rise"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for MultiResolutionConfig {
+    fn default() -> Self {
+        MultiResolutionConfig {
+            resolutions: vec![
+                Resolution {
+                    fft_size: 16384,
+                    max_hz: 250.0,
+                },
+                Resolution {
+                    fft_size: 4096,
+                    max_hz: 2000.0,
+                },
+                Resolution {
+                    fft_size: 1024,
+                    max_hz: 0.0,
+                },
+            ],
+            window: WindowFunction::Hann,
+            crossover_octaves: 0.5,
+            scaling: Scaling::Amplitude,
+        }
+    }
+}
+
+/// Analysis at one fft size.
+struct Band {
+    fft_size: usize,
+    real_to_complex: Arc<dyn RealToComplex<f32>>,
+    window: Vec<f32>,
+    indata: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    /// Scaled spectrum.
+    scaled: Vec<f32>,
+    /// Weight of this band in each bin of the stitched spectrum.
+    weights: Vec<f32>,
+}
+
+/// Stitches spectra of several fft sizes into one.
+pub struct MultiResolutionAnalyzer {
+    config: MultiResolutionConfig,
+    axis: FrequencyAxis,
+    bands: Vec<Band>,
+    /// Most recent samples, as many as the longest fft needs.
+    history: SampleWindow<f32>,
+    output: Vec<f32>,
+}
+
+impl MultiResolutionAnalyzer {
+    /// Returns an analyzer for a signal at `sample_rate`, planning its ffts
+    /// with the given planner.
+    pub fn new(
+        fft_planner: &Rc<RefCell<RealFftPlanner<f32>>>,
+        sample_rate: u32,
+        config: MultiResolutionConfig,
+    ) -> Result<Self, ConfigError> {
+        config.validate()?;
+        let longest = config.resolutions[0].fft_size;
+        let axis = FrequencyAxis::new(sample_rate, longest);
+
+        // Share of the spectrum handed from each band to the next shorter one.
+        let handover = |crossover: f32, hz: f32| {
+            if hz <= 0.0 {
+                return 0.0;
+            }
+            let position = (hz / crossover).log2() / config.crossover_octaves.max(f32::EPSILON);
+            let position = position.clamp(-0.5, 0.5);
+            0.5 + 0.5 * (PI * position).sin()
+        };
+
+        let num_resolutions = config.resolutions.len();
+        let bands = config
+            .resolutions
+            .iter()
+            .enumerate()
+            .map(|(i, resolution)| {
+                let real_to_complex = fft_planner
+                    .borrow_mut()
+                    .plan_fft_forward(resolution.fft_size);
+                let weights = axis
+                    .frequencies()
+                    .map(|hz| {
+                        let reached: f32 = config.resolutions[..i]
+                            .iter()
+                            .map(|previous| handover(previous.max_hz, hz))
+                            .product();
+                        if i + 1 < num_resolutions {
+                            reached * (1.0 - handover(resolution.max_hz, hz))
+                        } else {
+                            reached
+                        }
+                    })
+                    .collect();
+                let spectrum = real_to_complex.make_output_vec();
+                Band {
+                    fft_size: resolution.fft_size,
+                    window: config.window.coefficients(resolution.fft_size),
+                    indata: real_to_complex.make_input_vec(),
+                    scratch: real_to_complex.make_scratch_vec(),
+                    scaled: vec![0.0; spectrum.len()],
+                    spectrum,
+                    weights,
+                    real_to_complex,
+                }
+            })
+            .collect();
+
+        Ok(MultiResolutionAnalyzer {
+            axis,
+            bands,
+            history: SampleWindow::new(longest),
+            output: vec![0.0; axis.num_bins()],
+            config,
+        })
+    }
+
+    /// Returns the configuration of the analyzer.
+    pub fn config(&self) -> &MultiResolutionConfig {
+        &self.config
+    }
+
+    /// Returns the frequency axis of the stitched spectrum.
+    pub fn frequency_axis(&self) -> FrequencyAxis {
+        self.axis
+    }
+
+    /// Adds samples, keeping only as many as the longest fft needs.
+    pub fn push(&mut self, samples: &[f32]) {
+        self.history.push(samples);
+    }
+
+    /// Adds every sample available in a tap of the audio source.
+    pub fn push_from(&mut self, tap: &mut SampleTap) {
+        tap.drain(|samples| self.push(samples));
+    }
+
+    /// Forgets the buffered samples.
+    pub fn reset(&mut self) {
+        self.history.reset();
+    }
+
+    /// Computes the stitched spectrum of the most recent samples. Every
+    /// window ends at the newest sample. Returns None until the longest fft
+    /// can be filled.
+    pub fn process(&mut self) -> Option<&[f32]> {
+        if !self.history.is_full() {
+            return None;
+        }
+        let sample_rate = self.axis.sample_rate() as f32;
+        let scaling = self.config.scaling;
+        self.output.iter_mut().for_each(|x| *x = 0.0);
+
+        for band in &mut self.bands {
+            let frame = self.history.latest(band.fft_size);
+            for ((input, &sample), &w) in band.indata.iter_mut().zip(frame).zip(&band.window) {
+                *input = sample * w;
+            }
+            band.real_to_complex
+                .process_with_scratch(&mut band.indata, &mut band.spectrum, &mut band.scratch)
+                .unwrap();
+
+            let norm = match scaling {
+                Scaling::Amplitude => 2.0 / window::coherent_gain(&band.window),
+                Scaling::PowerDensity => 2.0 / (sample_rate * window::power_gain(&band.window)),
+            };
+            for (k, (scaled, bin)) in band.scaled.iter_mut().zip(&band.spectrum).enumerate() {
+                // DC and Nyquist aren't doubled.
+                let edge = if k == 0 || 2 * k == band.fft_size {
+                    0.5
+                } else {
+                    1.0
+                };
+                *scaled = match scaling {
+                    Scaling::Amplitude => edge * norm * bin.norm(),
+                    Scaling::PowerDensity => edge * norm * bin.norm_sqr(),
+                };
+            }
+
+            // Interpolate linearly onto the grid of the longest fft.
+            let ratio = band.fft_size as f32 / self.history.capacity() as f32;
+            let last = band.scaled.len() - 1;
+            for (k, (output, &weight)) in self.output.iter_mut().zip(&band.weights).enumerate() {
+                if weight == 0.0 {
+                    continue;
+                }
+                let position = k as f32 * ratio;
+                let lower = (position as usize).min(last);
+                let upper = (lower + 1).min(last);
+                let fraction = position - lower as f32;
+                let value = band.scaled[lower] * (1.0 - fraction) + band.scaled[upper] * fraction;
+                *output += weight * value;
+            }
+        }
+        Some(&self.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime_fft::realtime_fft_src::SrcInfo;
+
+    fn config() -> MultiResolutionConfig {
+        MultiResolutionConfig {
+            resolutions: vec![
+                Resolution {
+                    fft_size: 4096,
+                    max_hz: 250.0,
+                },
+                Resolution {
+                    fft_size: 1024,
+                    max_hz: 2000.0,
+                },
+                Resolution {
+                    fft_size: 256,
+                    max_hz: 0.0,
+                },
+            ],
+            ..MultiResolutionConfig::default()
+        }
+    }
+
+    fn analyzer(config: MultiResolutionConfig) -> Result<MultiResolutionAnalyzer, ConfigError> {
+        let planner = Rc::new(RefCell::new(RealFftPlanner::new()));
+        MultiResolutionAnalyzer::new(&planner, 16000, config)
+    }
+
+    fn sine(hz: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| (2.0 * PI * hz * n as f32 / 16000.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn every_band_reads_sine_amplitudes() {
+        let mut analyzer = analyzer(config()).unwrap();
+        let axis = analyzer.frequency_axis();
+        assert_eq!(axis.fft_size(), 4096);
+        for hz in [125.0, 1000.0, 4000.0, 6000.0] {
+            analyzer.reset();
+            analyzer.push(&sine(hz, 3000));
+            assert!(analyzer.process().is_none());
+            analyzer.push(&sine(hz, 5000));
+            let spectrum = analyzer.process().unwrap();
+            let amplitude = spectrum[axis.nearest_bin(hz)];
+            assert!(
+                (amplitude - 1.0).abs() < 0.01,
+                "{} Hz read {}",
+                hz,
+                amplitude
+            );
+        }
+    }
+
+    #[test]
+    fn tap_is_drained() {
+        let mut src_info: SrcInfo = SrcInfo::new(0);
+        let mut tap = src_info.add_sample_tap(8192);
+        src_info.push_callback_data(&sine(1000.0, 8000), 0);
+        let mut analyzer = analyzer(config()).unwrap();
+        analyzer.push_from(&mut tap);
+        assert!(tap.is_empty());
+        assert!(analyzer.process().is_some());
+    }
+
+    #[test]
+    fn resolutions_must_run_from_longest_to_shortest() {
+        let mut growing = config();
+        growing.resolutions.reverse();
+        assert!(analyzer(growing).is_err());
+
+        let mut falling_crossovers = config();
+        falling_crossovers.resolutions[1].max_hz = 200.0;
+        assert!(analyzer(falling_crossovers).is_err());
+
+        let empty = MultiResolutionConfig {
+            resolutions: Vec::new(),
+            ..config()
+        };
+        assert!(analyzer(empty).is_err());
+        assert!(analyzer(MultiResolutionConfig::default()).is_ok());
+    }
+}
@@ -0,0 +1,85 @@
+//! Musical pitch helpers shared by the chroma and pitch analyses.
+
+use std::fmt;
+
+/// Names of the twelve pitch classes starting from C.
+pub const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Maps frequencies to equal tempered notes given the pitch of A4.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tuning {
+    /// Frequency of A4 in Hz.
+    pub a4_hz: f32,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning { a4_hz: 440.0 }
+    }
+}
+
+impl Tuning {
+    /// Returns the fractional MIDI note number of a frequency.
+    pub fn hz_to_midi(&self, hz: f32) -> f32 {
+        69.0 + 12.0 * (hz / self.a4_hz).log2()
+    }
+
+    /// Returns the frequency of a fractional MIDI note number.
+    pub fn midi_to_hz(&self, midi: f32) -> f32 {
+        self.a4_hz * 2f32.powf((midi - 69.0) / 12.0)
+    }
+
+    /// Returns the nearest note and the deviation from it in cents.
+    pub fn nearest_note(&self, hz: f32) -> (Note, f32) {
+        let midi = self.hz_to_midi(hz);
+        let nearest = midi.round();
+        (Note(nearest as i32), (midi - nearest) * 100.0)
+    }
+}
+
+/// An equal tempered note given by its MIDI number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Note(pub i32);
+
+impl Note {
+    /// Returns the pitch class, 0 being C.
+    pub fn pitch_class(&self) -> usize {
+        self.0.rem_euclid(12) as usize
+    }
+
+    /// Returns the scientific pitch notation octave, C4 being middle C.
+    pub fn octave(&self) -> i32 {
+        self.0.div_euclid(12) - 1
+    }
+
+    /// Returns the name of the pitch class.
+    pub fn name(&self) -> &'static str {
+        NOTE_NAMES[self.pitch_class()]
+    }
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.name(), self.octave())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notes_of_frequencies() {
+        let tuning = Tuning::default();
+        assert_eq!(tuning.hz_to_midi(440.0), 69.0);
+        assert!((tuning.midi_to_hz(60.0) - 261.6256).abs() < 1e-3);
+
+        let (note, cents) = tuning.nearest_note(261.6256 * 2f32.powf(10.0 / 1200.0));
+        assert_eq!(note, Note(60));
+        assert!((cents - 10.0).abs() < 0.01);
+        assert_eq!(note.to_string(), "C4");
+        assert_eq!(Note(-1).to_string(), "B-2");
+    }
+}
@@ -0,0 +1,470 @@
+//! Fractional-octave band analysis. Band centres follow IEC 61260-1 (base 10)
+//! with ISO 266 nominal frequencies. Levels come either from the RealtimeFft
+//! spectrum or from a bank of time domain band-pass filters which stays
+//! accurate at low frequencies where the fft resolution is too coarse.
+
+use crate::biquad::{self, Cascade};
+use crate::error::ConfigError;
+use crate::frequency_axis::FrequencyAxis;
+use crate::realtime_fft::realtime_fft_src::SampleTap;
+use crate::window::{self, WindowFunction};
+use rustfft::num_complex::Complex;
+
+/// Octave ratio of IEC 61260-1.
+const OCTAVE_RATIO: f64 = 1.995_262_314_968_879_6; // 10^(3/10)
+/// Reference frequency of the band series.
+const REFERENCE_FREQUENCY: f64 = 1000.0;
+/// ISO 266 R10 preferred numbers.
+const R10: [f32; 10] = [1.0, 1.25, 1.6, 2.0, 2.5, 3.15, 4.0, 5.0, 6.3, 8.0];
+/// Band indices are limited to this many octaves either side of 1 kHz.
+const MAX_OCTAVES: i32 = 30;
+
+/// Width of each band as a fraction of an octave.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BandFraction {
+    Octave,
+    Third,
+    Sixth,
+    Twelfth,
+    TwentyFourth,
+}
+
+impl BandFraction {
+    /// Number of bands per octave.
+    pub fn bands_per_octave(&self) -> u32 {
+        match self {
+            BandFraction::Octave => 1,
+            BandFraction::Third => 3,
+            BandFraction::Sixth => 6,
+            BandFraction::Twelfth => 12,
+            BandFraction::TwentyFourth => 24,
+        }
+    }
+
+    /// Exact mid-band frequency of band `index`. Band 0 is centred on 1 kHz
+    /// for odd fractions and just above it for even ones.
+    pub fn exact_centre(&self, index: i32) -> f64 {
+        let b = self.bands_per_octave() as f64;
+        let exponent = if self.bands_per_octave() % 2 == 1 {
+            index as f64 / b
+        } else {
+            (2 * index + 1) as f64 / (2.0 * b)
+        };
+        REFERENCE_FREQUENCY * OCTAVE_RATIO.powf(exponent)
+    }
+
+    /// Returns the index of the band whose centre is closest to `frequency`,
+    /// limited to MAX_OCTAVES either side of 1 kHz.
+    fn nearest_index(&self, frequency: f64) -> i32 {
+        let b = self.bands_per_octave() as f64;
+        let octaves = (frequency / REFERENCE_FREQUENCY).ln() / OCTAVE_RATIO.ln();
+        let index = if self.bands_per_octave() % 2 == 1 {
+            (octaves * b).round()
+        } else {
+            ((octaves * 2.0 * b - 1.0) / 2.0).round()
+        };
+        let limit = (MAX_OCTAVES * self.bands_per_octave() as i32) as f64;
+        index.max(-limit).min(limit) as i32
+    }
+}
+
+/// A single fractional-octave band.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Band {
+    /// Index of the band relative to the 1 kHz band.
+    pub index: i32,
+    /// Exact mid-band frequency in Hz.
+    pub centre: f32,
+    /// Nominal mid-band frequency for labelling, in Hz.
+    pub nominal_centre: f32,
+    /// Lower band edge in Hz.
+    pub lower: f32,
+    /// Upper band edge in Hz.
+    pub upper: f32,
+}
+
+/// Returns every band whose centre lies between `min_hz` and `max_hz`. The
+/// limits must be positive and finite with `min_hz` not above `max_hz`.
+pub fn bands(fraction: BandFraction, min_hz: f32, max_hz: f32) -> Result<Vec<Band>, ConfigError> {
+    if !(min_hz > 0.0 && max_hz.is_finite()) {
+        return Err(ConfigError::new(
+            "min_hz and max_hz must be positive and finite",
+        ));
+    }
+    if min_hz > max_hz {
+        return Err(ConfigError::new(format!(
+            "min_hz of {} Hz is above max_hz of {} Hz",
+            min_hz, max_hz
+        )));
+    }
+    let half_width = OCTAVE_RATIO.powf(1.0 / (2.0 * fraction.bands_per_octave() as f64));
+    let first = fraction.nearest_index(min_hz as f64);
+    let last = fraction.nearest_index(max_hz as f64);
+    let bands = (first..=last)
+        .map(|index| {
+            let centre = fraction.exact_centre(index);
+            Band {
+                index,
+                centre: centre as f32,
+                nominal_centre: nominal_frequency(fraction, centre),
+                lower: (centre / half_width) as f32,
+                upper: (centre * half_width) as f32,
+            }
+        })
+        .filter(|band| band.centre >= min_hz * 0.99 && band.centre <= max_hz * 1.01)
+        .collect();
+    Ok(bands)
+}
+
+/// Nominal frequency of a band. Octave and third-octave bands use the ISO 266
+/// preferred numbers, narrower bands the exact centre to three significant
+/// figures.
+fn nominal_frequency(fraction: BandFraction, centre: f64) -> f32 {
+    match fraction {
+        BandFraction::Octave | BandFraction::Third => {
+            let step = (10.0 * centre.log10()).round() as i32;
+            let decade = step.div_euclid(10);
+            R10[step.rem_euclid(10) as usize] * 10f32.powi(decade)
+        }
+        _ => {
+            let magnitude = 10f64.powi(centre.log10().floor() as i32 - 2);
+            ((centre / magnitude).round() * magnitude) as f32
+        }
+    }
+}
+
+/// Band levels computed from the RealtimeFft spectrum. Bins straddling a band
+/// edge contribute in proportion to the part of the bin inside the band.
+pub struct SpectrumBandAnalyzer {
+    bands: Vec<Band>,
+    /// Bins and weights making up each band.
+    weights: Vec<Vec<(usize, f32)>>,
+    /// Converts the sum of squared magnitudes to mean square.
+    normalization: f32,
+}
+
+impl SpectrumBandAnalyzer {
+    /// Returns an analyzer for spectra on `axis` computed with `window_size`
+    /// samples of `window`. The window may be shorter than the fft when the
+    /// frames are zero-padded.
+    pub fn new(
+        fraction: BandFraction,
+        min_hz: f32,
+        max_hz: f32,
+        axis: &FrequencyAxis,
+        window: WindowFunction,
+        window_size: usize,
+    ) -> Result<Self, ConfigError> {
+        if window_size == 0 || window_size > axis.fft_size() {
+            return Err(ConfigError::new(format!(
+                "window_size must be between 1 and the fft size of {}",
+                axis.fft_size()
+            )));
+        }
+        let bands = bands(fraction, min_hz, max_hz.min(axis.nyquist()))?;
+        let bin_width = axis.bin_width();
+        let weights = bands
+            .iter()
+            .map(|band| {
+                let first = axis.hz_to_bin(band.lower).round().max(0.0) as usize;
+                let last = (axis.hz_to_bin(band.upper).round() as usize).min(axis.num_bins() - 1);
+                (first..=last)
+                    .filter_map(|bin| {
+                        let centre = axis.bin_to_hz(bin as f32);
+                        let low = (centre - bin_width / 2.0).max(0.0).max(band.lower);
+                        let high = (centre + bin_width / 2.0).min(band.upper);
+                        let overlap = (high - low) / bin_width;
+                        if overlap > 0.0 {
+                            Some((bin, overlap))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // One-sided Parseval with the power gain of the window. Zero-padding
+        // adds no energy, so the gain is that of the window actually applied.
+        let coefficients = window.coefficients(window_size);
+        let normalization = 2.0 / (axis.fft_size() as f32 * window::power_gain(&coefficients));
+
+        Ok(SpectrumBandAnalyzer {
+            bands,
+            weights,
+            normalization,
+        })
+    }
+
+    /// Returns the bands analysed.
+    pub fn bands(&self) -> &[Band] {
+        &self.bands
+    }
+
+    /// Returns the mean square of the signal in each band.
+    pub fn process(&self, spectrum: &[Complex<f32>]) -> Vec<f32> {
+        self.weights
+            .iter()
+            .map(|weights| {
+                let sum: f32 = weights
+                    .iter()
+                    .filter(|(bin, _)| *bin < spectrum.len())
+                    .map(|&(bin, weight)| weight * spectrum[bin].norm_sqr())
+                    .sum();
+                sum * self.normalization
+            })
+            .collect()
+    }
+
+    /// Returns the level of each band in dB relative to a mean square of 1.
+    pub fn levels_db(&self, spectrum: &[Complex<f32>]) -> Vec<f32> {
+        self.process(spectrum)
+            .into_iter()
+            .map(power_to_db)
+            .collect()
+    }
+}
+
+/// Band levels computed by a bank of Butterworth band-pass filters running
+/// on the time signal.
+pub struct FilterBankAnalyzer {
+    bands: Vec<Band>,
+    filters: Vec<Cascade>,
+    /// Sum of squared filter outputs since the last call to take_levels.
+    energy: Vec<f64>,
+    samples: usize,
+}
+
+impl FilterBankAnalyzer {
+    /// Order of the band-pass filters in pole pairs. Each filter is 3 dB
+    /// down at the band edges.
+    const ORDER: usize = 3;
+
+    /// Returns a filter bank for a signal at `sample_rate`.
+    pub fn new(
+        fraction: BandFraction,
+        min_hz: f32,
+        max_hz: f32,
+        sample_rate: u32,
+    ) -> Result<Self, ConfigError> {
+        // Bands reaching past Nyquist can't be realised.
+        let nyquist = sample_rate as f32 / 2.0;
+        let bands: Vec<Band> = bands(fraction, min_hz, max_hz)?
+            .into_iter()
+            .filter(|band| band.upper < nyquist)
+            .collect();
+        let filters = bands
+            .iter()
+            .map(|band| {
+                biquad::butterworth_bandpass(
+                    Self::ORDER,
+                    band.lower as f64,
+                    band.upper as f64,
+                    sample_rate as f64,
+                )
+            })
+            .collect();
+        Ok(FilterBankAnalyzer {
+            energy: vec![0.0; bands.len()],
+            bands,
+            filters,
+            samples: 0,
+        })
+    }
+
+    /// Returns the bands analysed.
+    pub fn bands(&self) -> &[Band] {
+        &self.bands
+    }
+
+    /// Filters a block of samples.
+    pub fn process(&mut self, samples: &[f32]) {
+        for (filter, energy) in self.filters.iter_mut().zip(self.energy.iter_mut()) {
+            for &sample in samples {
+                let y = filter.process(sample as f64);
+                *energy += y * y;
+            }
+        }
+        self.samples += samples.len();
+    }
+
+    /// Filters every sample available in a tap of the audio source.
+    pub fn push_from(&mut self, tap: &mut SampleTap) {
+        tap.drain(|samples| self.process(samples));
+    }
+
+    /// Returns the mean square in each band since the last call and starts a
+    /// new measurement interval.
+    pub fn take_levels(&mut self) -> Vec<f32> {
+        let samples = self.samples.max(1) as f64;
+        let levels = self
+            .energy
+            .iter_mut()
+            .map(|energy| (std::mem::replace(energy, 0.0) / samples) as f32)
+            .collect();
+        self.samples = 0;
+        levels
+    }
+
+    /// Clears the filter states and accumulated energy.
+    pub fn reset(&mut self) {
+        self.filters.iter_mut().for_each(Cascade::reset);
+        self.energy.iter_mut().for_each(|energy| *energy = 0.0);
+        self.samples = 0;
+    }
+}
+
+/// Converts a mean square to dB, clamping silence to a finite level.
+pub fn power_to_db(power: f32) -> f32 {
+    10.0 * power.max(1e-20).log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn sine(frequency: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| (2.0 * PI * frequency * n as f32 / sample_rate as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn third_octave_nominal_centres() {
+        let nominal: Vec<f32> = bands(BandFraction::Third, 20.0, 20000.0)
+            .unwrap()
+            .iter()
+            .map(|band| band.nominal_centre)
+            .collect();
+        let expected = [
+            20.0, 25.0, 31.5, 40.0, 50.0, 63.0, 80.0, 100.0, 125.0, 160.0, 200.0, 250.0, 315.0,
+            400.0, 500.0, 630.0, 800.0, 1000.0, 1250.0, 1600.0, 2000.0, 2500.0, 3150.0, 4000.0,
+            5000.0, 6300.0, 8000.0, 10000.0, 12500.0, 16000.0, 20000.0,
+        ];
+        assert_eq!(nominal.len(), expected.len());
+        for (a, b) in nominal.iter().zip(expected.iter()) {
+            assert!((a / b - 1.0).abs() < 1e-4);
+        }
+
+        // Octave bands are centred on exact base 10 frequencies.
+        let octaves = bands(BandFraction::Octave, 31.5, 16000.0).unwrap();
+        assert!((octaves[0].centre - 31.623).abs() < 1e-3);
+        assert!((octaves[0].upper / octaves[0].lower - 1.9953).abs() < 1e-4);
+    }
+
+    #[test]
+    fn filters_are_3_db_down_at_band_edges() {
+        let bank = FilterBankAnalyzer::new(BandFraction::Third, 25.0, 16000.0, 48000).unwrap();
+        for (band, filter) in bank.bands.iter().zip(&bank.filters) {
+            let gain_db =
+                |frequency: f32| 20.0 * filter.response(frequency as f64, 48000.0).norm().log10();
+            assert!(gain_db(band.centre).abs() < 0.01);
+            assert!((gain_db(band.lower) + 3.01).abs() < 0.05);
+            assert!((gain_db(band.upper) + 3.01).abs() < 0.05);
+            // An octave away from a third-octave band.
+            assert!(gain_db(band.centre / 2.0) < -40.0);
+            if band.centre * 2.0 < 24000.0 {
+                assert!(gain_db(band.centre * 2.0) < -40.0);
+            }
+        }
+    }
+
+    #[test]
+    fn sine_level_in_its_band() {
+        // A unit sine has a mean square of 1/2, -3.01 dB.
+        let samples = sine(1000.0, 48000, 48000);
+        let mut bank = FilterBankAnalyzer::new(BandFraction::Octave, 250.0, 4000.0, 48000).unwrap();
+        bank.process(&samples[..24000]);
+        bank.take_levels();
+        bank.process(&samples[24000..]);
+        let levels: Vec<f32> = bank.take_levels().into_iter().map(power_to_db).collect();
+        let band = bank
+            .bands()
+            .iter()
+            .position(|band| band.index == 0)
+            .unwrap();
+        assert!((levels[band] + 3.01).abs() < 0.05);
+        assert!(levels[band - 1] < -20.0 && levels[band + 1] < -20.0);
+
+        let axis = FrequencyAxis::new(48000, 8192);
+        let window = WindowFunction::Hann.coefficients(8192);
+        let mut input: Vec<f32> = samples[..8192]
+            .iter()
+            .zip(&window)
+            .map(|(x, w)| x * w)
+            .collect();
+        let fft = realfft::RealFftPlanner::<f32>::new().plan_fft_forward(8192);
+        let mut spectrum = fft.make_output_vec();
+        fft.process(&mut input, &mut spectrum).unwrap();
+        let analyzer = SpectrumBandAnalyzer::new(
+            BandFraction::Octave,
+            250.0,
+            4000.0,
+            &axis,
+            WindowFunction::Hann,
+            8192,
+        )
+        .unwrap();
+        let levels = analyzer.levels_db(&spectrum);
+        assert!((levels[band] + 3.01).abs() < 0.05);
+        assert!(levels[band - 1] < -40.0 && levels[band + 1] < -40.0);
+
+        // A 2048 sample window zero-padded to 8192 reads the same level.
+        let mut input: Vec<f32> = samples[..2048]
+            .iter()
+            .zip(&WindowFunction::Hann.coefficients(2048))
+            .map(|(x, w)| x * w)
+            .collect();
+        input.resize(8192, 0.0);
+        fft.process(&mut input, &mut spectrum).unwrap();
+        let analyzer = SpectrumBandAnalyzer::new(
+            BandFraction::Octave,
+            250.0,
+            4000.0,
+            &axis,
+            WindowFunction::Hann,
+            2048,
+        )
+        .unwrap();
+        assert!((analyzer.levels_db(&spectrum)[band] + 3.01).abs() < 0.05);
+    }
+
+    #[test]
+    fn invalid_limits_are_rejected() {
+        for &(min_hz, max_hz) in &[
+            (0.0, 20000.0),
+            (-1.0, 20000.0),
+            (f32::NAN, 20000.0),
+            (20.0, f32::INFINITY),
+            (20.0, f32::NAN),
+            (1000.0, 500.0),
+        ] {
+            assert!(bands(BandFraction::Third, min_hz, max_hz).is_err());
+            assert!(FilterBankAnalyzer::new(BandFraction::Third, min_hz, max_hz, 48000).is_err());
+        }
+        let axis = FrequencyAxis::new(48000, 1024);
+        assert!(SpectrumBandAnalyzer::new(
+            BandFraction::Third,
+            0.0,
+            20000.0,
+            &axis,
+            WindowFunction::Hann,
+            1024
+        )
+        .is_err());
+        assert!(SpectrumBandAnalyzer::new(
+            BandFraction::Third,
+            20.0,
+            20000.0,
+            &axis,
+            WindowFunction::Hann,
+            2048
+        )
+        .is_err());
+
+        // Tiny positive limits still give a bounded number of bands.
+        let tiny = bands(BandFraction::TwentyFourth, f32::MIN_POSITIVE, 1.0).unwrap();
+        assert!(tiny.len() <= (MAX_OCTAVES * 24) as usize + 1);
+    }
+}
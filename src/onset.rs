@@ -0,0 +1,271 @@
+//! Onset detection from successive RealtimeFft spectra. A detection function
+//! is computed per frame and onsets are picked where it peaks above an
+//! adaptive threshold.
+
+use rustfft::num_complex::Complex;
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::time::{Duration, Instant};
+
+/// Detection function measuring the novelty of each frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnsetFunction {
+    /// Sum of the increases in magnitude of each bin.
+    SpectralFlux,
+    /// Energy weighted by bin index, sensitive to percussive attacks.
+    HighFrequencyContent,
+    /// Distance from the magnitude and phase predicted from the previous two
+    /// frames, counting only bins that grow. Phase prediction assumes frames
+    /// are evenly spaced.
+    ComplexDomain,
+}
+
+/// Parameters of the onset detector.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OnsetConfig {
+    pub function: OnsetFunction,
+    /// Length of the history whose median forms the threshold.
+    pub median_window: Duration,
+    /// Multiple of the median added to the threshold.
+    pub multiplier: f32,
+    /// Fraction of the recent maximum of the detection function added to
+    /// the threshold.
+    pub offset: f32,
+    /// Time constant with which the recent maximum decays.
+    pub max_decay: Duration,
+    /// Minimum time between two onsets.
+    pub min_interval: Duration,
+}
+
+impl Default for OnsetConfig {
+    fn default() -> Self {
+        OnsetConfig {
+            function: OnsetFunction::SpectralFlux,
+            median_window: Duration::from_millis(250),
+            multiplier: 1.5,
+            offset: 0.1,
+            max_decay: Duration::from_secs(3),
+            min_interval: Duration::from_millis(50),
+        }
+    }
+}
+
+/// A detected onset.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Onset {
+    /// Instant of the frame holding the onset.
+    pub instant: Instant,
+    /// Value of the detection function at the onset.
+    pub strength: f32,
+}
+
+/// Detects onsets in successive spectra.
+pub struct OnsetDetector {
+    config: OnsetConfig,
+    /// Magnitudes and phases of the previous frame.
+    previous_magnitude: Vec<f32>,
+    previous_phase: Vec<f32>,
+    /// Phases of the frame before the previous one.
+    older_phase: Vec<f32>,
+    /// Recent values of the detection function.
+    history: VecDeque<(Instant, f32)>,
+    /// Decaying maximum of the detection function.
+    recent_max: f32,
+    last_onset: Option<Instant>,
+}
+
+impl OnsetDetector {
+    /// Returns a new OnsetDetector.
+    pub fn new(config: OnsetConfig) -> Self {
+        OnsetDetector {
+            config,
+            previous_magnitude: Vec::new(),
+            previous_phase: Vec::new(),
+            older_phase: Vec::new(),
+            history: VecDeque::new(),
+            recent_max: 0.0,
+            last_onset: None,
+        }
+    }
+
+    /// Forgets every previous frame.
+    pub fn reset(&mut self) {
+        self.previous_magnitude.clear();
+        self.previous_phase.clear();
+        self.older_phase.clear();
+        self.history.clear();
+        self.recent_max = 0.0;
+        self.last_onset = None;
+    }
+
+    /// Adds the spectrum of a frame computed at `instant`. Returns the value
+    /// of the detection function and the onset found, if any. Onsets are
+    /// reported one frame late as a peak needs the following frame.
+    pub fn process(&mut self, spectrum: &[Complex<f32>], instant: Instant) -> (f32, Option<Onset>) {
+        if self.previous_magnitude.len() != spectrum.len() {
+            self.reset();
+        }
+        let value = self.detection_function(spectrum);
+
+        self.previous_magnitude.clear();
+        self.previous_magnitude
+            .extend(spectrum.iter().map(|bin| bin.norm()));
+        std::mem::swap(&mut self.older_phase, &mut self.previous_phase);
+        self.previous_phase.clear();
+        self.previous_phase
+            .extend(spectrum.iter().map(|bin| bin.arg()));
+
+        // Decay the recent maximum with the time since the last frame.
+        if let Some(&(last, _)) = self.history.back() {
+            let elapsed = instant.saturating_duration_since(last).as_secs_f32();
+            let tau = self.config.max_decay.as_secs_f32().max(f32::EPSILON);
+            self.recent_max *= (-elapsed / tau).exp();
+        }
+        self.recent_max = self.recent_max.max(value);
+
+        self.history.push_back((instant, value));
+        while let Some(&(oldest, _)) = self.history.front() {
+            if instant.saturating_duration_since(oldest) > self.config.median_window {
+                self.history.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        (value, self.pick_peak())
+    }
+
+    /// Returns the onset at the second to last frame if it is a local
+    /// maximum above the threshold.
+    fn pick_peak(&mut self) -> Option<Onset> {
+        let len = self.history.len();
+        if len < 3 {
+            return None;
+        }
+        let (instant, candidate) = self.history[len - 2];
+        if candidate <= self.history[len - 3].1 || candidate < self.history[len - 1].1 {
+            return None;
+        }
+
+        let mut values: Vec<f32> = self.history.iter().map(|&(_, value)| value).collect();
+        values.sort_by(|a, b| a.total_cmp(b));
+        let median = values[values.len() / 2];
+        let threshold = self.config.multiplier * median + self.config.offset * self.recent_max;
+        if candidate <= threshold {
+            return None;
+        }
+        if let Some(last) = self.last_onset {
+            if instant.saturating_duration_since(last) < self.config.min_interval {
+                return None;
+            }
+        }
+        self.last_onset = Some(instant);
+        Some(Onset {
+            instant,
+            strength: candidate,
+        })
+    }
+
+    /// Computes the detection function of a frame given the previous ones.
+    fn detection_function(&self, spectrum: &[Complex<f32>]) -> f32 {
+        let has_previous = !self.previous_magnitude.is_empty();
+        match self.config.function {
+            OnsetFunction::SpectralFlux if has_previous => spectrum
+                .iter()
+                .zip(&self.previous_magnitude)
+                .map(|(bin, previous)| (bin.norm() - previous).max(0.0))
+                .sum(),
+            OnsetFunction::HighFrequencyContent => {
+                spectrum
+                    .iter()
+                    .enumerate()
+                    .map(|(k, bin)| k as f32 * bin.norm_sqr())
+                    .sum::<f32>()
+                    / spectrum.len() as f32
+            }
+            OnsetFunction::ComplexDomain if !self.older_phase.is_empty() => spectrum
+                .iter()
+                .zip(&self.previous_magnitude)
+                .zip(self.previous_phase.iter().zip(&self.older_phase))
+                .filter(|((bin, &previous), _)| bin.norm() >= previous)
+                .map(|((bin, &previous), (&phase, &older))| {
+                    let predicted_phase = princarg(2.0 * phase - older);
+                    (bin - Complex::from_polar(previous, predicted_phase)).norm()
+                })
+                .sum(),
+            _ => 0.0,
+        }
+    }
+}
+
+/// Wraps a phase to [-pi, pi).
+fn princarg(phase: f32) -> f32 {
+    (phase + PI).rem_euclid(2.0 * PI) - PI
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Spectrum whose bins all have the given magnitude.
+    fn flat(magnitude: f32) -> Vec<Complex<f32>> {
+        vec![Complex::new(magnitude, 0.0); 64]
+    }
+
+    #[test]
+    fn detects_a_step_in_level() {
+        for &function in &[
+            OnsetFunction::SpectralFlux,
+            OnsetFunction::HighFrequencyContent,
+            OnsetFunction::ComplexDomain,
+        ] {
+            let mut detector = OnsetDetector::new(OnsetConfig {
+                function,
+                ..OnsetConfig::default()
+            });
+            let start = Instant::now();
+            let frame = |n: u64| start + Duration::from_millis(10 * n);
+            let mut onsets = Vec::new();
+            for n in 0..100 {
+                // A note starting at frame 50 and decaying.
+                let magnitude = if n < 50 {
+                    0.01
+                } else {
+                    0.01 + (-(n as f32 - 50.0) / 10.0).exp()
+                };
+                if let (_, Some(onset)) = detector.process(&flat(magnitude), frame(n)) {
+                    onsets.push(onset);
+                }
+            }
+            assert_eq!(onsets.len(), 1);
+            assert_eq!(onsets[0].instant, frame(50));
+        }
+    }
+
+    #[test]
+    fn onsets_are_spaced_by_min_interval() {
+        let mut detector = OnsetDetector::new(OnsetConfig {
+            min_interval: Duration::from_millis(100),
+            ..OnsetConfig::default()
+        });
+        let start = Instant::now();
+        let mut onsets = 0;
+        // Clicks every 50 ms.
+        for n in 0..100 {
+            let magnitude = if n % 5 == 0 { 1.0 } else { 0.01 };
+            let instant = start + Duration::from_millis(10 * n);
+            onsets += detector.process(&flat(magnitude), instant).1.is_some() as usize;
+        }
+        assert!((9..=10).contains(&onsets));
+    }
+
+    #[test]
+    fn nan_values_do_not_panic() {
+        let mut detector = OnsetDetector::new(OnsetConfig::default());
+        let start = Instant::now();
+        for n in 0..10 {
+            let instant = start + Duration::from_millis(10 * n);
+            detector.process(&flat(if n == 5 { f32::NAN } else { 1.0 }), instant);
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! Peak detection in a spectrum with sub-bin frequency and amplitude
+//! estimates.
+
+use crate::frequency_axis::FrequencyAxis;
+use rustfft::num_complex::Complex;
+use std::f32::consts::PI;
+
+/// Method used to refine the position of a peak between bins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Report the centre of the peak bin.
+    None,
+    /// Parabola through the log magnitudes of the peak and its neighbours.
+    Parabolic,
+    /// Gaussian through the magnitudes of the peak and its neighbours.
+    /// Exact for Gaussian windows and close for most smooth windows.
+    Gaussian,
+    /// Quinn's second estimator. Uses the complex values of the spectrum and
+    /// assumes no window was applied.
+    Quinn,
+}
+
+/// A peak in a spectrum.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Peak {
+    /// Bin holding the local maximum.
+    pub bin: usize,
+    /// Interpolated frequency in Hz.
+    pub frequency: f32,
+    /// Interpolated magnitude, in the units of the spectrum.
+    pub amplitude: f32,
+    /// Height of the peak above the higher of its surrounding minima, in dB.
+    pub prominence_db: f32,
+}
+
+/// Finds the largest local maxima of a spectrum.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeakFinder {
+    /// Maximum number of peaks returned.
+    pub max_peaks: usize,
+    /// Method used to refine each peak.
+    pub interpolation: Interpolation,
+    /// Peaks closer than this to a larger peak are discarded.
+    pub min_distance_hz: f32,
+    /// Peaks less prominent than this are discarded.
+    pub min_prominence_db: f32,
+}
+
+impl Default for PeakFinder {
+    fn default() -> Self {
+        PeakFinder {
+            max_peaks: 10,
+            interpolation: Interpolation::Parabolic,
+            min_distance_hz: 0.0,
+            min_prominence_db: 3.0,
+        }
+    }
+}
+
+impl PeakFinder {
+    /// Returns the peaks of the spectrum ordered from largest to smallest.
+    pub fn find(&self, spectrum: &[Complex<f32>], axis: &FrequencyAxis) -> Vec<Peak> {
+        let magnitudes: Vec<f32> = spectrum.iter().map(|bin| bin.norm()).collect();
+        let levels: Vec<f32> = magnitudes.iter().map(|&mag| to_db(mag)).collect();
+
+        // Candidates are strict local maxima. Plateaus count once at their left edge.
+        let mut candidates: Vec<(usize, f32)> = (1..levels.len().saturating_sub(1))
+            .filter(|&k| levels[k] > levels[k - 1] && levels[k] >= levels[k + 1])
+            .map(|k| (k, prominence(&levels, k)))
+            .filter(|&(_, prominence)| prominence >= self.min_prominence_db)
+            .collect();
+        candidates.sort_by(|a, b| levels[b.0].total_cmp(&levels[a.0]));
+
+        // Greedily keep the largest peaks that are far enough from kept ones.
+        let min_distance_bins = axis.hz_to_bin(self.min_distance_hz);
+        let mut peaks: Vec<Peak> = Vec::with_capacity(self.max_peaks);
+        for (bin, prominence_db) in candidates {
+            if peaks.len() >= self.max_peaks {
+                break;
+            }
+            let too_close = peaks
+                .iter()
+                .any(|peak| ((peak.bin as f32) - bin as f32).abs() < min_distance_bins);
+            if too_close {
+                continue;
+            }
+            let (offset, amplitude) = self.interpolate(spectrum, &magnitudes, bin);
+            peaks.push(Peak {
+                bin,
+                frequency: axis.bin_to_hz(bin as f32 + offset),
+                amplitude,
+                prominence_db,
+            });
+        }
+        peaks
+    }
+
+    /// Returns the offset from `bin` in bins and the amplitude of the peak.
+    fn interpolate(&self, spectrum: &[Complex<f32>], magnitudes: &[f32], bin: usize) -> (f32, f32) {
+        let (left, centre, right) = (magnitudes[bin - 1], magnitudes[bin], magnitudes[bin + 1]);
+        match self.interpolation {
+            Interpolation::None => (0.0, centre),
+            Interpolation::Parabolic => {
+                let (a, b, c) = (to_db(left), to_db(centre), to_db(right));
+                let (offset, peak_db) = fit_parabola(a, b, c);
+                (offset, 10f32.powf(peak_db / 20.0))
+            }
+            Interpolation::Gaussian => {
+                let ln = |mag: f32| mag.max(f32::MIN_POSITIVE).ln();
+                let (offset, peak_ln) = fit_parabola(ln(left), ln(centre), ln(right));
+                (offset, peak_ln.exp())
+            }
+            Interpolation::Quinn => {
+                let offset = quinn(spectrum[bin - 1], spectrum[bin], spectrum[bin + 1]);
+                // Undo the scalloping of the rectangular window.
+                let scallop = if offset.abs() > 1e-6 {
+                    (PI * offset).sin() / (PI * offset)
+                } else {
+                    1.0
+                };
+                (offset, centre / scallop)
+            }
+        }
+    }
+}
+
+/// Fits a parabola through three equally spaced points and returns the
+/// offset of its vertex from the middle point and its value.
+pub fn fit_parabola(a: f32, b: f32, c: f32) -> (f32, f32) {
+    let denominator = a - 2.0 * b + c;
+    if denominator.abs() < f32::EPSILON {
+        return (0.0, b);
+    }
+    let offset = (0.5 * (a - c) / denominator).clamp(-0.5, 0.5);
+    (offset, b - 0.25 * (a - c) * offset)
+}
+
+/// Quinn's second estimator of the offset of a sinusoid from the peak bin.
+fn quinn(left: Complex<f32>, centre: Complex<f32>, right: Complex<f32>) -> f32 {
+    let norm = centre.norm_sqr();
+    if norm == 0.0 {
+        return 0.0;
+    }
+    let tau = |x: f32| {
+        let root = (2.0f32 / 3.0).sqrt();
+        0.25 * (3.0 * x * x + 6.0 * x + 1.0).ln()
+            - 6f32.sqrt() / 24.0 * ((x + 1.0 - root) / (x + 1.0 + root)).ln()
+    };
+    let ap = (right.re * centre.re + right.im * centre.im) / norm;
+    let dp = -ap / (1.0 - ap);
+    let am = (left.re * centre.re + left.im * centre.im) / norm;
+    let dm = am / (1.0 - am);
+    let offset = (dp + dm) / 2.0 + tau(dp * dp) - tau(dm * dm);
+    if offset.is_finite() {
+        offset.clamp(-0.5, 0.5)
+    } else {
+        0.0
+    }
+}
+
+/// Height of the peak at `bin` above the higher of the minima between it
+/// and the nearest higher point on each side.
+fn prominence(levels: &[f32], bin: usize) -> f32 {
+    let peak = levels[bin];
+    let base = |range: &mut dyn Iterator<Item = usize>| {
+        let mut min = peak;
+        for k in range {
+            if levels[k] > peak {
+                break;
+            }
+            min = min.min(levels[k]);
+        }
+        min
+    };
+    let left = base(&mut (0..bin).rev());
+    let right = base(&mut (bin + 1..levels.len()));
+    peak - left.max(right)
+}
+
+/// Converts a magnitude to dB, clamping silence to a finite level.
+fn to_db(magnitude: f32) -> f32 {
+    20.0 * magnitude.max(1e-12).log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window::{self, WindowFunction};
+    use realfft::RealFftPlanner;
+
+    /// Spectrum of a unit sine at `f0` and a half amplitude one at `f1`.
+    fn spectrum(window: WindowFunction, f0: f32, f1: f32) -> Vec<Complex<f32>> {
+        let coefficients = window.coefficients(1024);
+        let mut samples: Vec<f32> = (0..1024)
+            .map(|n| {
+                let t = n as f32 / 8000.0;
+                let x = (2.0 * PI * f0 * t).sin() + 0.5 * (2.0 * PI * f1 * t).sin();
+                x * coefficients[n]
+            })
+            .collect();
+        let fft = RealFftPlanner::<f32>::new().plan_fft_forward(1024);
+        let mut spectrum = fft.make_output_vec();
+        fft.process(&mut samples, &mut spectrum).unwrap();
+        spectrum
+    }
+
+    #[test]
+    fn interpolates_between_bins() {
+        let axis = FrequencyAxis::new(8000, 1024);
+        let cases = [
+            (Interpolation::Parabolic, WindowFunction::Hann, 0.5),
+            (Interpolation::Gaussian, WindowFunction::BlackmanHarris, 0.2),
+            (Interpolation::Quinn, WindowFunction::Rectangular, 0.05),
+        ];
+        for &(interpolation, window, tolerance_hz) in cases.iter() {
+            let finder = PeakFinder {
+                max_peaks: 2,
+                interpolation,
+                min_distance_hz: 50.0,
+                min_prominence_db: 6.0,
+            };
+            let peaks = finder.find(&spectrum(window, 1003.6, 2500.7), &axis);
+            assert_eq!(peaks.len(), 2);
+            assert!((peaks[0].frequency - 1003.6).abs() < tolerance_hz);
+            assert!((peaks[1].frequency - 2500.7).abs() < tolerance_hz);
+
+            // A unit sine peaks at half the window's sum.
+            let expected = 0.5 * window::coherent_gain(&window.coefficients(1024));
+            assert!((peaks[0].amplitude / expected - 1.0).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn nan_bins_do_not_panic() {
+        let axis = FrequencyAxis::new(8000, 1024);
+        let mut spectrum = spectrum(WindowFunction::Hann, 1003.6, 2500.7);
+        spectrum[300] = Complex::new(f32::NAN, 0.0);
+        let peaks = PeakFinder::default().find(&spectrum, &axis);
+        assert!((peaks[0].frequency - 1003.6).abs() < 0.5);
+    }
+
+    #[test]
+    fn parabola_vertex() {
+        // y = -(x - 0.25)^2 + 1 sampled at -1, 0 and 1.
+        let (offset, peak) = fit_parabola(-0.5625, 0.9375, 0.4375);
+        assert!((offset - 0.25).abs() < 1e-6);
+        assert!((peak - 1.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,489 @@
+//! Monophonic pitch detection for tuning instruments. Runs on the raw sample
+//! stream of the source, usually through a sample tap of RealtimeFft.
+
+use crate::error::ConfigError;
+use crate::music::{Note, Tuning};
+use crate::peaks;
+use crate::realtime_fft::realtime_fft_src::SampleTap;
+use crate::sample_window::SampleWindow;
+use crate::window::WindowFunction;
+use realfft::{RealFftPlanner, RealToComplex};
+use rustfft::num_complex::Complex;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Number of thresholds considered by pYIN.
+const PYIN_THRESHOLDS: usize = 100;
+/// Probability given to the global minimum when no dip is below a threshold.
+const PYIN_ABSOLUTE_MIN_PROBABILITY: f32 = 0.01;
+/// Spread in semitones of the pYIN pitch transition weighting.
+const PYIN_TRANSITION_SEMITONES: f32 = 1.0;
+
+/// Algorithm used to find the pitch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PitchMethod {
+    /// YIN cumulative mean normalised difference with an absolute threshold.
+    Yin,
+    /// Probabilistic YIN. Candidates from a distribution of thresholds are
+    /// tracked across frames. Tracking is greedy rather than a full Viterbi
+    /// pass so estimates are available immediately.
+    PYin,
+    /// Harmonic product spectrum of a windowed fft.
+    HarmonicProductSpectrum,
+}
+
+/// Parameters of the pitch detector.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PitchConfig {
+    pub method: PitchMethod,
+    /// Lowest detectable frequency in Hz.
+    pub min_hz: f32,
+    /// Highest detectable frequency in Hz.
+    pub max_hz: f32,
+    /// Reference used to name notes.
+    pub tuning: Tuning,
+    /// YIN threshold on the normalised difference.
+    pub yin_threshold: f32,
+    /// Number of harmonics multiplied by the harmonic product spectrum.
+    pub harmonics: usize,
+}
+
+impl PitchConfig {
+    /// Checks that the frequency range is positive, not empty and below the
+    /// Nyquist frequency of `sample_rate`, and that at least one harmonic is
+    /// used.
+    pub fn validate(&self, sample_rate: u32) -> Result<(), ConfigError> {
+        if self.min_hz.is_nan() || self.min_hz <= 0.0 {
+            return Err(ConfigError::new("min_hz must be positive"));
+        }
+        if self.max_hz.is_nan() || self.max_hz <= self.min_hz {
+            return Err(ConfigError::new("min_hz must be below max_hz"));
+        }
+        let nyquist = sample_rate as f32 / 2.0;
+        if self.max_hz >= nyquist {
+            return Err(ConfigError::new(format!(
+                "max_hz must be below the Nyquist frequency of {} Hz",
+                nyquist
+            )));
+        }
+        if self.harmonics == 0 {
+            return Err(ConfigError::new("harmonics must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for PitchConfig {
+    fn default() -> Self {
+        PitchConfig {
+            method: PitchMethod::Yin,
+            min_hz: 40.0,
+            max_hz: 2000.0,
+            tuning: Tuning::default(),
+            yin_threshold: 0.15,
+            harmonics: 5,
+        }
+    }
+}
+
+/// A detected pitch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PitchEstimate {
+    /// Fundamental frequency in Hz.
+    pub frequency: f32,
+    /// Nearest equal tempered note.
+    pub note: Note,
+    /// Deviation from the nearest note in cents.
+    pub cents: f32,
+    /// Confidence of the estimate in [0, 1].
+    pub confidence: f32,
+}
+
+/// Detects the pitch of the most recent samples.
+pub struct PitchDetector {
+    config: PitchConfig,
+    sample_rate: u32,
+    /// Most recent frame_len samples.
+    frame: SampleWindow<f32>,
+    /// Cumulative mean normalised difference function.
+    difference: Vec<f32>,
+    /// Plan and buffers for the harmonic product spectrum.
+    real_to_complex: Arc<dyn RealToComplex<f32>>,
+    indata: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    window: Vec<f32>,
+    /// Prior probability of each pYIN threshold.
+    threshold_prior: Vec<f32>,
+    /// Previous pYIN estimate used for tracking.
+    previous: Option<f32>,
+}
+
+impl PitchDetector {
+    /// Returns a detector for a signal at `sample_rate`, planning its fft
+    /// with the given planner.
+    pub fn new(
+        fft_planner: &Rc<RefCell<RealFftPlanner<f32>>>,
+        sample_rate: u32,
+        config: PitchConfig,
+    ) -> Result<Self, ConfigError> {
+        config.validate(sample_rate)?;
+        let max_lag = (sample_rate as f32 / config.min_hz).ceil() as usize;
+        let frame_len = match config.method {
+            // Integration window as long as the longest period.
+            PitchMethod::Yin | PitchMethod::PYin => 2 * max_lag + 2,
+            // Several periods of the lowest note for enough resolution.
+            PitchMethod::HarmonicProductSpectrum => (8 * max_lag).next_power_of_two(),
+        };
+        let real_to_complex = fft_planner.borrow_mut().plan_fft_forward(frame_len);
+
+        // Beta(2, 18) distribution of thresholds, mean 0.1.
+        let mut threshold_prior: Vec<f32> = (1..=PYIN_THRESHOLDS)
+            .map(|i| {
+                let s = i as f32 / PYIN_THRESHOLDS as f32;
+                s * (1.0 - s).powi(17)
+            })
+            .collect();
+        let total: f32 = threshold_prior.iter().sum();
+        threshold_prior.iter_mut().for_each(|p| *p /= total);
+
+        Ok(PitchDetector {
+            config,
+            sample_rate,
+            frame: SampleWindow::new(frame_len),
+            difference: vec![0.0; max_lag + 2],
+            indata: real_to_complex.make_input_vec(),
+            spectrum: real_to_complex.make_output_vec(),
+            scratch: real_to_complex.make_scratch_vec(),
+            window: WindowFunction::Hann.coefficients(frame_len),
+            real_to_complex,
+            threshold_prior,
+            previous: None,
+        })
+    }
+
+    /// Returns the number of samples analysed per estimate.
+    pub fn frame_len(&self) -> usize {
+        self.frame.capacity()
+    }
+
+    /// Adds samples, keeping only the most recent frame.
+    pub fn push(&mut self, samples: &[f32]) {
+        self.frame.push(samples);
+    }
+
+    /// Adds every sample available in a tap of the audio source.
+    pub fn push_from(&mut self, tap: &mut SampleTap) {
+        tap.drain(|samples| self.frame.push(samples));
+    }
+
+    /// Forgets the buffered samples and the pitch track.
+    pub fn reset(&mut self) {
+        self.frame.reset();
+        self.previous = None;
+    }
+
+    /// Estimates the pitch of the most recent frame. Returns None until a
+    /// full frame has been received or if no pitch is found.
+    pub fn estimate(&mut self) -> Option<PitchEstimate> {
+        if !self.frame.is_full() {
+            return None;
+        }
+        let (frequency, confidence) = match self.config.method {
+            PitchMethod::Yin => self.yin()?,
+            PitchMethod::PYin => self.pyin()?,
+            PitchMethod::HarmonicProductSpectrum => self.harmonic_product_spectrum()?,
+        };
+        if frequency < self.config.min_hz || frequency > self.config.max_hz {
+            return None;
+        }
+        let (note, cents) = self.config.tuning.nearest_note(frequency);
+        Some(PitchEstimate {
+            frequency,
+            note,
+            cents,
+            confidence: confidence.clamp(0.0, 1.0),
+        })
+    }
+
+    /// Range of lags searched, in samples.
+    fn lag_range(&self) -> (usize, usize) {
+        let min_lag = ((self.sample_rate as f32 / self.config.max_hz).floor() as usize).max(2);
+        (min_lag, self.difference.len() - 2)
+    }
+
+    /// Computes the cumulative mean normalised difference of the frame.
+    fn compute_difference(&mut self) {
+        let frame = self.frame.samples();
+        let window = frame.len() - self.difference.len();
+        self.difference[0] = 1.0;
+        let mut running_sum = 0.0;
+        for lag in 1..self.difference.len() {
+            let d: f32 = frame[..window]
+                .iter()
+                .zip(&frame[lag..lag + window])
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum();
+            running_sum += d;
+            self.difference[lag] = if running_sum > 0.0 {
+                d * lag as f32 / running_sum
+            } else {
+                1.0
+            };
+        }
+    }
+
+    /// Returns the first local minimum of the difference below `threshold`.
+    fn first_dip_below(&self, threshold: f32) -> Option<usize> {
+        let (min_lag, max_lag) = self.lag_range();
+        let mut lag = min_lag;
+        while lag <= max_lag {
+            if self.difference[lag] < threshold {
+                while lag < max_lag && self.difference[lag + 1] < self.difference[lag] {
+                    lag += 1;
+                }
+                return Some(lag);
+            }
+            lag += 1;
+        }
+        None
+    }
+
+    /// Returns the lag of the global minimum of the difference.
+    fn global_minimum(&self) -> usize {
+        let (min_lag, max_lag) = self.lag_range();
+        (min_lag..=max_lag)
+            .min_by(|&a, &b| self.difference[a].total_cmp(&self.difference[b]))
+            .unwrap_or(min_lag)
+    }
+
+    /// Converts a lag to a frequency, refining it with a parabola.
+    fn lag_to_hz(&self, lag: usize) -> f32 {
+        let d = &self.difference;
+        let (offset, _) = peaks::fit_parabola(d[lag - 1], d[lag], d[lag + 1]);
+        self.sample_rate as f32 / (lag as f32 + offset)
+    }
+
+    fn yin(&mut self) -> Option<(f32, f32)> {
+        self.compute_difference();
+        let lag = self
+            .first_dip_below(self.config.yin_threshold)
+            .unwrap_or_else(|| self.global_minimum());
+        Some((self.lag_to_hz(lag), 1.0 - self.difference[lag]))
+    }
+
+    fn pyin(&mut self) -> Option<(f32, f32)> {
+        self.compute_difference();
+        let global_minimum = self.global_minimum();
+
+        // Accumulate the prior of every threshold onto the dip it selects.
+        let mut candidates: Vec<(usize, f32)> = Vec::new();
+        for (i, prior) in self.threshold_prior.iter().enumerate() {
+            let threshold = (i + 1) as f32 / PYIN_THRESHOLDS as f32;
+            let (lag, probability) = match self.first_dip_below(threshold) {
+                Some(lag) => (lag, *prior),
+                None => (global_minimum, prior * PYIN_ABSOLUTE_MIN_PROBABILITY),
+            };
+            match candidates
+                .iter_mut()
+                .find(|(candidate, _)| *candidate == lag)
+            {
+                Some((_, total)) => *total += probability,
+                None => candidates.push((lag, probability)),
+            }
+        }
+
+        // Weight candidates by their distance from the previous pitch.
+        let previous = self.previous;
+        let (lag, probability, _) = candidates
+            .iter()
+            .map(|&(lag, probability)| {
+                let weight = previous.map_or(1.0, |previous| {
+                    let semitones = 12.0 * (self.lag_to_hz(lag) / previous).log2();
+                    let spread = semitones / PYIN_TRANSITION_SEMITONES;
+                    0.5 + 0.5 * (-0.5 * spread * spread).exp()
+                });
+                (lag, probability, probability * weight)
+            })
+            .max_by(|a, b| a.2.total_cmp(&b.2))?;
+
+        let frequency = self.lag_to_hz(lag);
+        self.previous = Some(frequency);
+        Some((frequency, probability))
+    }
+
+    fn harmonic_product_spectrum(&mut self) -> Option<(f32, f32)> {
+        for ((input, &sample), &w) in self
+            .indata
+            .iter_mut()
+            .zip(self.frame.samples())
+            .zip(&self.window)
+        {
+            *input = sample * w;
+        }
+        self.real_to_complex
+            .process_with_scratch(&mut self.indata, &mut self.spectrum, &mut self.scratch)
+            .unwrap();
+
+        let bin_width = self.sample_rate as f32 / self.frame.capacity() as f32;
+        let harmonics = self.config.harmonics;
+        let last =
+            ((self.spectrum.len() - 1) / harmonics).min((self.config.max_hz / bin_width) as usize);
+        let first = ((self.config.min_hz / bin_width).floor() as usize).max(1);
+        if first >= last {
+            return None;
+        }
+        let product = |bin: usize| {
+            (1..=harmonics)
+                .map(|h| self.spectrum[bin * h].norm().max(1e-12).ln())
+                .sum::<f32>()
+        };
+        let mut best = (first..=last)
+            .max_by(|&a, &b| product(a).total_cmp(&product(b)))
+            .unwrap();
+
+        // Octave error correction: prefer the subharmonic if it is nearly
+        // as strong.
+        let half = best / 2;
+        if half >= first && product(half) > product(best) - (harmonics as f32) * 0.7 {
+            let neighbourhood = half.saturating_sub(1)..=half + 1;
+            best = neighbourhood
+                .max_by(|&a, &b| product(a).total_cmp(&product(b)))
+                .unwrap();
+        }
+
+        let magnitude = |bin: usize| self.spectrum[bin].norm().max(1e-12).ln();
+        let (offset, _) =
+            peaks::fit_parabola(magnitude(best - 1), magnitude(best), magnitude(best + 1));
+
+        // Confidence is the fraction of energy in the harmonics.
+        let total: f32 = self.spectrum.iter().map(|x| x.norm_sqr()).sum();
+        let harmonic: f32 = (1..=harmonics)
+            .flat_map(|h| {
+                (best * h).saturating_sub(1)..=(best * h + 1).min(self.spectrum.len() - 1)
+            })
+            .map(|bin| self.spectrum[bin].norm_sqr())
+            .sum();
+        let confidence = if total > 0.0 { harmonic / total } else { 0.0 };
+        Some(((best as f32 + offset) * bin_width, confidence))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn detector(method: PitchMethod) -> PitchDetector {
+        let planner = Rc::new(RefCell::new(RealFftPlanner::new()));
+        let config = PitchConfig {
+            method,
+            ..PitchConfig::default()
+        };
+        PitchDetector::new(&planner, 44100, config).unwrap()
+    }
+
+    /// Sawtooth-like tone with `harmonics` harmonics of falling amplitude.
+    fn tone(frequency: f32, harmonics: usize, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| {
+                let t = n as f32 / 44100.0;
+                (1..=harmonics)
+                    .map(|h| (2.0 * PI * frequency * h as f32 * t).sin() / h as f32)
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detects_the_fundamental() {
+        let cases = [
+            (PitchMethod::Yin, 1.0),
+            (PitchMethod::PYin, 1.0),
+            (PitchMethod::HarmonicProductSpectrum, 2.0),
+        ];
+        for &(method, tolerance_cents) in cases.iter() {
+            for &frequency in &[82.41, 440.0, 1046.5] {
+                let mut detector = detector(method);
+                let samples = tone(frequency, 4, detector.frame_len() + 1000);
+                // Pushed in blocks like a sound card callback.
+                for block in samples.chunks(512) {
+                    detector.push(block);
+                }
+                let estimate = detector.estimate().unwrap();
+                let cents = 1200.0 * (estimate.frequency / frequency).log2();
+                assert!(cents.abs() < tolerance_cents);
+                assert!(estimate.confidence > 0.5);
+            }
+        }
+    }
+
+    #[test]
+    fn names_the_note() {
+        let mut detector = detector(PitchMethod::Yin);
+        // Ten cents sharp of A4.
+        let frequency = 440.0 * 2f32.powf(10.0 / 1200.0);
+        detector.push(&tone(frequency, 1, detector.frame_len()));
+        let estimate = detector.estimate().unwrap();
+        assert_eq!(estimate.note.to_string(), "A4");
+        assert!((estimate.cents - 10.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn waits_for_a_full_frame() {
+        let mut detector = detector(PitchMethod::Yin);
+        let samples = tone(440.0, 1, detector.frame_len());
+        detector.push(&samples[1..]);
+        assert!(detector.estimate().is_none());
+        detector.push(&samples[..1]);
+        assert!(detector.estimate().is_some());
+        detector.reset();
+        assert!(detector.estimate().is_none());
+    }
+
+    #[test]
+    fn nan_samples_do_not_panic() {
+        for &method in &[
+            PitchMethod::Yin,
+            PitchMethod::PYin,
+            PitchMethod::HarmonicProductSpectrum,
+        ] {
+            let mut detector = detector(method);
+            detector.push(&vec![f32::NAN; detector.frame_len()]);
+            let _ = detector.estimate();
+        }
+    }
+
+    #[test]
+    fn invalid_range_is_rejected() {
+        let planner = Rc::new(RefCell::new(RealFftPlanner::new()));
+        let invalid = [
+            PitchConfig {
+                min_hz: 1000.0,
+                max_hz: 500.0,
+                ..PitchConfig::default()
+            },
+            PitchConfig {
+                min_hz: 0.0,
+                ..PitchConfig::default()
+            },
+            PitchConfig {
+                min_hz: f32::NAN,
+                ..PitchConfig::default()
+            },
+            PitchConfig {
+                max_hz: 4000.0,
+                ..PitchConfig::default()
+            },
+            PitchConfig {
+                harmonics: 0,
+                ..PitchConfig::default()
+            },
+        ];
+        for config in invalid.iter() {
+            assert!(PitchDetector::new(&planner, 8000, *config).is_err());
+        }
+        assert!(PitchDetector::new(&planner, 8000, PitchConfig::default()).is_ok());
+    }
+}
@@ -1,52 +1,142 @@
 //! Module for computing realtime ffts given an audio source that implements
 //! the RealtimeFftSrc trait.
 
-use realfft::RealFftPlanner;
+use self::realtime_fft_src::{RealtimeFftSrc, Sample};
+use crate::averaging::{AveragingMode, SpectrumAverager};
+use crate::frequency_axis::FrequencyAxis;
+use crate::reassignment::{Reassigner, ReassignmentConfig};
+use crate::window::WindowFunction;
+use realfft::{RealFftPlanner, RealToComplex};
 use rustfft::num_complex::Complex;
 use std::cell::RefCell;
 use std::ops::DerefMut;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 /// Module for handling information about the audio souce.
 pub mod realtime_fft_src {
     use ringbuf::{Consumer, Producer, RingBuffer};
-    use std::sync::{Arc, Mutex};
+    use rustfft::FftNum;
+    use std::ops::{Deref, DerefMut};
+    use std::sync::{Arc, Condvar, Mutex, Weak};
     use std::time::{Duration, Instant};
 
+    /// Floating point type of the samples and spectra of a RealtimeFft.
+    /// Audio is captured as f32 and converted when it enters a source.
+    pub trait Sample: FftNum + Default {
+        /// Converts a captured sample.
+        fn from_captured(value: f32) -> Self;
+        /// Converts to f32 for the analyses working in single precision.
+        fn as_f32(self) -> f32;
+    }
+
+    impl Sample for f32 {
+        fn from_captured(value: f32) -> Self {
+            value
+        }
+
+        fn as_f32(self) -> f32 {
+            self
+        }
+    }
+
+    impl Sample for f64 {
+        fn from_captured(value: f32) -> Self {
+            value as f64
+        }
+
+        fn as_f32(self) -> f32 {
+            self as f32
+        }
+    }
+
     /// Describes the latency of the audio callback.
     pub struct LatencyInfo {
         /// The sample number and timestamp of the latest sample in the buffer.
         pub sample_at_instant: Option<(usize, Instant)>,
         /// Latency of audio callback.
         pub max_latency: Option<Duration>,
+        /// Number of samples discarded because the buffer was full.
+        pub dropped_samples: u64,
     }
 
     /// Trait that an audio source must implement in order to use RealtimeFft.
-    pub trait RealtimeFftSrc {
+    /// S is the precision the samples are buffered in.
+    pub trait RealtimeFftSrc<S: Sample = f32> {
         /// Fills the sample buffer and records the time that it received the samples.
         fn init(&mut self, sample_buffer_size: usize);
         /// Returns the sample rate of the dft source. Must be made available before init.
         fn sample_rate(&self) -> u32;
         /// Returns the buffer consumer
         /// Must be valid after call to init.
-        fn sample_cons(&self) -> &Arc<Mutex<Consumer<f32>>>;
+        fn sample_cons(&self) -> &Arc<Mutex<Consumer<S>>>;
         /// Returns the max latency of the source (How long it takes for a callback).
         fn latency_info(&self) -> &Arc<Mutex<LatencyInfo>>;
+        /// Returns the notifier that is signalled whenever new samples arrive.
+        /// Must be valid after call to init.
+        fn sample_notifier(&self) -> &SampleNotifier;
+        /// Returns a tap that receives a copy of every sample pushed from
+        /// now on, as captured. Must be valid after call to init.
+        fn add_sample_tap(&self, capacity: usize) -> SampleTap;
+        /// Grows the sample buffer to at least `sample_buffer_size` samples
+        /// keeping the samples it holds. Must be valid after call to init.
+        fn reserve(&self, sample_buffer_size: usize);
+    }
+
+    /// Wakes threads that are waiting for new samples from the source.
+    #[derive(Clone, Default)]
+    pub struct SampleNotifier {
+        /// Generation counter incremented on every notification.
+        inner: Arc<(Mutex<u64>, Condvar)>,
+    }
+
+    impl SampleNotifier {
+        /// Creates a new SampleNotifier.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Signals every waiting thread that new samples are available.
+        pub fn notify(&self) {
+            let (generation, condvar) = &*self.inner;
+            *generation.lock().unwrap() += 1;
+            condvar.notify_all();
+        }
+
+        /// Returns the number of notifications so far.
+        pub fn generation(&self) -> u64 {
+            *self.inner.0.lock().unwrap()
+        }
+
+        /// Blocks until a notification newer than `last_generation` arrives or
+        /// the timeout elapses. Returns the current generation.
+        pub fn wait_timeout(&self, last_generation: u64, timeout: Duration) -> u64 {
+            let (generation, condvar) = &*self.inner;
+            let guard = generation.lock().unwrap();
+            let (guard, _) = condvar
+                .wait_timeout_while(guard, timeout, |generation| *generation == last_generation)
+                .unwrap();
+            *guard
+        }
     }
 
     /// Struct that contains info needed by RealtimeFft.
     #[derive(Clone)]
-    pub struct SrcInfo {
+    pub struct SrcInfo<S: Sample = f32> {
         /// Samples are written to ringbuffer producer.
-        sample_prod: Arc<Mutex<Producer<f32>>>,
+        sample_prod: Arc<Mutex<Producer<S>>>,
         /// Samples are read by RealtimeFft from ringbuffer consumer.
-        sample_cons: Arc<Mutex<Consumer<f32>>>,
+        sample_cons: Arc<Mutex<Consumer<S>>>,
         /// Gives information about latency of the source.
         latency_info: Arc<Mutex<LatencyInfo>>,
+        /// Signalled whenever samples are pushed.
+        notifier: SampleNotifier,
+        /// Extra consumers of the raw sample stream.
+        taps: Arc<Mutex<Vec<TapProducer>>>,
     }
 
-    impl SrcInfo {
+    impl<S: Sample> SrcInfo<S> {
         /// Creates a new SourceInfo
         pub fn new(sample_buffer_size: usize) -> Self {
             let (sample_prod, sample_cons) = RingBuffer::new(sample_buffer_size).split();
@@ -56,34 +146,33 @@ pub mod realtime_fft_src {
             let latency_info = Arc::new(Mutex::new(LatencyInfo {
                 sample_at_instant: None,
                 max_latency: None,
+                dropped_samples: 0,
             }));
 
             SrcInfo {
                 sample_prod,
                 sample_cons,
                 latency_info,
+                notifier: SampleNotifier::new(),
+                taps: Arc::new(Mutex::new(Vec::new())),
             }
         }
 
-        /// Puts sample data into the ringbuffer and updates latency information.
+        /// Puts sample data into the ringbuffer, converting it to S, and
+        /// updates latency information.
         pub fn push_callback_data(&mut self, data: &[f32], sample_buffer_size: usize) {
             let mut sample_prod = self.sample_prod.lock().unwrap();
 
             // Ringbuf is not big enough given the window len and data len.
-            if data.len() + sample_buffer_size > sample_prod.capacity() {
-                let mut old_cons = self.sample_cons.lock().unwrap();
-                let (new_prod, new_cons) =
-                    reallocate_ring_buf(&mut old_cons, data.len() + sample_buffer_size);
-                *old_cons = new_cons;
-                *sample_prod = new_prod;
-            }
+            self.grow(&mut sample_prod, data.len() + sample_buffer_size);
             // Unable to push entire slice. Drop earlier samples.
             let sample_prod_remaining = sample_prod.remaining();
             if data.len() > sample_prod_remaining {
                 let mut sample_cons = self.sample_cons.lock().unwrap();
-                sample_cons.discard(data.len() - sample_prod_remaining);
+                let dropped = sample_cons.discard(data.len() - sample_prod_remaining);
+                self.latency_info.lock().unwrap().dropped_samples += dropped as u64;
             }
-            sample_prod.push_slice(data);
+            sample_prod.push_iter(&mut data.iter().map(|&sample| S::from_captured(sample)));
 
             // Calculate latency information
             let mut latency_info = self.latency_info.lock().unwrap();
@@ -91,12 +180,43 @@ pub mod realtime_fft_src {
             let now = Instant::now();
             latency_info.max_latency = latency_info
                 .sample_at_instant
-                .map_or(None, |(_, instant)| Some(now - instant));
+                .map(|(_, instant)| now - instant);
             latency_info.sample_at_instant = Some((prod_len, now));
+            drop(latency_info);
+            drop(sample_prod);
+
+            // Taps that can't keep up lose the newest samples. Dropped taps
+            // are forgotten.
+            let mut taps = self.taps.lock().unwrap();
+            taps.retain(TapProducer::is_alive);
+            for tap in taps.iter_mut() {
+                tap.producer.push_slice(data);
+            }
+            drop(taps);
+
+            self.notifier.notify();
+        }
+
+        /// Grows the ringbuffer to at least `sample_buffer_size` samples
+        /// keeping the samples it holds.
+        pub fn reserve(&self, sample_buffer_size: usize) {
+            let mut sample_prod = self.sample_prod.lock().unwrap();
+            self.grow(&mut sample_prod, sample_buffer_size);
+        }
+
+        /// Reallocates the ringbuffer if it's smaller than `size`. The
+        /// producer lock must be held.
+        fn grow(&self, sample_prod: &mut Producer<S>, size: usize) {
+            if size > sample_prod.capacity() {
+                let mut old_cons = self.sample_cons.lock().unwrap();
+                let (new_prod, new_cons) = reallocate_ring_buf(&mut old_cons, size);
+                *old_cons = new_cons;
+                *sample_prod = new_prod;
+            }
         }
 
         // Returns a reference to the consumer.
-        pub fn sample_cons(&self) -> &Arc<Mutex<Consumer<f32>>> {
+        pub fn sample_cons(&self) -> &Arc<Mutex<Consumer<S>>> {
             &self.sample_cons
         }
 
@@ -104,6 +224,203 @@ pub mod realtime_fft_src {
         pub fn latency_info(&self) -> &Arc<Mutex<LatencyInfo>> {
             &self.latency_info
         }
+
+        // Returns a reference to the sample notifier.
+        pub fn sample_notifier(&self) -> &SampleNotifier {
+            &self.notifier
+        }
+
+        // Adds a tap receiving a copy of every sample pushed from now on.
+        pub fn add_sample_tap(&self, capacity: usize) -> SampleTap {
+            let (producer, consumer) = RingBuffer::new(capacity).split();
+            let alive = Arc::new(());
+            self.taps.lock().unwrap().push(TapProducer {
+                producer,
+                alive: Arc::downgrade(&alive),
+            });
+            SampleTap {
+                consumer,
+                _alive: alive,
+            }
+        }
+
+        // Returns the number of taps that haven't been dropped.
+        pub fn tap_count(&self) -> usize {
+            let mut taps = self.taps.lock().unwrap();
+            taps.retain(TapProducer::is_alive);
+            taps.len()
+        }
+    }
+
+    /// Writing end of a SampleTap.
+    struct TapProducer {
+        producer: Producer<f32>,
+        /// Gone once the SampleTap is dropped.
+        alive: Weak<()>,
+    }
+
+    impl TapProducer {
+        fn is_alive(&self) -> bool {
+            self.alive.strong_count() > 0
+        }
+    }
+
+    /// Reading end of a copy of the raw sample stream of a source. The
+    /// source stops copying samples once the tap is dropped.
+    pub struct SampleTap {
+        consumer: Consumer<f32>,
+        /// Only referenced weakly by the source.
+        _alive: Arc<()>,
+    }
+
+    impl SampleTap {
+        /// Passes every available sample to `f`, oldest first, then discards
+        /// them. `f` is called twice when the samples wrap around the ring.
+        pub fn drain<F: FnMut(&[f32])>(&mut self, mut f: F) {
+            let mut len = 0;
+            self.consumer.access(|first, second| {
+                if !first.is_empty() {
+                    f(first);
+                }
+                if !second.is_empty() {
+                    f(second);
+                }
+                len = first.len() + second.len();
+            });
+            self.consumer.discard(len);
+        }
+    }
+
+    impl Deref for SampleTap {
+        type Target = Consumer<f32>;
+
+        fn deref(&self) -> &Consumer<f32> {
+            &self.consumer
+        }
+    }
+
+    impl DerefMut for SampleTap {
+        fn deref_mut(&mut self) -> &mut Consumer<f32> {
+            &mut self.consumer
+        }
+    }
+
+    /// Pair of sources filled from the same interleaved callback so their
+    /// samples stay synchronized. The first channel is the reference and
+    /// the second the measurement.
+    #[derive(Clone)]
+    pub struct DualSrcInfo {
+        reference: SrcInfo,
+        measurement: SrcInfo,
+        /// Held while pushing so both channels always hold the same samples.
+        push_lock: Arc<Mutex<()>>,
+        /// Size of the sample buffer of each channel.
+        sample_buffer_size: usize,
+        /// De-interleaved samples of the current callback, kept to avoid
+        /// allocating on the audio thread.
+        reference_scratch: Vec<f32>,
+        measurement_scratch: Vec<f32>,
+    }
+
+    impl DualSrcInfo {
+        /// Creates a new DualSrcInfo whose channels buffer at least
+        /// `sample_buffer_size` samples.
+        pub fn new(sample_buffer_size: usize) -> Self {
+            DualSrcInfo {
+                reference: SrcInfo::new(sample_buffer_size),
+                measurement: SrcInfo::new(sample_buffer_size),
+                push_lock: Arc::new(Mutex::new(())),
+                sample_buffer_size,
+                reference_scratch: Vec::with_capacity(sample_buffer_size),
+                measurement_scratch: Vec::with_capacity(sample_buffer_size),
+            }
+        }
+
+        /// Splits interleaved data into the two channels. Channels beyond the
+        /// second are ignored, as is data with fewer than two channels since
+        /// this runs in the audio callback.
+        pub fn push_interleaved(&mut self, data: &[f32], channels: usize) {
+            if channels < 2 {
+                return;
+            }
+            let _guard = self.push_lock.lock().unwrap();
+            self.reference_scratch.clear();
+            self.measurement_scratch.clear();
+            for frame in data.chunks_exact(channels) {
+                self.reference_scratch.push(frame[0]);
+                self.measurement_scratch.push(frame[1]);
+            }
+            self.reference
+                .push_callback_data(&self.reference_scratch, self.sample_buffer_size);
+            self.measurement
+                .push_callback_data(&self.measurement_scratch, self.sample_buffer_size);
+        }
+
+        // Returns the reference channel.
+        pub fn reference(&self) -> &SrcInfo {
+            &self.reference
+        }
+
+        // Returns the measurement channel.
+        pub fn measurement(&self) -> &SrcInfo {
+            &self.measurement
+        }
+
+        // Adds a tap to each channel. Both receive exactly the same samples.
+        pub fn add_sample_taps(&self, capacity: usize) -> (SampleTap, SampleTap) {
+            let _guard = self.push_lock.lock().unwrap();
+            (
+                self.reference.add_sample_tap(capacity),
+                self.measurement.add_sample_tap(capacity),
+            )
+        }
+    }
+
+    /// One channel of a source whose buffers are filled elsewhere, e.g. by
+    /// DualSrcInfo. Its buffer must be large enough for the RealtimeFft
+    /// reading it.
+    #[derive(Clone)]
+    pub struct ChannelSrc<S: Sample = f32> {
+        src_info: SrcInfo<S>,
+        sample_rate: u32,
+    }
+
+    impl<S: Sample> ChannelSrc<S> {
+        /// Creates a new ChannelSrc reading from `src_info`.
+        pub fn new(src_info: SrcInfo<S>, sample_rate: u32) -> Self {
+            ChannelSrc {
+                src_info,
+                sample_rate,
+            }
+        }
+    }
+
+    impl<S: Sample> RealtimeFftSrc<S> for ChannelSrc<S> {
+        fn init(&mut self, _sample_buffer_size: usize) {}
+
+        fn sample_rate(&self) -> u32 {
+            self.sample_rate
+        }
+
+        fn sample_cons(&self) -> &Arc<Mutex<Consumer<S>>> {
+            self.src_info.sample_cons()
+        }
+
+        fn latency_info(&self) -> &Arc<Mutex<LatencyInfo>> {
+            self.src_info.latency_info()
+        }
+
+        fn sample_notifier(&self) -> &SampleNotifier {
+            self.src_info.sample_notifier()
+        }
+
+        fn add_sample_tap(&self, capacity: usize) -> SampleTap {
+            self.src_info.add_sample_tap(capacity)
+        }
+
+        fn reserve(&self, sample_buffer_size: usize) {
+            self.src_info.reserve(sample_buffer_size);
+        }
     }
 
     /// Function to reallocate a ring buffer.
@@ -117,90 +434,339 @@ pub mod realtime_fft_src {
     }
 }
 
-/// Structure for calculating a realtime fft.
-pub struct RealtimeFft<T: realtime_fft_src::RealtimeFftSrc> {
+/// Analysis parameters of a RealtimeFft. Each can be changed while it runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnalysisConfig {
+    /// Length of the analysis window. It is rounded down to an even number
+    /// of samples.
+    pub window_duration: Duration,
+    /// Size of the fft. The window is zero padded up to it. None or sizes
+    /// shorter than the window use the window length.
+    pub fft_size: Option<usize>,
+    pub window: WindowFunction,
+    /// Shortest time between the start of two frames. Zero computes a frame
+    /// on every update.
+    pub hop: Duration,
+    /// Averaging of the spectrum magnitudes, see RealtimeFft::averaged.
+    pub averaging: AveragingMode,
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        AnalysisConfig {
+            window_duration: Duration::from_millis(20),
+            fft_size: None,
+            window: WindowFunction::Rectangular,
+            hop: Duration::from_secs(0),
+            averaging: AveragingMode::None,
+        }
+    }
+}
+
+/// Structure for calculating a realtime fft in precision S.
+pub struct RealtimeFft<T: RealtimeFftSrc<S>, S: Sample = f32> {
+    /// Current analysis parameters.
+    config: AnalysisConfig,
     /// Used to calculate fft.
-    fft_planner: Rc<RefCell<RealFftPlanner<f32>>>,
+    fft_planner: Rc<RefCell<RealFftPlanner<S>>>,
+    /// Plan for the fft size and the buffers it works in, kept so that
+    /// computing a spectrum doesn't allocate.
+    real_to_complex: Arc<dyn RealToComplex<S>>,
+    indata: Vec<S>,
+    scratch: Vec<Complex<S>>,
+    /// Coefficients of the window function.
+    window: Vec<S>,
     /// Spectrum of the fft.
-    /// Note: It's len is always half that of the window len because the input
+    /// Note: It's len is always half that of the fft size because the input
     /// signal is real. As such, the values are mirrored.
-    sliding_dft: Rc<RefCell<Vec<Complex<f32>>>>,
+    sliding_dft: Rc<RefCell<Vec<Complex<S>>>>,
+    /// Time domain samples the spectrum was computed from.
+    frame: Rc<RefCell<Vec<S>>>,
     /// Audio source implementing the RealtimeFftSrc trait.
     dft_src: T,
     /// Latency due to window length.
     latency: Duration,
+    /// Minimum number of samples between the start of two frames.
+    hop: u64,
+    /// Averages the magnitudes of each spectrum when enabled.
+    averager: Option<SpectrumAverager>,
+    /// Magnitudes handed to the averager.
+    magnitudes: Vec<f32>,
+    /// Number of samples discarded from the source by RealtimeFft.
+    consumed_samples: u64,
+    /// Index since the source started of the first sample of the frame.
+    frame_sample_index: u64,
+    /// Time at which the first sample of the frame was recorded.
+    frame_instant: Option<Instant>,
+    /// Computes reassigned coordinates of each frame when enabled.
+    reassigner: Option<Reassigner>,
 }
 
-impl<T: realtime_fft_src::RealtimeFftSrc> RealtimeFft<T> {
+impl<T: RealtimeFftSrc<S>, S: Sample> RealtimeFft<T, S> {
     /// Returns a new RealtimeFft given an audio source and a window duration.
-    pub fn new(mut dft_src: T, window_duration: Duration) -> RealtimeFft<T> {
-        let sample_rate = dft_src.sample_rate();
+    pub fn new(dft_src: T, window_duration: Duration) -> RealtimeFft<T, S> {
+        Self::with_config(
+            dft_src,
+            AnalysisConfig {
+                window_duration,
+                ..Default::default()
+            },
+        )
+    }
 
-        let window_size: usize = (sample_rate as f64 * window_duration.as_secs_f64()) as usize;
+    /// Returns a new RealtimeFft given an audio source and its analysis
+    /// parameters.
+    pub fn with_config(mut dft_src: T, config: AnalysisConfig) -> RealtimeFft<T, S> {
+        let sample_rate = dft_src.sample_rate();
+        let window_size = window_len(sample_rate, config.window_duration);
+        let fft_size = config.fft_size.unwrap_or(0).max(window_size);
 
         dft_src.init(window_size * 2);
 
+        let fft_planner = Rc::new(RefCell::new(RealFftPlanner::new()));
+        let real_to_complex = fft_planner.borrow_mut().plan_fft_forward(fft_size);
+
         RealtimeFft {
-            fft_planner: Rc::new(RefCell::new(RealFftPlanner::new())),
-            sliding_dft: Rc::new(RefCell::new(vec![
-                Complex::<f32>::new(0.0, 0.0);
-                (window_size / 2) + 1
-            ])),
+            config,
+            fft_planner,
+            indata: real_to_complex.make_input_vec(),
+            scratch: real_to_complex.make_scratch_vec(),
+            real_to_complex,
+            window: window_coefficients(config.window, window_size),
+            sliding_dft: Rc::new(RefCell::new(vec![Complex::default(); (fft_size / 2) + 1])),
+            frame: Rc::new(RefCell::new(vec![S::default(); window_size])),
             dft_src,
-            latency: window_duration,
+            latency: config.window_duration,
+            hop: hop_len(sample_rate, config.hop),
+            averager: averager(config.averaging),
+            magnitudes: Vec::new(),
+            consumed_samples: 0,
+            frame_sample_index: 0,
+            frame_instant: None,
+            reassigner: None,
         }
     }
 
     /// Updates the value for the SDFT. Should be called in a fairly tight loop.
     /// Perhaps even in its own thread.
-    pub fn update(&mut self) {
-        let window_size = (self.sliding_dft.borrow().len() - 1) * 2;
+    /// Returns true if a new spectrum was computed.
+    pub fn update(&mut self) -> bool {
+        let window_size = self.window_size();
         let latency_info_ref = self.dft_src.latency_info();
 
         // If Latency and sample at instant are present, calculate starting
         // sample for dft. Otherwise return.
-        let window_start_sample = match latency_info_ref.lock().unwrap().deref_mut() {
-            realtime_fft_src::LatencyInfo {
-                sample_at_instant: Some((sample_at, sample_instant)),
-                max_latency: Some(src_latency),
-            } => {
-                let window_end_instant = Instant::now() - *src_latency;
-                let window_start_instant = window_end_instant - self.latency;
-
-                // Latency is longer than expected.) Return and try again later.
-                if window_end_instant > *sample_instant {
-                    return;
-                }
+        let (window_start_sample, window_start_instant) =
+            match latency_info_ref.lock().unwrap().deref_mut() {
+                realtime_fft_src::LatencyInfo {
+                    sample_at_instant: Some((sample_at, sample_instant)),
+                    max_latency: Some(src_latency),
+                    ..
+                } => {
+                    let window_end_instant = Instant::now() - *src_latency;
+                    let window_start_instant = window_end_instant - self.latency;
+
+                    // Latency is longer than expected.) Return and try again later.
+                    if window_end_instant > *sample_instant {
+                        return false;
+                    }
 
-                // Start sample is the number of samples behind the sample at sample_instant.
-                let window_start_sample = (*sample_at)
-                    .checked_sub(
+                    // Start sample is the number of samples behind the sample at sample_instant.
+                    let window_start_sample = (*sample_at).saturating_sub(
                         ((*sample_instant - window_start_instant) * self.dft_src.sample_rate())
                             .as_secs() as usize,
-                    )
-                    .unwrap_or(0);
+                    );
 
-                *sample_at -= window_start_sample;
-                window_start_sample
-            }
-            _ => return,
-        };
+                    *sample_at -= window_start_sample;
+                    (window_start_sample, window_start_instant)
+                }
+                _ => return false,
+            };
 
-        self.process_fft(window_size, window_start_sample);
+        self.process_fft(window_size, window_start_sample, window_start_instant)
     }
 
     /// Returns the dft of the singal.
-    pub fn dft(&self) -> &Rc<RefCell<Vec<Complex<f32>>>> {
+    pub fn dft(&self) -> &Rc<RefCell<Vec<Complex<S>>>> {
         &self.sliding_dft
     }
 
+    /// Returns the time domain samples of the window the dft was computed
+    /// from.
+    pub fn frame(&self) -> &Rc<RefCell<Vec<S>>> {
+        &self.frame
+    }
+
+    /// Returns the index since the source started of the first sample of
+    /// the frame. Counts samples the source dropped.
+    pub fn frame_sample_index(&self) -> u64 {
+        self.frame_sample_index
+    }
+
+    /// Returns the time at which the first sample of the frame was
+    /// recorded, None before the first fft.
+    pub fn frame_instant(&self) -> Option<Instant> {
+        self.frame_instant
+    }
+
     /// Returns sample rate of audio source.
     pub fn sample_rate(&self) -> u32 {
         self.dft_src.sample_rate()
     }
 
+    /// Returns the number of samples in the analysis window, always even.
+    pub fn window_size(&self) -> usize {
+        self.window.len()
+    }
+
+    /// Returns the size of the fft.
+    pub fn fft_size(&self) -> usize {
+        self.indata.len()
+    }
+
+    /// Returns the frequency axis of the dft.
+    pub fn frequency_axis(&self) -> FrequencyAxis {
+        FrequencyAxis::new(self.sample_rate(), self.fft_size())
+    }
+
+    /// Returns the duration of the analysis window.
+    pub fn window_duration(&self) -> Duration {
+        self.latency
+    }
+
+    /// Returns the notifier signalled when the source receives new samples.
+    pub fn sample_notifier(&self) -> &realtime_fft_src::SampleNotifier {
+        self.dft_src.sample_notifier()
+    }
+
+    /// Returns a tap receiving a copy of the raw samples of the source.
+    pub fn add_sample_tap(&self, capacity: usize) -> realtime_fft_src::SampleTap {
+        self.dft_src.add_sample_tap(capacity)
+    }
+
+    /// Computes the reassigned spectrogram of every frame from now on.
+    /// Reassignment works in f32 whatever the precision of the fft.
+    pub fn enable_reassignment(&mut self, config: ReassignmentConfig) {
+        self.enable_reassignment_with_len(config, self.window_size());
+    }
+
+    fn enable_reassignment_with_len(&mut self, config: ReassignmentConfig, window_size: usize) {
+        self.reassigner = Some(Reassigner::new(
+            &Rc::new(RefCell::new(RealFftPlanner::new())),
+            self.sample_rate(),
+            window_size,
+            config,
+        ));
+    }
+
+    /// Stops computing the reassigned spectrogram.
+    pub fn disable_reassignment(&mut self) {
+        self.reassigner = None;
+    }
+
+    /// Returns the reassignment of the last frame if enabled.
+    pub fn reassigner(&self) -> Option<&Reassigner> {
+        self.reassigner.as_ref()
+    }
+
+    /// Returns the averaged magnitudes of the spectrum, None if averaging
+    /// is off.
+    pub fn averaged(&self) -> Option<&[f32]> {
+        self.averager.as_ref().map(|averager| averager.output())
+    }
+
+    /// Returns the current analysis parameters.
+    pub fn config(&self) -> &AnalysisConfig {
+        &self.config
+    }
+
+    /// Changes the analysis parameters. Buffers are resized and the fft
+    /// replanned as needed while the source keeps its buffered samples and
+    /// keeps running.
+    pub fn reconfigure(&mut self, config: AnalysisConfig) {
+        let sample_rate = self.sample_rate();
+        let window_size = window_len(sample_rate, config.window_duration);
+        let fft_size = config.fft_size.unwrap_or(0).max(window_size);
+
+        if window_size != self.window_size() {
+            self.dft_src.reserve(window_size * 2);
+            self.frame.borrow_mut().resize(window_size, S::default());
+            if let Some(reassigner) = &self.reassigner {
+                let reassignment = *reassigner.config();
+                self.enable_reassignment_with_len(reassignment, window_size);
+            }
+        }
+        if window_size != self.window_size() || config.window != self.config.window {
+            self.window = window_coefficients(config.window, window_size);
+        }
+        if fft_size != self.fft_size() {
+            self.real_to_complex = self.fft_planner.borrow_mut().plan_fft_forward(fft_size);
+            self.indata = self.real_to_complex.make_input_vec();
+            self.scratch = self.real_to_complex.make_scratch_vec();
+            self.sliding_dft
+                .borrow_mut()
+                .resize(fft_size / 2 + 1, Complex::default());
+        }
+        self.latency = config.window_duration;
+        self.hop = hop_len(sample_rate, config.hop);
+        match (&mut self.averager, config.averaging) {
+            (_, AveragingMode::None) => self.averager = None,
+            (Some(averager), mode) => averager.set_mode(mode),
+            (None, mode) => self.averager = averager(mode),
+        }
+        self.config = config;
+    }
+
+    /// Changes the length of the analysis window.
+    pub fn set_window_duration(&mut self, window_duration: Duration) {
+        self.reconfigure(AnalysisConfig {
+            window_duration,
+            ..self.config
+        });
+    }
+
+    /// Changes the size of the fft, None to follow the window length.
+    pub fn set_fft_size(&mut self, fft_size: Option<usize>) {
+        self.reconfigure(AnalysisConfig {
+            fft_size,
+            ..self.config
+        });
+    }
+
+    /// Changes the window function.
+    pub fn set_window_function(&mut self, window: WindowFunction) {
+        self.reconfigure(AnalysisConfig {
+            window,
+            ..self.config
+        });
+    }
+
+    /// Changes the shortest time between the start of two frames.
+    pub fn set_hop(&mut self, hop: Duration) {
+        self.reconfigure(AnalysisConfig { hop, ..self.config });
+    }
+
+    /// Changes the averaging of the spectrum magnitudes.
+    pub fn set_averaging(&mut self, averaging: AveragingMode) {
+        self.reconfigure(AnalysisConfig {
+            averaging,
+            ..self.config
+        });
+    }
+
+    /// Returns the planner used for the fft so other analyses can share plans.
+    pub fn fft_planner(&self) -> &Rc<RefCell<RealFftPlanner<S>>> {
+        &self.fft_planner
+    }
+
     /// Performs an fft given a window size and its start sample.
-    fn process_fft(&mut self, window_size: usize, window_start_sample: usize) {
+    /// Returns true if there were enough samples to compute the fft.
+    fn process_fft(
+        &mut self,
+        window_size: usize,
+        window_start_sample: usize,
+        window_start_instant: Instant,
+    ) -> bool {
         // Acquire consumer lock.
         let sample_cons_lock = self.dft_src.sample_cons();
         let mut sample_cons = sample_cons_lock.lock().unwrap();
@@ -214,31 +780,270 @@ impl<T: realtime_fft_src::RealtimeFftSrc> RealtimeFft<T> {
         //);
 
         // Window has moved past these samples. Discard them.
-        sample_cons.discard(window_start_sample);
+        self.consumed_samples += sample_cons.discard(window_start_sample) as u64;
 
         // Cannot continue as there aren't enough samples.
         if window_size > sample_cons.len() {
-            return;
+            return false;
         }
 
-        // Performs dft.
-        let mut dft_clone = self.sliding_dft.clone();
-        let fft_planner_clone = self.fft_planner.clone();
-        sample_cons.access(|buf1, buf2| {
-            let full_buf = [buf1, buf2].concat();
-            let window = &full_buf[0..window_size];
+        // The source only drops samples while holding the consumer lock.
+        let dropped_samples = self.dft_src.latency_info().lock().unwrap().dropped_samples;
+        let frame_sample_index = dropped_samples + self.consumed_samples;
 
-            let real_to_complex = fft_planner_clone.borrow_mut().plan_fft_forward(window_size);
-            // make input and output vectors
-            let mut indata = real_to_complex.make_input_vec();
+        // Too soon after the previous frame.
+        if self.frame_instant.is_some() && frame_sample_index < self.frame_sample_index + self.hop {
+            return false;
+        }
+        self.frame_sample_index = frame_sample_index;
+        self.frame_instant = Some(window_start_instant);
 
-            indata[0..window_size].copy_from_slice(window);
+        // Performs dft. The window may straddle the two halves of the ring
+        // buffer so it's copied out of both.
+        let real_to_complex = &self.real_to_complex;
+        let indata = &mut self.indata;
+        let scratch = &mut self.scratch;
+        let window = &self.window;
+        let mut frame = self.frame.borrow_mut();
+        let mut dft = self.sliding_dft.borrow_mut();
+        sample_cons.access(|buf1, buf2| {
+            let first_len = buf1.len().min(window_size);
+            frame[..first_len].copy_from_slice(&buf1[..first_len]);
+            frame[first_len..].copy_from_slice(&buf2[..window_size - first_len]);
 
-            // TODO: Apply hanning window.
+            // The fft overwrites its input so keep a copy of the frame.
+            // Beyond the window the input is zero padded.
+            let (windowed, padding) = indata.split_at_mut(window_size);
+            for ((input, &sample), &w) in windowed.iter_mut().zip(frame.iter()).zip(window) {
+                *input = sample * w;
+            }
+            padding.iter_mut().for_each(|input| *input = S::default());
 
             real_to_complex
-                .process(&mut indata, &mut dft_clone.borrow_mut()[..])
+                .process_with_scratch(indata, &mut dft, scratch)
                 .unwrap();
         });
+        drop(frame);
+
+        if let Some(averager) = &mut self.averager {
+            self.magnitudes.clear();
+            self.magnitudes
+                .extend(dft.iter().map(|bin| bin.norm_sqr().as_f32().sqrt()));
+            averager.process(&self.magnitudes, window_start_instant);
+        }
+        drop(dft);
+
+        if let Some(reassigner) = &mut self.reassigner {
+            reassigner.process(&self.frame.borrow());
+        }
+        true
+    }
+}
+
+/// Returns the number of samples in a window of `duration`.
+fn window_len(sample_rate: u32, duration: Duration) -> usize {
+    let len = (sample_rate as f64 * duration.as_secs_f64()) as usize;
+    // Windows have always had an even length, the window size once being
+    // derived from the number of bins as (bins - 1) * 2.
+    len - len % 2
+}
+
+/// Returns the number of samples in a hop of `duration`.
+fn hop_len(sample_rate: u32, duration: Duration) -> u64 {
+    (sample_rate as f64 * duration.as_secs_f64()).round() as u64
+}
+
+fn window_coefficients<S: Sample>(window: WindowFunction, len: usize) -> Vec<S> {
+    window
+        .coefficients(len)
+        .into_iter()
+        .map(S::from_captured)
+        .collect()
+}
+
+fn averager(mode: AveragingMode) -> Option<SpectrumAverager> {
+    match mode {
+        AveragingMode::None => None,
+        mode => Some(SpectrumAverager::new(mode)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::realtime_fft_src::{ChannelSrc, DualSrcInfo, SrcInfo};
+    use super::*;
+    use crate::window::WindowFunction;
+
+    #[test]
+    fn dual_source_splits_channels() {
+        let mut src_info = DualSrcInfo::new(64);
+        let (mut reference, mut measurement) = src_info.add_sample_taps(256);
+        let data: Vec<f32> = (0..48).map(|n| n as f32).collect();
+        src_info.push_interleaved(&data, 3);
+
+        let mut drained = Vec::new();
+        reference.drain(|samples| drained.extend_from_slice(samples));
+        assert_eq!(
+            drained,
+            [
+                0.0, 3.0, 6.0, 9.0, 12.0, 15.0, 18.0, 21.0, 24.0, 27.0, 30.0, 33.0, 36.0, 39.0,
+                42.0, 45.0
+            ]
+        );
+        drained.clear();
+        measurement.drain(|samples| drained.extend_from_slice(samples));
+        assert_eq!(drained.len(), 16);
+        assert_eq!(drained[15], 46.0);
+
+        // Mono and empty frames are ignored rather than panicking.
+        src_info.push_interleaved(&data, 1);
+        src_info.push_interleaved(&data, 0);
+        assert_eq!(reference.len(), 0);
+        assert_eq!(measurement.len(), 0);
+    }
+
+    #[test]
+    fn dropped_taps_are_forgotten() {
+        let mut src_info: SrcInfo = SrcInfo::new(16);
+        let mut kept = src_info.add_sample_tap(4);
+        let dropped = src_info.add_sample_tap(4);
+        assert_eq!(src_info.tap_count(), 2);
+        drop(dropped);
+        src_info.push_callback_data(&[1.0, 2.0, 3.0], 0);
+        assert_eq!(src_info.tap_count(), 1);
+
+        // The tap wraps around its ring and keeps the oldest samples.
+        kept.discard(2);
+        src_info.push_callback_data(&[4.0, 5.0, 6.0], 0);
+        let mut drained = Vec::new();
+        kept.drain(|samples| drained.extend_from_slice(samples));
+        assert_eq!(drained, [3.0, 4.0, 5.0, 6.0]);
+        assert!(kept.is_empty());
+    }
+
+    #[test]
+    fn frame_spans_both_ring_slices() {
+        let mut src_info: SrcInfo = SrcInfo::new(1000);
+        let src = ChannelSrc::new(src_info.clone(), 512);
+        let mut fft = RealtimeFft::new(src, Duration::from_secs(1));
+        let window_size = fft.window_size();
+
+        // 1000 samples fill the ring. Discarding 700 leaves too few for a
+        // window until 600 more are pushed, wrapping around the ring's end.
+        let ramp: Vec<f32> = (0..1600).map(|n| n as f32).collect();
+        src_info.push_callback_data(&ramp[..1000], 0);
+        assert!(!fft.process_fft(window_size, 700, Instant::now()));
+        src_info.push_callback_data(&ramp[1000..], 0);
+        assert!(fft.process_fft(window_size, 0, Instant::now()));
+
+        let expected = &ramp[700..700 + window_size];
+        assert_eq!(&fft.frame().borrow()[..], expected);
+        assert_eq!(fft.frame_sample_index(), 700);
+    }
+
+    #[test]
+    fn reconfiguring_keeps_buffered_samples() {
+        let mut src_info: SrcInfo = SrcInfo::new(1024);
+        let src = ChannelSrc::new(src_info.clone(), 1024);
+        let mut fft = RealtimeFft::new(src, Duration::from_millis(500));
+        let ramp: Vec<f32> = (0..1024).map(|n| n as f32).collect();
+        src_info.push_callback_data(&ramp[..1000], 0);
+
+        // The window outgrows the ring which must keep what it holds.
+        fft.reconfigure(AnalysisConfig {
+            window_duration: Duration::from_secs(1),
+            fft_size: Some(4096),
+            window: WindowFunction::Hann,
+            ..*fft.config()
+        });
+        assert_eq!(fft.window_size(), 1024);
+        assert_eq!(fft.fft_size(), 4096);
+        assert_eq!(fft.dft().borrow().len(), 2049);
+        assert!(!fft.process_fft(1024, 0, Instant::now()));
+
+        src_info.push_callback_data(&ramp[1000..], 0);
+        assert!(fft.process_fft(1024, 0, Instant::now()));
+        assert_eq!(&fft.frame().borrow()[..], &ramp[..]);
+    }
+
+    #[test]
+    fn reassignment_follows_the_window() {
+        let mut src_info: SrcInfo = SrcInfo::new(2048);
+        let src = ChannelSrc::new(src_info.clone(), 1024);
+        let mut fft = RealtimeFft::new(src, Duration::from_millis(250));
+        fft.enable_reassignment(ReassignmentConfig::default());
+        fft.reconfigure(AnalysisConfig {
+            window_duration: Duration::from_millis(500),
+            ..*fft.config()
+        });
+        src_info.push_callback_data(&[0.5; 2048], 0);
+
+        assert!(fft.process_fft(512, 0, Instant::now()));
+        let reassigner = fft.reassigner().unwrap();
+        assert_eq!(reassigner.bins().len(), 257);
+        assert_eq!(reassigner.spectrum().len(), fft.dft().borrow().len());
+        fft.disable_reassignment();
+        assert!(fft.reassigner().is_none());
+    }
+
+    #[test]
+    fn windows_are_rounded_to_even_lengths() {
+        let src_info: SrcInfo = SrcInfo::new(1024);
+        let src = ChannelSrc::new(src_info, 1000);
+        let mut fft = RealtimeFft::new(src, Duration::from_millis(251));
+        assert_eq!(fft.window_size(), 250);
+        assert_eq!(fft.fft_size(), 250);
+
+        // The duration is kept even when the rounded window doesn't change.
+        fft.set_window_duration(Duration::from_millis(250));
+        assert_eq!(fft.window_size(), 250);
+        assert_eq!(fft.window_duration(), Duration::from_millis(250));
+        fft.set_window_duration(Duration::from_millis(253));
+        assert_eq!(fft.window_size(), 252);
+        assert_eq!(fft.window_duration(), Duration::from_millis(253));
+    }
+
+    #[test]
+    fn hop_skips_frames() {
+        let mut src_info: SrcInfo = SrcInfo::new(2048);
+        let src = ChannelSrc::new(src_info.clone(), 1024);
+        let mut fft = RealtimeFft::new(src, Duration::from_millis(250));
+        fft.set_hop(Duration::from_millis(100));
+        src_info.push_callback_data(&[0.0; 2048], 0);
+
+        assert!(fft.process_fft(256, 0, Instant::now()));
+        assert!(!fft.process_fft(256, 50, Instant::now()));
+        assert!(fft.process_fft(256, 52, Instant::now()));
+        assert_eq!(fft.frame_sample_index(), 102);
+    }
+
+    #[test]
+    fn double_precision_matches_single() {
+        let mut single_info: SrcInfo<f32> = SrcInfo::new(2048);
+        let mut double_info: SrcInfo<f64> = SrcInfo::new(2048);
+        let mut single = RealtimeFft::new(
+            ChannelSrc::new(single_info.clone(), 1024),
+            Duration::from_secs(1),
+        );
+        let mut double = RealtimeFft::new(
+            ChannelSrc::new(double_info.clone(), 1024),
+            Duration::from_secs(1),
+        );
+
+        let samples: Vec<f32> = (0..1024).map(|n| (n as f32 * 0.37).sin()).collect();
+        single_info.push_callback_data(&samples, 0);
+        double_info.push_callback_data(&samples, 0);
+        assert!(single.process_fft(1024, 0, Instant::now()));
+        assert!(double.process_fft(1024, 0, Instant::now()));
+
+        for (a, b) in single
+            .dft()
+            .borrow()
+            .iter()
+            .zip(double.dft().borrow().iter())
+        {
+            assert!((a.re as f64 - b.re).abs() < 1e-3);
+            assert!((a.im as f64 - b.im).abs() < 1e-3);
+        }
     }
 }
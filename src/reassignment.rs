@@ -0,0 +1,257 @@
+//! Time-frequency reassignment (Auger and Flandrin, 1995). Alongside the
+//! spectrum taken with a window h, spectra taken with its derivative and
+//! with the time ramped window move the energy of each bin to the centre of
+//! gravity of the signal around it. Synchrosqueezing sums the bins back onto
+//! the frequency grid at their reassigned frequencies.
+
+use crate::frequency_axis::FrequencyAxis;
+use crate::realtime_fft::realtime_fft_src::Sample;
+use crate::window::WindowFunction;
+use realfft::{RealFftPlanner, RealToComplex};
+use rustfft::num_complex::Complex;
+use std::cell::RefCell;
+use std::f32::consts::PI;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Parameters of the reassignment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReassignmentConfig {
+    /// Window of the main spectrum. Must be smooth, e.g. Hann.
+    pub window: WindowFunction,
+    /// Bins this far below the largest one keep their nominal coordinates
+    /// as reassignment is meaningless in noise, in dB.
+    pub threshold_db: f32,
+    /// Whether to also compute the synchrosqueezed spectrum.
+    pub synchrosqueeze: bool,
+}
+
+impl Default for ReassignmentConfig {
+    fn default() -> Self {
+        ReassignmentConfig {
+            window: WindowFunction::Hann,
+            threshold_db: -80.0,
+            synchrosqueeze: false,
+        }
+    }
+}
+
+/// A bin moved to its reassigned coordinates.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReassignedBin {
+    /// Reassigned frequency in Hz.
+    pub frequency: f32,
+    /// Reassigned time in seconds from the first sample of the frame.
+    pub time: f32,
+    /// Magnitude of the bin in the main spectrum.
+    pub magnitude: f32,
+}
+
+/// Computes reassigned coordinates for the bins of a frame.
+pub struct Reassigner {
+    config: ReassignmentConfig,
+    axis: FrequencyAxis,
+    real_to_complex: Arc<dyn RealToComplex<f32>>,
+    /// The window, its derivative per sample and the window multiplied by
+    /// the time from its centre in samples.
+    window: Vec<f32>,
+    derivative_window: Vec<f32>,
+    ramped_window: Vec<f32>,
+    indata: Vec<f32>,
+    scratch: Vec<Complex<f32>>,
+    spectrum: Vec<Complex<f32>>,
+    derivative_spectrum: Vec<Complex<f32>>,
+    ramped_spectrum: Vec<Complex<f32>>,
+    bins: Vec<ReassignedBin>,
+    squeezed: Vec<Complex<f32>>,
+}
+
+impl Reassigner {
+    /// Returns a reassigner for frames of `frame_len` samples at
+    /// `sample_rate`, planning its ffts with the given planner.
+    pub fn new(
+        fft_planner: &Rc<RefCell<RealFftPlanner<f32>>>,
+        sample_rate: u32,
+        frame_len: usize,
+        config: ReassignmentConfig,
+    ) -> Self {
+        let real_to_complex = fft_planner.borrow_mut().plan_fft_forward(frame_len);
+        let window = config.window.coefficients(frame_len);
+
+        // Central differences of the periodic window.
+        let derivative_window = (0..frame_len)
+            .map(|n| {
+                let next = window[(n + 1) % frame_len];
+                let previous = window[(n + frame_len - 1) % frame_len];
+                (next - previous) / 2.0
+            })
+            .collect();
+        let centre = frame_len as f32 / 2.0;
+        let ramped_window = window
+            .iter()
+            .enumerate()
+            .map(|(n, w)| (n as f32 - centre) * w)
+            .collect();
+
+        let spectrum = real_to_complex.make_output_vec();
+        let num_bins = spectrum.len();
+        Reassigner {
+            config,
+            axis: FrequencyAxis::new(sample_rate, frame_len),
+            window,
+            derivative_window,
+            ramped_window,
+            indata: real_to_complex.make_input_vec(),
+            scratch: real_to_complex.make_scratch_vec(),
+            derivative_spectrum: spectrum.clone(),
+            ramped_spectrum: spectrum.clone(),
+            spectrum,
+            bins: vec![ReassignedBin::default(); num_bins],
+            squeezed: vec![Complex::new(0.0, 0.0); num_bins],
+            real_to_complex,
+        }
+    }
+
+    /// Returns the configuration of the reassigner.
+    pub fn config(&self) -> &ReassignmentConfig {
+        &self.config
+    }
+
+    /// Returns the spectrum of the last frame taken with the main window.
+    pub fn spectrum(&self) -> &[Complex<f32>] {
+        &self.spectrum
+    }
+
+    /// Returns the reassigned coordinates of every bin of the last frame.
+    pub fn bins(&self) -> &[ReassignedBin] {
+        &self.bins
+    }
+
+    /// Returns the synchrosqueezed spectrum of the last frame, or None if
+    /// synchrosqueezing is disabled. Each bin holds the sum of the main
+    /// spectrum's bins reassigned to it, with phases referred to the centre
+    /// of the frame.
+    pub fn squeezed(&self) -> Option<&[Complex<f32>]> {
+        if self.config.synchrosqueeze {
+            Some(&self.squeezed)
+        } else {
+            None
+        }
+    }
+
+    /// Reassigns the bins of a frame of frame_len samples.
+    pub fn process<S: Sample>(&mut self, frame: &[S]) {
+        for (window, spectrum) in [
+            (&self.window, &mut self.spectrum),
+            (&self.derivative_window, &mut self.derivative_spectrum),
+            (&self.ramped_window, &mut self.ramped_spectrum),
+        ] {
+            for ((input, &sample), &w) in self.indata.iter_mut().zip(frame).zip(window) {
+                *input = sample.as_f32() * w;
+            }
+            self.real_to_complex
+                .process_with_scratch(&mut self.indata, spectrum, &mut self.scratch)
+                .unwrap();
+        }
+
+        let max_power = self
+            .spectrum
+            .iter()
+            .map(|bin| bin.norm_sqr())
+            .fold(0.0, f32::max);
+        let threshold = max_power * 10f32.powf(self.config.threshold_db / 10.0);
+        let sample_rate = self.axis.sample_rate() as f32;
+        let centre = self.window.len() as f32 / 2.0;
+        let last_bin = self.bins.len() - 1;
+        self.squeezed
+            .iter_mut()
+            .for_each(|bin| *bin = Complex::new(0.0, 0.0));
+
+        for (k, bin) in self.bins.iter_mut().enumerate() {
+            let x = self.spectrum[k];
+            let power = x.norm_sqr();
+            let nominal = self.axis.bin_to_hz(k as f32);
+            *bin = if power > threshold && power > 0.0 {
+                let frequency_offset = -(self.derivative_spectrum[k] * x.conj()).im / power;
+                let time_offset = (self.ramped_spectrum[k] * x.conj()).re / power;
+                ReassignedBin {
+                    frequency: nominal + frequency_offset * sample_rate / (2.0 * PI),
+                    time: (centre + time_offset) / sample_rate,
+                    magnitude: power.sqrt(),
+                }
+            } else {
+                ReassignedBin {
+                    frequency: nominal,
+                    time: centre / sample_rate,
+                    magnitude: power.sqrt(),
+                }
+            };
+
+            if self.config.synchrosqueeze {
+                // Refer the phase to the centre of the frame so the bins of a
+                // component add up coherently.
+                let centred = if k % 2 == 0 { x } else { -x };
+                let target = self.axis.hz_to_bin(bin.frequency).round();
+                if target >= 0.0 && target as usize <= last_bin {
+                    self.squeezed[target as usize] += centred;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reassigner(config: ReassignmentConfig) -> Reassigner {
+        let planner = Rc::new(RefCell::new(RealFftPlanner::new()));
+        Reassigner::new(&planner, 8000, 1024, config)
+    }
+
+    #[test]
+    fn sine_is_moved_to_its_frequency() {
+        let frame: Vec<f32> = (0..1024)
+            .map(|n| (2.0 * PI * 1003.0 * n as f32 / 8000.0).sin())
+            .collect();
+        let mut reassigner = reassigner(ReassignmentConfig::default());
+        reassigner.process(&frame);
+
+        // Bins of the main lobe all point at the sine, centred in time.
+        for bin in &reassigner.bins()[127..=130] {
+            assert!((bin.frequency - 1003.0).abs() < 0.1);
+            assert!((bin.time - 0.064).abs() < 1e-4);
+        }
+        assert!(reassigner.squeezed().is_none());
+    }
+
+    #[test]
+    fn impulse_is_moved_to_its_time() {
+        let mut frame = vec![0.0f32; 1024];
+        frame[400] = 1.0;
+        let mut reassigner = reassigner(ReassignmentConfig::default());
+        reassigner.process(&frame);
+
+        for bin in &reassigner.bins()[1..512] {
+            assert!((bin.time - 0.05).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn synchrosqueezing_concentrates_a_sine() {
+        let frame: Vec<f32> = (0..1024)
+            .map(|n| (2.0 * PI * 1003.0 * n as f32 / 8000.0).sin())
+            .collect();
+        let mut reassigner = reassigner(ReassignmentConfig {
+            synchrosqueeze: true,
+            ..ReassignmentConfig::default()
+        });
+        reassigner.process(&frame);
+
+        let peak = reassigner.spectrum()[128].norm();
+        let squeezed = reassigner.squeezed().unwrap();
+        assert!(squeezed[128].norm() > 1.5 * peak);
+        assert!(squeezed[127].norm() < 1e-3 * peak);
+        assert!(squeezed[129].norm() < 1e-3 * peak);
+    }
+}
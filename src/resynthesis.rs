@@ -0,0 +1,429 @@
+//! Short time Fourier transform resynthesis. Each frame is windowed,
+//! transformed, handed to a spectral processor, transformed back, windowed
+//! again and overlap added (WOLA) before being written to a sink.
+
+use crate::error::ConfigError;
+use crate::frequency_axis::FrequencyAxis;
+use crate::realtime_fft::realtime_fft_src::{Sample, SampleTap, SrcInfo};
+use crate::sample_window::SampleWindow;
+use crate::wav::WavWriter;
+use crate::window::WindowFunction;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use rustfft::num_complex::Complex;
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Modifies the spectrum of each frame before resynthesis.
+pub trait SpectralProcessor {
+    /// Processes the one-sided spectrum of a frame in place.
+    fn process(&mut self, spectrum: &mut [Complex<f32>], axis: &FrequencyAxis);
+}
+
+impl<F: FnMut(&mut [Complex<f32>], &FrequencyAxis)> SpectralProcessor for F {
+    fn process(&mut self, spectrum: &mut [Complex<f32>], axis: &FrequencyAxis) {
+        self(spectrum, axis)
+    }
+}
+
+/// Destination of resynthesized samples.
+pub trait SampleSink {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()>;
+}
+
+impl SampleSink for WavWriter {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        WavWriter::write(self, samples)
+    }
+}
+
+/// Feeds the output to another source, e.g. a ChannelSrc read by a
+/// RealtimeFft. The SrcInfo must be created large enough for its reader.
+impl<S: Sample> SampleSink for SrcInfo<S> {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        self.push_callback_data(samples, 0);
+        Ok(())
+    }
+}
+
+impl SampleSink for Vec<f32> {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        self.extend_from_slice(samples);
+        Ok(())
+    }
+}
+
+/// Parameters of the resynthesis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResynthesisConfig {
+    pub fft_size: usize,
+    /// Number of samples between the start of two frames.
+    pub hop: usize,
+    /// Window applied before the forward and after the inverse fft.
+    pub window: WindowFunction,
+}
+
+impl ResynthesisConfig {
+    /// Checks that the hop is between 1 and the fft size.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.hop == 0 || self.hop > self.fft_size {
+            return Err(ConfigError::new("hop must be between 1 and fft_size"));
+        }
+        Ok(())
+    }
+
+    /// Hann window with 75% overlap. Its square overlap adds to a constant
+    /// so processing artefacts are spread evenly.
+    pub fn hann(fft_size: usize) -> Self {
+        ResynthesisConfig {
+            fft_size,
+            hop: fft_size / 4,
+            window: WindowFunction::Hann,
+        }
+    }
+
+    /// Blackman-Harris window with 87.5% overlap, for processing that needs
+    /// low spectral leakage.
+    pub fn blackman_harris(fft_size: usize) -> Self {
+        ResynthesisConfig {
+            fft_size,
+            hop: fft_size / 8,
+            window: WindowFunction::BlackmanHarris,
+        }
+    }
+
+    /// Returns the delay between input and output in samples before the
+    /// output is realigned.
+    pub fn latency(&self) -> usize {
+        self.fft_size - self.hop
+    }
+}
+
+impl Default for ResynthesisConfig {
+    fn default() -> Self {
+        ResynthesisConfig::hann(2048)
+    }
+}
+
+/// STFT analysis, processing and overlap-add resynthesis of a stream.
+/// Without processing the output equals the input.
+pub struct Resynthesizer<P: SpectralProcessor, S: SampleSink> {
+    config: ResynthesisConfig,
+    axis: FrequencyAxis,
+    processor: P,
+    sink: S,
+    real_to_complex: Arc<dyn RealToComplex<f32>>,
+    complex_to_real: Arc<dyn ComplexToReal<f32>>,
+    window: Vec<f32>,
+    /// Inverse of the overlap added squared window at each position within
+    /// a hop, including the 1 / fft_size of the inverse fft.
+    normalisation: Vec<f32>,
+    /// Most recent fft_size input samples.
+    pending: SampleWindow<f32>,
+    /// Input samples still to receive before the next frame.
+    until_frame: usize,
+    /// Overlap added output of the frames processed so far, a ring starting
+    /// at overlap_start.
+    overlap: Vec<f32>,
+    overlap_start: usize,
+    /// One hop of normalised output.
+    output: Vec<f32>,
+    /// Output samples still to drop to realign output with input.
+    skip: usize,
+    /// Number of input samples received.
+    received: usize,
+    indata: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    outdata: Vec<f32>,
+    /// Scratch of each fft, kept so processing a frame doesn't allocate.
+    forward_scratch: Vec<Complex<f32>>,
+    inverse_scratch: Vec<Complex<f32>>,
+}
+
+impl<P: SpectralProcessor, S: SampleSink> Resynthesizer<P, S> {
+    /// Returns a resynthesizer for a signal at `sample_rate`, planning its
+    /// ffts with the given planner.
+    pub fn new(
+        fft_planner: &Rc<RefCell<RealFftPlanner<f32>>>,
+        sample_rate: u32,
+        config: ResynthesisConfig,
+        processor: P,
+        sink: S,
+    ) -> Result<Self, ConfigError> {
+        config.validate()?;
+        let mut planner = fft_planner.borrow_mut();
+        let real_to_complex = planner.plan_fft_forward(config.fft_size);
+        let complex_to_real = planner.plan_fft_inverse(config.fft_size);
+        let window = config.window.coefficients(config.fft_size);
+
+        // Dividing by the overlap added squared window reconstructs the input
+        // exactly for any window and hop where it doesn't vanish.
+        let normalisation = (0..config.hop)
+            .map(|i| {
+                let sum: f32 = window
+                    .iter()
+                    .skip(i)
+                    .step_by(config.hop)
+                    .map(|w| w * w)
+                    .sum();
+                if sum > f32::EPSILON {
+                    1.0 / (sum * config.fft_size as f32)
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        // The window starts out as zeros so the first frame ends with the
+        // first hop of input.
+        Ok(Resynthesizer {
+            config,
+            axis: FrequencyAxis::new(sample_rate, config.fft_size),
+            processor,
+            sink,
+            window,
+            normalisation,
+            pending: SampleWindow::new(config.fft_size),
+            until_frame: config.hop,
+            overlap: vec![0.0; config.fft_size],
+            overlap_start: 0,
+            output: vec![0.0; config.hop],
+            skip: config.latency(),
+            received: 0,
+            indata: real_to_complex.make_input_vec(),
+            spectrum: real_to_complex.make_output_vec(),
+            outdata: complex_to_real.make_output_vec(),
+            forward_scratch: real_to_complex.make_scratch_vec(),
+            inverse_scratch: complex_to_real.make_scratch_vec(),
+            real_to_complex,
+            complex_to_real,
+        })
+    }
+
+    /// Returns the configuration of the resynthesizer.
+    pub fn config(&self) -> &ResynthesisConfig {
+        &self.config
+    }
+
+    /// Returns the spectral processor.
+    pub fn processor_mut(&mut self) -> &mut P {
+        &mut self.processor
+    }
+
+    /// Returns the sink.
+    pub fn sink_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    /// Consumes the resynthesizer, returning its sink. Call flush first to
+    /// receive the end of the input.
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+
+    /// Adds samples, writing the output of every frame that becomes
+    /// complete to the sink.
+    pub fn push(&mut self, samples: &[f32]) -> io::Result<()> {
+        self.received += samples.len();
+        let mut samples = samples;
+        while !samples.is_empty() {
+            let len = self.until_frame.min(samples.len());
+            self.pending.push(&samples[..len]);
+            self.until_frame -= len;
+            samples = &samples[len..];
+            if self.until_frame == 0 {
+                self.process_frame()?;
+                self.until_frame = self.config.hop;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds every sample available in a tap of the audio source.
+    /// The samples are consumed even if the sink fails.
+    pub fn push_from(&mut self, tap: &mut SampleTap) -> io::Result<()> {
+        let mut result = Ok(());
+        tap.drain(|samples| {
+            if result.is_ok() {
+                result = self.push(samples);
+            }
+        });
+        result
+    }
+
+    /// Pushes enough silence for every input sample to reach the sink. The
+    /// output is padded to a whole hop.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let hop = self.config.hop;
+        let end = (self.received + self.config.latency()).div_ceil(hop) * hop;
+        let padding = end - self.received;
+        let received = self.received;
+        self.push(&vec![0.0; padding])?;
+        self.received = received;
+        Ok(())
+    }
+
+    /// Processes the pending samples and writes one hop of output.
+    fn process_frame(&mut self) -> io::Result<()> {
+        let fft_size = self.config.fft_size;
+        let hop = self.config.hop;
+        let frame = self.pending.samples();
+        for ((input, &sample), &w) in self.indata.iter_mut().zip(frame).zip(&self.window) {
+            *input = sample * w;
+        }
+        self.real_to_complex
+            .process_with_scratch(
+                &mut self.indata,
+                &mut self.spectrum,
+                &mut self.forward_scratch,
+            )
+            .unwrap();
+
+        self.processor.process(&mut self.spectrum, &self.axis);
+        // The spectrum of a real signal has real DC and Nyquist bins.
+        let last = self.spectrum.len() - 1;
+        self.spectrum[0].im = 0.0;
+        if fft_size % 2 == 0 {
+            self.spectrum[last].im = 0.0;
+        }
+        self.complex_to_real
+            .process_with_scratch(
+                &mut self.spectrum,
+                &mut self.outdata,
+                &mut self.inverse_scratch,
+            )
+            .unwrap();
+
+        // The ring is split at its start so the frame is added oldest first.
+        let (wrapped, oldest) = self.overlap.split_at_mut(self.overlap_start);
+        for ((output, &sample), &w) in oldest
+            .iter_mut()
+            .chain(wrapped.iter_mut())
+            .zip(&self.outdata)
+            .zip(&self.window)
+        {
+            *output += sample * w;
+        }
+
+        // The oldest hop is complete. Move it out and leave zeros for the
+        // end of the next frame.
+        for (i, (output, &norm)) in self.output.iter_mut().zip(&self.normalisation).enumerate() {
+            let overlap = &mut self.overlap[(self.overlap_start + i) % fft_size];
+            *output = *overlap * norm;
+            *overlap = 0.0;
+        }
+        self.overlap_start = (self.overlap_start + hop) % fft_size;
+
+        let skip = self.skip.min(hop);
+        self.skip -= skip;
+        if skip < hop {
+            self.sink.write(&self.output[skip..])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::f32::consts::PI;
+
+    fn planner() -> Rc<RefCell<RealFftPlanner<f32>>> {
+        Rc::new(RefCell::new(RealFftPlanner::new()))
+    }
+
+    fn noise(len: usize) -> Vec<f32> {
+        let mut rng = StdRng::seed_from_u64(5);
+        (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect()
+    }
+
+    fn unchanged(_: &mut [Complex<f32>], _: &FrequencyAxis) {}
+
+    #[test]
+    fn unprocessed_output_equals_input() {
+        let input = noise(10000);
+        for config in [
+            ResynthesisConfig::hann(512),
+            ResynthesisConfig::blackman_harris(1024),
+            ResynthesisConfig {
+                fft_size: 500,
+                hop: 120,
+                window: WindowFunction::Hann,
+            },
+        ] {
+            let mut resynthesizer =
+                Resynthesizer::new(&planner(), 8000, config, unchanged, Vec::new()).unwrap();
+            for chunk in input.chunks(777) {
+                resynthesizer.push(chunk).unwrap();
+            }
+            resynthesizer.flush().unwrap();
+            let output = resynthesizer.into_sink();
+
+            assert_eq!((output.len() + config.latency()) % config.hop, 0);
+            assert!(output.len() >= input.len());
+            for (&out, &sample) in output.iter().zip(&input) {
+                assert!((out - sample).abs() < 1e-4);
+            }
+            assert!(output[input.len()..].iter().all(|x| x.abs() < 1e-4));
+        }
+    }
+
+    #[test]
+    fn removed_bins_are_silent() {
+        // 250 Hz is kept and 2 kHz removed.
+        let input: Vec<f32> = (0..16000)
+            .map(|n| {
+                let t = n as f32 / 8000.0;
+                (2.0 * PI * 250.0 * t).sin() + (2.0 * PI * 2000.0 * t).sin()
+            })
+            .collect();
+        let lowpass = |spectrum: &mut [Complex<f32>], axis: &FrequencyAxis| {
+            for (bin, value) in spectrum.iter_mut().enumerate() {
+                if axis.bin_to_hz(bin as f32) > 1000.0 {
+                    *value = Complex::new(0.0, 0.0);
+                }
+            }
+        };
+        let config = ResynthesisConfig::hann(1024);
+        let mut resynthesizer =
+            Resynthesizer::new(&planner(), 8000, config, lowpass, Vec::new()).unwrap();
+        resynthesizer.push(&input).unwrap();
+        let output = resynthesizer.into_sink();
+
+        for (n, &out) in output.iter().enumerate().skip(1024) {
+            let expected = (2.0 * PI * 250.0 * n as f32 / 8000.0).sin();
+            assert!((out - expected).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn tap_is_drained() {
+        let input = noise(3000);
+        let mut src_info: SrcInfo = SrcInfo::new(0);
+        let mut tap = src_info.add_sample_tap(4096);
+        src_info.push_callback_data(&input, 0);
+
+        let config = ResynthesisConfig::hann(256);
+        let mut resynthesizer =
+            Resynthesizer::new(&planner(), 8000, config, unchanged, Vec::new()).unwrap();
+        resynthesizer.push_from(&mut tap).unwrap();
+        assert!(tap.is_empty());
+        let output = resynthesizer.into_sink();
+        assert_eq!(output.len(), 3000 / 64 * 64 - config.latency());
+        for (&out, &sample) in output.iter().zip(&input) {
+            assert!((out - sample).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn invalid_hop_is_rejected() {
+        let config = ResynthesisConfig {
+            hop: 0,
+            ..ResynthesisConfig::default()
+        };
+        assert!(Resynthesizer::new(&planner(), 8000, config, unchanged, Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! Fixed length window over the most recent samples of a stream, used by
+//! the analyses that run on the raw samples of a sample tap.
+
+/// Holds the most recent samples of a stream, oldest first. Every sample is
+/// stored twice so the window is always contiguous and pushing a sample
+/// costs the same whatever the window length.
+#[derive(Clone, Debug)]
+pub struct SampleWindow<T> {
+    /// Two copies of the window, one after the other.
+    buffer: Vec<T>,
+    /// Index of the oldest sample.
+    start: usize,
+    /// Number of samples pushed since the last reset, at most the capacity.
+    filled: usize,
+}
+
+impl<T: Copy + Default> SampleWindow<T> {
+    /// Returns a window of `capacity` samples initially holding defaults.
+    pub fn new(capacity: usize) -> Self {
+        SampleWindow {
+            buffer: vec![T::default(); 2 * capacity],
+            start: 0,
+            filled: 0,
+        }
+    }
+
+    /// Returns the number of samples in the window.
+    pub fn capacity(&self) -> usize {
+        self.buffer.len() / 2
+    }
+
+    /// Returns the number of samples pushed since the last reset, at most
+    /// the capacity.
+    pub fn filled(&self) -> usize {
+        self.filled
+    }
+
+    /// Returns true once a whole window of samples has been pushed.
+    pub fn is_full(&self) -> bool {
+        self.filled == self.capacity()
+    }
+
+    /// Adds samples, dropping the oldest ones.
+    pub fn push(&mut self, samples: &[T]) {
+        let capacity = self.capacity();
+        for &sample in &samples[samples.len().saturating_sub(capacity)..] {
+            self.push_one(sample);
+        }
+    }
+
+    /// Adds a single sample, dropping the oldest one.
+    pub fn push_one(&mut self, sample: T) {
+        let capacity = self.capacity();
+        if capacity == 0 {
+            return;
+        }
+        self.buffer[self.start] = sample;
+        self.buffer[self.start + capacity] = sample;
+        self.start = (self.start + 1) % capacity;
+        self.filled = (self.filled + 1).min(capacity);
+    }
+
+    /// Returns the window, oldest sample first. Samples not pushed yet are
+    /// defaults.
+    pub fn samples(&self) -> &[T] {
+        &self.buffer[self.start..self.start + self.capacity()]
+    }
+
+    /// Returns the `len` most recent samples, oldest first.
+    pub fn latest(&self, len: usize) -> &[T] {
+        let samples = self.samples();
+        &samples[samples.len() - len.min(samples.len())..]
+    }
+
+    /// Forgets every sample.
+    pub fn reset(&mut self) {
+        self.buffer
+            .iter_mut()
+            .for_each(|sample| *sample = T::default());
+        self.start = 0;
+        self.filled = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_most_recent_samples() {
+        let mut window = SampleWindow::new(4);
+        window.push(&[1, 2, 3]);
+        assert!(!window.is_full());
+        assert_eq!(window.samples(), [0, 1, 2, 3]);
+        window.push(&[4, 5]);
+        assert!(window.is_full());
+        assert_eq!(window.samples(), [2, 3, 4, 5]);
+        window.push(&[6, 7, 8, 9, 10, 11]);
+        assert_eq!(window.samples(), [8, 9, 10, 11]);
+        window.push_one(12);
+        assert_eq!(window.samples(), [9, 10, 11, 12]);
+        assert_eq!(window.latest(2), [11, 12]);
+
+        window.reset();
+        assert_eq!(window.filled(), 0);
+        assert_eq!(window.samples(), [0, 0, 0, 0]);
+    }
+}
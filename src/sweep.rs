@@ -0,0 +1,373 @@
+//! Offline impulse response measurement with exponential sine sweeps
+//! (Farina, 2000). Deconvolving the recorded response with the inverse
+//! filter of the sweep yields the linear impulse response, preceded in time
+//! by the impulse response of each harmonic distortion product.
+
+use crate::error::ConfigError;
+use crate::wav::{self, WavSpec};
+use realfft::RealFftPlanner;
+use rustfft::num_complex::Complex;
+use std::cell::RefCell;
+use std::f64::consts::PI;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Parameters of an exponential sweep.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SweepConfig {
+    /// Frequency at the start of the sweep in Hz.
+    pub start_hz: f32,
+    /// Frequency at the end of the sweep in Hz.
+    pub end_hz: f32,
+    pub duration: Duration,
+    /// Peak amplitude of the sweep.
+    pub amplitude: f32,
+    /// Length of the raised cosine fades at either end.
+    pub fade: Duration,
+}
+
+impl SweepConfig {
+    /// Checks that the sweep rises from a positive frequency to at most the
+    /// Nyquist frequency of `sample_rate` and lasts at least two samples.
+    pub fn validate(&self, sample_rate: u32) -> Result<(), ConfigError> {
+        if self.start_hz.is_nan() || self.start_hz <= 0.0 {
+            return Err(ConfigError::new("start_hz must be positive"));
+        }
+        if self.end_hz.is_nan() || self.end_hz <= self.start_hz {
+            return Err(ConfigError::new("end_hz must be above start_hz"));
+        }
+        let nyquist = sample_rate as f32 / 2.0;
+        if self.end_hz > nyquist {
+            return Err(ConfigError::new(format!(
+                "end_hz must not exceed the Nyquist frequency of {} Hz",
+                nyquist
+            )));
+        }
+        if (self.duration.as_secs_f64() * sample_rate as f64).round() < 2.0 {
+            return Err(ConfigError::new("duration must be at least two samples"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for SweepConfig {
+    fn default() -> Self {
+        SweepConfig {
+            start_hz: 20.0,
+            end_hz: 20000.0,
+            duration: Duration::from_secs(5),
+            amplitude: 0.5,
+            fade: Duration::from_millis(10),
+        }
+    }
+}
+
+/// An exponential sweep and its inverse filter.
+pub struct ExponentialSweep {
+    config: SweepConfig,
+    sample_rate: u32,
+    sweep: Vec<f32>,
+    inverse: Vec<f32>,
+}
+
+impl ExponentialSweep {
+    /// Generates the sweep at `sample_rate`. The inverse filter is
+    /// normalised with the planner so deconvolving the sweep itself gives a
+    /// unit impulse.
+    pub fn new(
+        fft_planner: &Rc<RefCell<RealFftPlanner<f32>>>,
+        sample_rate: u32,
+        config: SweepConfig,
+    ) -> Result<Self, ConfigError> {
+        config.validate(sample_rate)?;
+        let fs = sample_rate as f64;
+        let len = (config.duration.as_secs_f64() * fs).round() as usize;
+        let rate = sweep_rate(&config);
+        let start = config.start_hz as f64;
+
+        let fade_len = ((config.fade.as_secs_f64() * fs) as usize).min(len / 2);
+        let fade = |n: usize| {
+            let from_edge = n.min(len - 1 - n);
+            if from_edge < fade_len {
+                0.5 - 0.5 * (PI * from_edge as f64 / fade_len as f64).cos()
+            } else {
+                1.0
+            }
+        };
+        let sweep: Vec<f32> = (0..len)
+            .map(|n| {
+                let t = n as f64 / fs;
+                let phase = 2.0 * PI * start * rate * ((t / rate).exp() - 1.0);
+                (config.amplitude as f64 * fade(n) * phase.sin()) as f32
+            })
+            .collect();
+
+        // Time reversed sweep falling by 6 dB per octave towards low
+        // frequencies to compensate for the time the sweep spends there.
+        let end = (len - 1) as f64 / fs;
+        let mut inverse: Vec<f32> = (0..len)
+            .map(|n| {
+                let t = (len - 1 - n) as f64 / fs;
+                (sweep[len - 1 - n] as f64 * ((t - end) / rate).exp()) as f32
+            })
+            .collect();
+
+        // Scale by the mean gain of sweep and inverse inside the band, away
+        // from the fades.
+        let response = convolve(fft_planner, &sweep, &inverse);
+        let spectrum = spectrum(fft_planner, &response, response.len().next_power_of_two());
+        let bin_width = fs / (2 * (spectrum.len() - 1)) as f64;
+        let lower = ((2.0 * start / bin_width) as usize).min(spectrum.len() - 1);
+        let upper = (config.end_hz as f64 / 2.0 / bin_width) as usize;
+        let gain = spectrum[lower..=upper.max(lower)]
+            .iter()
+            .map(|bin| bin.norm())
+            .sum::<f32>()
+            / (upper.max(lower) - lower + 1) as f32;
+        inverse.iter_mut().for_each(|x| *x /= gain);
+
+        Ok(ExponentialSweep {
+            config,
+            sample_rate,
+            sweep,
+            inverse,
+        })
+    }
+
+    /// Returns the configuration of the sweep.
+    pub fn config(&self) -> &SweepConfig {
+        &self.config
+    }
+
+    /// Returns the sample rate of the sweep.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Returns the samples of the sweep.
+    pub fn samples(&self) -> &[f32] {
+        &self.sweep
+    }
+
+    /// Returns the inverse filter of the sweep.
+    pub fn inverse_filter(&self) -> &[f32] {
+        &self.inverse
+    }
+
+    /// Returns how far ahead of the linear impulse response the response of
+    /// the harmonic `order` appears, in samples.
+    pub fn harmonic_offset(&self, order: usize) -> f32 {
+        (sweep_rate(&self.config) * (order as f64).ln() * self.sample_rate as f64) as f32
+    }
+
+    /// Writes the sweep followed by `silence` to a mono WAV file. The
+    /// silence leaves room for the decay of the system being measured.
+    pub fn write_wav<P: AsRef<Path>>(&self, path: P, silence: Duration) -> io::Result<()> {
+        let silence = (silence.as_secs_f64() * self.sample_rate as f64) as usize;
+        let mut samples = self.sweep.clone();
+        samples.resize(samples.len() + silence, 0.0);
+        wav::write_wav(
+            path,
+            WavSpec {
+                sample_rate: self.sample_rate,
+                channels: 1,
+            },
+            &samples,
+        )
+    }
+
+    /// Deconvolves a recording of the response to the sweep, started at the
+    /// same time as playback, into impulse responses.
+    pub fn deconvolve(
+        &self,
+        fft_planner: &Rc<RefCell<RealFftPlanner<f32>>>,
+        recording: &[f32],
+    ) -> ImpulseResponse {
+        ImpulseResponse {
+            samples: convolve(fft_planner, recording, &self.inverse),
+            sample_rate: self.sample_rate,
+            linear_start: self.sweep.len() - 1,
+            harmonic_offsets: (1..)
+                .map(|order| self.harmonic_offset(order))
+                .take_while(|&offset| (offset as usize) < self.sweep.len())
+                .collect(),
+        }
+    }
+}
+
+/// Result of deconvolving a swept sine recording.
+pub struct ImpulseResponse {
+    /// Full deconvolution. Harmonic responses come before linear_start.
+    samples: Vec<f32>,
+    sample_rate: u32,
+    /// Index at which the linear response of a system without delay starts.
+    linear_start: usize,
+    /// Offset before linear_start of the response of each order from 1.
+    harmonic_offsets: Vec<f32>,
+}
+
+impl ImpulseResponse {
+    /// Returns the whole deconvolution.
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    /// Returns the sample rate of the impulse response.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Returns up to `len` samples of the linear impulse response, starting
+    /// `pre` samples early. The response is band limited by the sweep so it
+    /// rings before its peak. A few thousand samples of pre-ringing keep the
+    /// low frequency response accurate.
+    pub fn linear(&self, pre: usize, len: usize) -> &[f32] {
+        let start = self.linear_start.saturating_sub(pre);
+        let end = (self.linear_start + len).min(self.samples.len());
+        &self.samples[start..end]
+    }
+
+    /// Returns up to `len` samples of the impulse response of the harmonic
+    /// `order`, 1 being the linear response, starting `pre` samples early.
+    /// The length is limited so the response doesn't run into the response
+    /// of the order below. Returns None for orders the sweep can't separate.
+    pub fn harmonic(&self, order: usize, pre: usize, len: usize) -> Option<&[f32]> {
+        if order <= 1 {
+            return Some(self.linear(pre, len));
+        }
+        let offset = *self.harmonic_offsets.get(order - 1)?;
+        let gap = offset - self.harmonic_offsets[order - 2];
+        let start = (self.linear_start as f32 - offset).round() as usize;
+        let len = len.min((gap as usize).saturating_sub(pre));
+        Some(&self.samples[start.saturating_sub(pre)..start + len])
+    }
+
+    /// Returns the frequency response of a segment of the impulse response,
+    /// zero padded to `fft_size`.
+    pub fn frequency_response(
+        &self,
+        fft_planner: &Rc<RefCell<RealFftPlanner<f32>>>,
+        segment: &[f32],
+        fft_size: usize,
+    ) -> Vec<Complex<f32>> {
+        spectrum(fft_planner, segment, fft_size)
+    }
+
+    /// Writes `len` samples of the linear impulse response starting `pre`
+    /// samples early to a mono WAV file.
+    pub fn write_wav<P: AsRef<Path>>(&self, path: P, pre: usize, len: usize) -> io::Result<()> {
+        wav::write_wav(
+            path,
+            WavSpec {
+                sample_rate: self.sample_rate,
+                channels: 1,
+            },
+            self.linear(pre, len),
+        )
+    }
+}
+
+/// Time in seconds for the sweep frequency to rise by a factor of e.
+fn sweep_rate(config: &SweepConfig) -> f64 {
+    config.duration.as_secs_f64() / (config.end_hz as f64 / config.start_hz as f64).ln()
+}
+
+/// Returns the spectrum of `samples` zero padded to `fft_size`.
+fn spectrum(
+    fft_planner: &Rc<RefCell<RealFftPlanner<f32>>>,
+    samples: &[f32],
+    fft_size: usize,
+) -> Vec<Complex<f32>> {
+    let real_to_complex = fft_planner.borrow_mut().plan_fft_forward(fft_size);
+    let mut indata = real_to_complex.make_input_vec();
+    let len = samples.len().min(fft_size);
+    indata[..len].copy_from_slice(&samples[..len]);
+    let mut spectrum = real_to_complex.make_output_vec();
+    real_to_complex.process(&mut indata, &mut spectrum).unwrap();
+    spectrum
+}
+
+/// Returns the full linear convolution of two signals.
+fn convolve(fft_planner: &Rc<RefCell<RealFftPlanner<f32>>>, a: &[f32], b: &[f32]) -> Vec<f32> {
+    let len = a.len() + b.len() - 1;
+    let fft_size = len.next_power_of_two();
+    let mut product = spectrum(fft_planner, a, fft_size);
+    for (x, y) in product.iter_mut().zip(spectrum(fft_planner, b, fft_size)) {
+        *x *= y / fft_size as f32;
+    }
+    let complex_to_real = fft_planner.borrow_mut().plan_fft_inverse(fft_size);
+    let mut output = complex_to_real.make_output_vec();
+    complex_to_real.process(&mut product, &mut output).unwrap();
+    output.truncate(len);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn planner() -> Rc<RefCell<RealFftPlanner<f32>>> {
+        Rc::new(RefCell::new(RealFftPlanner::new()))
+    }
+
+    #[test]
+    fn delayed_gain_with_second_harmonic() {
+        let planner = planner();
+        let config = SweepConfig {
+            start_hz: 50.0,
+            end_hz: 7000.0,
+            duration: Duration::from_secs(1),
+            ..SweepConfig::default()
+        };
+        let sweep = ExponentialSweep::new(&planner, 16000, config).unwrap();
+        let mut recording = vec![0.0; 40];
+        recording.extend(sweep.samples().iter().map(|&x| 0.5 * x + 0.2 * x * x));
+        let response = sweep.deconvolve(&planner, &recording);
+
+        let linear = response.linear(1000, 2000);
+        let peak = (0..linear.len())
+            .max_by(|&a, &b| linear[a].abs().total_cmp(&linear[b].abs()))
+            .unwrap();
+        assert_eq!(peak, 1040);
+
+        // Flat inside the band, well away from the fades.
+        let spectrum = response.frequency_response(&planner, linear, 4096);
+        for bin in &spectrum[30..890] {
+            assert!((bin.norm() - 0.5).abs() < 0.02);
+        }
+
+        // The square term only produces a second harmonic.
+        let max = |segment: &[f32]| segment.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+        let second = max(response.harmonic(2, 200, 1000).unwrap());
+        let third = max(response.harmonic(3, 200, 1000).unwrap());
+        assert!(second > 0.02);
+        assert!(third < second / 100.0);
+    }
+
+    #[test]
+    fn invalid_sweeps_are_rejected() {
+        let planner = planner();
+        let invalid = [
+            SweepConfig {
+                duration: Duration::from_secs(0),
+                ..SweepConfig::default()
+            },
+            SweepConfig {
+                start_hz: 0.0,
+                ..SweepConfig::default()
+            },
+            SweepConfig {
+                start_hz: 1000.0,
+                end_hz: 1000.0,
+                ..SweepConfig::default()
+            },
+            // The default end of 20 kHz is above Nyquist at 32 kHz.
+            SweepConfig::default(),
+        ];
+        for config in invalid.iter() {
+            assert!(ExponentialSweep::new(&planner, 32000, *config).is_err());
+        }
+    }
+}
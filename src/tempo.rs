@@ -0,0 +1,263 @@
+//! Tempo and beat phase estimation from the onset detection function. The
+//! irregularly timed frames of RealtimeFft are resampled onto a fixed grid
+//! before the periodicity of the envelope is measured.
+
+use crate::error::ConfigError;
+use crate::peaks;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Parameters of the tempo tracker.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TempoConfig {
+    pub min_bpm: f32,
+    pub max_bpm: f32,
+    /// Tempo most likely a priori. Resolves octave ambiguities.
+    pub preferred_bpm: f32,
+    /// Length of onset envelope analysed.
+    pub history: Duration,
+    /// Rate of the resampled onset envelope in Hz.
+    pub envelope_rate: f32,
+}
+
+impl TempoConfig {
+    /// Checks that the tempo range is positive and not empty and that the
+    /// envelope rate gives a grid step of at least a microsecond.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.min_bpm.is_nan() || self.min_bpm <= 0.0 {
+            return Err(ConfigError::new("min_bpm must be positive"));
+        }
+        if self.max_bpm.is_nan() || self.max_bpm.is_infinite() || self.max_bpm <= self.min_bpm {
+            return Err(ConfigError::new("max_bpm must be finite and above min_bpm"));
+        }
+        if self.envelope_rate.is_nan() || self.envelope_rate <= 0.0 || self.envelope_rate > 1e6 {
+            return Err(ConfigError::new(
+                "envelope_rate must be positive and at most 1 MHz",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for TempoConfig {
+    fn default() -> Self {
+        TempoConfig {
+            min_bpm: 60.0,
+            max_bpm: 200.0,
+            preferred_bpm: 120.0,
+            history: Duration::from_secs(8),
+            envelope_rate: 100.0,
+        }
+    }
+}
+
+/// Estimated tempo and beat position.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TempoEstimate {
+    /// Beats per minute.
+    pub bpm: f32,
+    /// Fraction of the current beat that has elapsed, in [0, 1).
+    pub beat_phase: f32,
+    /// Instant of the most recent beat.
+    pub last_beat: Instant,
+    /// Predicted instant of the next beat.
+    pub next_beat: Instant,
+    /// Periodicity of the envelope at the tempo, in [0, 1].
+    pub confidence: f32,
+}
+
+/// Tracks the tempo of an onset detection function.
+pub struct TempoTracker {
+    config: TempoConfig,
+    /// Interval between envelope samples.
+    step: Duration,
+    /// Onset envelope sampled at envelope_rate, oldest first.
+    envelope: VecDeque<f32>,
+    /// Instant of the newest envelope sample.
+    envelope_end: Option<Instant>,
+    /// Last value pushed, used for interpolation.
+    last: Option<(Instant, f32)>,
+}
+
+impl TempoTracker {
+    /// Returns a new TempoTracker.
+    pub fn new(config: TempoConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        Ok(TempoTracker {
+            config,
+            step: Duration::from_secs_f64(1.0 / config.envelope_rate as f64),
+            envelope: VecDeque::new(),
+            envelope_end: None,
+            last: None,
+        })
+    }
+
+    /// Forgets the onset envelope.
+    pub fn reset(&mut self) {
+        self.envelope.clear();
+        self.envelope_end = None;
+        self.last = None;
+    }
+
+    /// Adds a value of the onset detection function at `instant`.
+    pub fn push(&mut self, instant: Instant, value: f32) {
+        let step = self.step;
+        let (last_instant, last_value) = match self.last {
+            Some(last) if instant > last.0 => last,
+            Some(_) => return,
+            None => {
+                self.last = Some((instant, value));
+                self.envelope_end = Some(instant);
+                self.envelope.push_back(value);
+                return;
+            }
+        };
+
+        // Linearly interpolate every grid point up to the new value.
+        let span = (instant - last_instant).as_secs_f32();
+        let mut end = self.envelope_end.unwrap();
+        while end + step <= instant {
+            end += step;
+            let t = (end - last_instant).as_secs_f32() / span;
+            self.envelope
+                .push_back(last_value + t * (value - last_value));
+        }
+        self.envelope_end = Some(end);
+        self.last = Some((instant, value));
+
+        let max_len = (self.config.history.as_secs_f32() * self.config.envelope_rate) as usize;
+        while self.envelope.len() > max_len {
+            self.envelope.pop_front();
+        }
+    }
+
+    /// Estimates the tempo. Returns None until the envelope spans a few beats
+    /// at the slowest tempo.
+    pub fn estimate(&self) -> Option<TempoEstimate> {
+        let rate = self.config.envelope_rate;
+        let min_lag = (60.0 * rate / self.config.max_bpm).floor().max(1.0) as usize;
+        let max_lag = (60.0 * rate / self.config.min_bpm).ceil() as usize;
+        if self.envelope.len() < 2 * max_lag + 1 {
+            return None;
+        }
+
+        let mean = self.envelope.iter().sum::<f32>() / self.envelope.len() as f32;
+        let envelope: Vec<f32> = self.envelope.iter().map(|x| x - mean).collect();
+        let autocorrelation = |lag: usize| {
+            envelope
+                .iter()
+                .zip(&envelope[lag..])
+                .map(|(a, b)| a * b)
+                .sum::<f32>()
+                / (envelope.len() - lag) as f32
+        };
+        let energy = autocorrelation(0);
+        if energy <= 0.0 {
+            return None;
+        }
+
+        // Autocorrelation at every lag, reinforced by twice the lag and
+        // weighted by a log-Gaussian prior around the preferred tempo.
+        let acf: Vec<f32> = (0..=2 * max_lag).map(autocorrelation).collect();
+        let score = |lag: usize| {
+            let octaves = (self.config.preferred_bpm * lag as f32 / (60.0 * rate)).log2();
+            (acf[lag] + 0.5 * acf[2 * lag]) * (-0.5 * octaves * octaves).exp()
+        };
+        let best = (min_lag..=max_lag).max_by(|&a, &b| score(a).total_cmp(&score(b)))?;
+        let (offset, _) = if best > min_lag && best < max_lag {
+            peaks::fit_parabola(acf[best - 1], acf[best], acf[best + 1])
+        } else {
+            (0.0, acf[best])
+        };
+        let period = best as f32 + offset;
+
+        // Beat phase: the offset from the newest sample whose comb of past
+        // beats collects the most onset energy.
+        let beats = (envelope.len() as f32 / period) as usize;
+        let newest = envelope.len() - 1;
+        let since_beat = (0..best)
+            .max_by(|&a, &b| {
+                let comb = |phase: usize| {
+                    (0..beats)
+                        .map(|k| phase as f32 + k as f32 * period)
+                        .take_while(|&t| t.round() as usize <= newest)
+                        .map(|t| envelope[newest - t.round() as usize])
+                        .sum::<f32>()
+                };
+                comb(a).total_cmp(&comb(b))
+            })
+            .unwrap_or(0);
+
+        let end = self.envelope_end?;
+        let beat_length = Duration::from_secs_f32(period / rate);
+        let last_beat = end - Duration::from_secs_f32(since_beat as f32 / rate);
+        Some(TempoEstimate {
+            bpm: 60.0 * rate / period,
+            beat_phase: (since_beat as f32 / period).min(1.0 - f32::EPSILON),
+            last_beat,
+            next_beat: last_beat + beat_length,
+            confidence: (acf[best] / energy).clamp(0.0, 1.0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tempo_of_a_click_track() {
+        let mut tracker = TempoTracker::new(TempoConfig::default()).unwrap();
+        let start = Instant::now();
+        // 128 bpm clicks at frames alternately 9 and 13 ms apart.
+        let beat = 60.0 / 128.0;
+        let mut t = 0.0;
+        let mut frame = 0;
+        while t < 10.0 {
+            let since_beat = t % beat;
+            let value = if since_beat < 0.011 { 1.0 } else { 0.0 };
+            tracker.push(start + Duration::from_secs_f64(t), value);
+            t += if frame % 2 == 0 { 0.009 } else { 0.013 };
+            frame += 1;
+        }
+
+        let estimate = tracker.estimate().unwrap();
+        assert!((estimate.bpm - 128.0).abs() < 1.0);
+        assert!(estimate.confidence > 0.5);
+        // The predicted beat falls on a click.
+        let next = (estimate.next_beat - start).as_secs_f64() / beat;
+        assert!((next - next.round()).abs() < 0.05);
+    }
+
+    #[test]
+    fn needs_a_few_beats() {
+        let mut tracker = TempoTracker::new(TempoConfig::default()).unwrap();
+        let start = Instant::now();
+        for n in 0..150 {
+            tracker.push(
+                start + Duration::from_millis(10 * n),
+                (n % 50 == 0) as u8 as f32,
+            );
+        }
+        assert!(tracker.estimate().is_none());
+        tracker.reset();
+        assert!(tracker.estimate().is_none());
+    }
+
+    #[test]
+    fn invalid_rates_are_rejected() {
+        for &envelope_rate in &[0.0, -100.0, f32::NAN, f32::INFINITY] {
+            let config = TempoConfig {
+                envelope_rate,
+                ..TempoConfig::default()
+            };
+            assert!(TempoTracker::new(config).is_err());
+        }
+        let config = TempoConfig {
+            min_bpm: 200.0,
+            max_bpm: 60.0,
+            ..TempoConfig::default()
+        };
+        assert!(TempoTracker::new(config).is_err());
+    }
+}
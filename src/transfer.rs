@@ -0,0 +1,452 @@
+//! Dual channel analysis of a system driven by a known signal. The
+//! reference channel records the stimulus and the measurement channel the
+//! response of the system, e.g. a loudspeaker in a room. Both must be
+//! sample synchronized such as the taps of DualSrcInfo.
+
+use crate::error::ConfigError;
+use crate::frequency_axis::FrequencyAxis;
+use crate::peaks;
+use crate::realtime_fft::realtime_fft_src::SampleTap;
+use crate::window::WindowFunction;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use rustfft::num_complex::Complex;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Parameters of the transfer function analyzer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransferConfig {
+    /// Number of samples per frame.
+    pub fft_size: usize,
+    /// Fraction of a frame shared with the next one, in [0, 1).
+    pub overlap: f32,
+    pub window: WindowFunction,
+    /// Number of frames in the exponential average. Frames are averaged
+    /// linearly until this many have been seen. None averages every frame
+    /// since the last reset.
+    pub averages: Option<usize>,
+    /// Longest delay between the channels searched for.
+    pub max_delay: Duration,
+}
+
+impl TransferConfig {
+    /// Checks that frames are at least 2 samples long and that the overlap
+    /// is in [0, 1).
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.fft_size < 2 {
+            return Err(ConfigError::new("fft_size must be at least 2"));
+        }
+        if !(0.0..1.0).contains(&self.overlap) {
+            return Err(ConfigError::new("overlap must be in [0, 1)"));
+        }
+        Ok(())
+    }
+
+    /// Returns the number of samples between the start of two frames.
+    pub fn hop(&self) -> usize {
+        ((self.fft_size as f32 * (1.0 - self.overlap)).round() as usize).max(1)
+    }
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        TransferConfig {
+            fft_size: 8192,
+            overlap: 0.5,
+            window: WindowFunction::Hann,
+            averages: Some(16),
+            max_delay: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Frequency response estimated from the averaged spectra.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferFunction {
+    /// Response estimate unbiased by noise on the measurement channel.
+    pub h1: Vec<Complex<f32>>,
+    /// Response estimate unbiased by noise on the reference channel.
+    pub h2: Vec<Complex<f32>>,
+    /// Magnitude squared coherence of each bin, in [0, 1].
+    pub coherence: Vec<f32>,
+    /// Averaged cross spectrum, conj(reference) * measurement.
+    pub cross_spectrum: Vec<Complex<f32>>,
+    pub axis: FrequencyAxis,
+}
+
+impl TransferFunction {
+    /// Returns the magnitude of H1 at `bin` in dB.
+    pub fn magnitude_db(&self, bin: usize) -> f32 {
+        20.0 * self.h1[bin].norm().log10()
+    }
+
+    /// Returns the phase of H1 at `bin` in radians.
+    pub fn phase(&self, bin: usize) -> f32 {
+        self.h1[bin].arg()
+    }
+}
+
+/// Estimates the transfer function between two synchronized channels.
+pub struct TransferAnalyzer {
+    config: TransferConfig,
+    axis: FrequencyAxis,
+    real_to_complex: Arc<dyn RealToComplex<f32>>,
+    complex_to_real: Arc<dyn ComplexToReal<f32>>,
+    window: Vec<f32>,
+    /// Samples not yet part of a complete frame.
+    pending_reference: Vec<f32>,
+    pending_measurement: Vec<f32>,
+    /// Samples still to drop from each channel to compensate the delay.
+    skip_reference: usize,
+    skip_measurement: usize,
+    /// Delay compensated so far. Positive when the measurement lags.
+    delay: isize,
+    /// Averaged auto and cross spectra.
+    reference_power: Vec<f32>,
+    measurement_power: Vec<f32>,
+    cross_spectrum: Vec<Complex<f32>>,
+    /// Number of frames averaged so far.
+    frames: usize,
+    indata: Vec<f32>,
+    reference_spectrum: Vec<Complex<f32>>,
+    measurement_spectrum: Vec<Complex<f32>>,
+    /// Samples popped from the taps by push_from.
+    reference_scratch: Vec<f32>,
+    measurement_scratch: Vec<f32>,
+}
+
+impl TransferAnalyzer {
+    /// Returns an analyzer for signals at `sample_rate`, planning its ffts
+    /// with the given planner.
+    pub fn new(
+        fft_planner: &Rc<RefCell<RealFftPlanner<f32>>>,
+        sample_rate: u32,
+        config: TransferConfig,
+    ) -> Result<Self, ConfigError> {
+        config.validate()?;
+        let mut planner = fft_planner.borrow_mut();
+        let real_to_complex = planner.plan_fft_forward(config.fft_size);
+        let complex_to_real = planner.plan_fft_inverse(config.fft_size);
+        let num_bins = config.fft_size / 2 + 1;
+        Ok(TransferAnalyzer {
+            config,
+            axis: FrequencyAxis::new(sample_rate, config.fft_size),
+            window: config.window.coefficients(config.fft_size),
+            pending_reference: Vec::with_capacity(config.fft_size * 2),
+            pending_measurement: Vec::with_capacity(config.fft_size * 2),
+            skip_reference: 0,
+            skip_measurement: 0,
+            delay: 0,
+            reference_power: vec![0.0; num_bins],
+            measurement_power: vec![0.0; num_bins],
+            cross_spectrum: vec![Complex::new(0.0, 0.0); num_bins],
+            frames: 0,
+            indata: real_to_complex.make_input_vec(),
+            reference_spectrum: real_to_complex.make_output_vec(),
+            measurement_spectrum: real_to_complex.make_output_vec(),
+            reference_scratch: Vec::new(),
+            measurement_scratch: Vec::new(),
+            real_to_complex,
+            complex_to_real,
+        })
+    }
+
+    /// Returns the configuration of the analyzer.
+    pub fn config(&self) -> &TransferConfig {
+        &self.config
+    }
+
+    /// Returns the number of frames averaged so far.
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Returns the delay currently compensated in samples. Positive when the
+    /// measurement lags the reference.
+    pub fn compensated_delay(&self) -> isize {
+        self.delay
+    }
+
+    /// Forgets the averaged spectra and buffered samples. The compensated
+    /// delay is kept.
+    pub fn reset(&mut self) {
+        self.pending_reference.clear();
+        self.pending_measurement.clear();
+        self.skip_reference = self.delay.min(0).unsigned_abs();
+        self.skip_measurement = self.delay.max(0) as usize;
+        self.reference_power.iter_mut().for_each(|x| *x = 0.0);
+        self.measurement_power.iter_mut().for_each(|x| *x = 0.0);
+        self.cross_spectrum
+            .iter_mut()
+            .for_each(|x| *x = Complex::new(0.0, 0.0));
+        self.frames = 0;
+    }
+
+    /// Adds the same span of samples of each channel, processing every
+    /// frame that becomes complete.
+    pub fn push(&mut self, reference: &[f32], measurement: &[f32]) {
+        assert_eq!(
+            reference.len(),
+            measurement.len(),
+            "Both channels must receive the same number of samples!"
+        );
+        let skip = self.skip_reference.min(reference.len());
+        self.skip_reference -= skip;
+        self.pending_reference.extend_from_slice(&reference[skip..]);
+        let skip = self.skip_measurement.min(measurement.len());
+        self.skip_measurement -= skip;
+        self.pending_measurement
+            .extend_from_slice(&measurement[skip..]);
+
+        let fft_size = self.config.fft_size;
+        let hop = self.config.hop();
+        while self.pending_reference.len() >= fft_size && self.pending_measurement.len() >= fft_size
+        {
+            self.process_frame();
+            let hop = hop.min(fft_size);
+            self.pending_reference.drain(..hop);
+            self.pending_measurement.drain(..hop);
+        }
+    }
+
+    /// Adds every sample available in both taps. Only as many samples as
+    /// the shorter tap holds are taken so the channels stay aligned.
+    pub fn push_from(&mut self, reference: &mut SampleTap, measurement: &mut SampleTap) {
+        let len = reference.len().min(measurement.len());
+        let mut reference_samples = std::mem::take(&mut self.reference_scratch);
+        let mut measurement_samples = std::mem::take(&mut self.measurement_scratch);
+        reference_samples.resize(len, 0.0);
+        measurement_samples.resize(len, 0.0);
+        reference.pop_slice(&mut reference_samples);
+        measurement.pop_slice(&mut measurement_samples);
+        self.push(&reference_samples, &measurement_samples);
+        self.reference_scratch = reference_samples;
+        self.measurement_scratch = measurement_samples;
+    }
+
+    /// Returns the transfer function and coherence averaged so far.
+    pub fn transfer_function(&self) -> TransferFunction {
+        let mut h1 = Vec::with_capacity(self.cross_spectrum.len());
+        let mut h2 = Vec::with_capacity(self.cross_spectrum.len());
+        let mut coherence = Vec::with_capacity(self.cross_spectrum.len());
+        let zero = Complex::new(0.0, 0.0);
+        for ((&gxy, &gxx), &gyy) in self
+            .cross_spectrum
+            .iter()
+            .zip(&self.reference_power)
+            .zip(&self.measurement_power)
+        {
+            h1.push(if gxx > 0.0 { gxy / gxx } else { zero });
+            h2.push(if gxy.norm_sqr() > 0.0 {
+                gyy / gxy.conj()
+            } else {
+                zero
+            });
+            coherence.push(if gxx > 0.0 && gyy > 0.0 {
+                (gxy.norm_sqr() / (gxx * gyy)).min(1.0)
+            } else {
+                0.0
+            });
+        }
+        TransferFunction {
+            h1,
+            h2,
+            coherence,
+            cross_spectrum: self.cross_spectrum.clone(),
+            axis: self.axis,
+        }
+    }
+
+    /// Finds the delay remaining between the channels in samples from the
+    /// peak of the phase transform weighted cross correlation. Positive when
+    /// the measurement lags the reference. Returns None before any frame
+    /// has been averaged.
+    pub fn find_delay(&mut self) -> Option<f32> {
+        if self.frames == 0 {
+            return None;
+        }
+        let mut weighted: Vec<Complex<f32>> = self
+            .cross_spectrum
+            .iter()
+            .map(|&gxy| {
+                let norm = gxy.norm();
+                if norm > 0.0 {
+                    gxy / norm
+                } else {
+                    Complex::new(0.0, 0.0)
+                }
+            })
+            .collect();
+        // Imaginary parts of DC and Nyquist must be zero for a real output.
+        let last = weighted.len() - 1;
+        weighted[0].im = 0.0;
+        weighted[last].im = 0.0;
+        self.complex_to_real
+            .process(&mut weighted, &mut self.indata)
+            .unwrap();
+
+        // Lags wrap around, negative lags being at the end of the output.
+        let len = self.indata.len();
+        let max_lag = ((self.config.max_delay.as_secs_f64() * self.axis.sample_rate() as f64)
+            as usize)
+            .min(len / 2 - 1);
+        let correlation = |lag: isize| self.indata[lag.rem_euclid(len as isize) as usize];
+        let best = (-(max_lag as isize)..=max_lag as isize)
+            .max_by(|&a, &b| correlation(a).total_cmp(&correlation(b)))?;
+        let (offset, _) = peaks::fit_parabola(
+            correlation(best - 1),
+            correlation(best),
+            correlation(best + 1),
+        );
+        Some(best as f32 + offset)
+    }
+
+    /// Finds the delay between the channels and compensates it by dropping
+    /// samples from the leading channel. The averages are reset as frames
+    /// taken before were misaligned. Returns the total compensated delay.
+    pub fn align(&mut self) -> Option<isize> {
+        let delay = self.find_delay()?.round() as isize;
+        self.delay += delay;
+        self.reset();
+        Some(self.delay)
+    }
+
+    /// Adds the spectra of the first fft_size pending samples of each
+    /// channel to the averages.
+    fn process_frame(&mut self) {
+        let fft_size = self.config.fft_size;
+        for (pending, spectrum) in [
+            (&self.pending_reference, &mut self.reference_spectrum),
+            (&self.pending_measurement, &mut self.measurement_spectrum),
+        ] {
+            for ((input, &sample), &w) in self
+                .indata
+                .iter_mut()
+                .zip(&pending[..fft_size])
+                .zip(&self.window)
+            {
+                *input = sample * w;
+            }
+            self.real_to_complex
+                .process(&mut self.indata, spectrum)
+                .unwrap();
+        }
+
+        self.frames += 1;
+        let limit = self.config.averages.unwrap_or(usize::MAX).max(1);
+        let alpha = 1.0 / self.frames.min(limit) as f32;
+        let spectra = self
+            .reference_spectrum
+            .iter()
+            .zip(&self.measurement_spectrum);
+        let averages = self
+            .reference_power
+            .iter_mut()
+            .zip(self.measurement_power.iter_mut())
+            .zip(self.cross_spectrum.iter_mut());
+        for ((x, y), ((gxx, gyy), gxy)) in spectra.zip(averages) {
+            *gxx += alpha * (x.norm_sqr() - *gxx);
+            *gyy += alpha * (y.norm_sqr() - *gyy);
+            *gxy += (x.conj() * y - *gxy) * alpha;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime_fft::realtime_fft_src::SrcInfo;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn planner() -> Rc<RefCell<RealFftPlanner<f32>>> {
+        Rc::new(RefCell::new(RealFftPlanner::new()))
+    }
+
+    fn config() -> TransferConfig {
+        TransferConfig {
+            fft_size: 1024,
+            averages: None,
+            max_delay: Duration::from_millis(10),
+            ..TransferConfig::default()
+        }
+    }
+
+    /// Returns white noise and the same noise halved and delayed by `delay`
+    /// samples.
+    fn halved_and_delayed(len: usize, delay: usize) -> (Vec<f32>, Vec<f32>) {
+        let mut rng = StdRng::seed_from_u64(3);
+        let reference: Vec<f32> = (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let measurement = (0..len)
+            .map(|n| n.checked_sub(delay).map_or(0.0, |m| 0.5 * reference[m]))
+            .collect();
+        (reference, measurement)
+    }
+
+    #[test]
+    fn gain_of_a_linear_system() {
+        let (reference, measurement) = halved_and_delayed(1 << 16, 0);
+        let mut analyzer = TransferAnalyzer::new(&planner(), 8000, config()).unwrap();
+        analyzer.push(&reference, &measurement);
+        assert_eq!(analyzer.frames(), 127);
+
+        let transfer = analyzer.transfer_function();
+        for bin in 1..512 {
+            assert!((transfer.magnitude_db(bin) + 6.02).abs() < 0.01);
+            assert!(transfer.phase(bin).abs() < 1e-3);
+            assert!((transfer.h2[bin] - transfer.h1[bin]).norm() < 1e-3);
+            assert!(transfer.coherence[bin] > 0.999);
+        }
+    }
+
+    #[test]
+    fn delay_is_found_and_compensated() {
+        let (reference, measurement) = halved_and_delayed(1 << 16, 23);
+        let mut analyzer = TransferAnalyzer::new(&planner(), 8000, config()).unwrap();
+        assert_eq!(analyzer.find_delay(), None);
+        analyzer.push(&reference[..1 << 14], &measurement[..1 << 14]);
+        assert!((analyzer.find_delay().unwrap() - 23.0).abs() < 0.1);
+        assert_eq!(analyzer.align(), Some(23));
+        assert_eq!(analyzer.frames(), 0);
+
+        analyzer.push(&reference[1 << 14..], &measurement[1 << 14..]);
+        let transfer = analyzer.transfer_function();
+        for bin in 1..512 {
+            assert!((transfer.magnitude_db(bin) + 6.02).abs() < 0.01);
+            assert!(transfer.coherence[bin] > 0.999);
+        }
+    }
+
+    #[test]
+    fn taps_are_read_in_step() {
+        let (reference, measurement) = halved_and_delayed(4096, 0);
+        let mut reference_src: SrcInfo = SrcInfo::new(0);
+        let mut measurement_src: SrcInfo = SrcInfo::new(0);
+        let mut reference_tap = reference_src.add_sample_tap(4096);
+        let mut measurement_tap = measurement_src.add_sample_tap(4096);
+        reference_src.push_callback_data(&reference, 0);
+        measurement_src.push_callback_data(&measurement[..3000], 0);
+
+        let mut analyzer = TransferAnalyzer::new(&planner(), 8000, config()).unwrap();
+        analyzer.push_from(&mut reference_tap, &mut measurement_tap);
+        assert_eq!(reference_tap.len(), 1096);
+        assert!(measurement_tap.is_empty());
+        assert_eq!(analyzer.frames(), 4);
+
+        measurement_src.push_callback_data(&measurement[3000..], 0);
+        analyzer.push_from(&mut reference_tap, &mut measurement_tap);
+        assert_eq!(analyzer.frames(), 7);
+        assert!((analyzer.transfer_function().magnitude_db(100) + 6.02).abs() < 0.01);
+    }
+
+    #[test]
+    fn invalid_overlap_is_rejected() {
+        let config = TransferConfig {
+            overlap: 1.0,
+            ..TransferConfig::default()
+        };
+        assert!(TransferAnalyzer::new(&planner(), 8000, config).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+//! Minimal reading and writing of WAV files so results can be exchanged with
+//! other tools. Files are written as 32 bit float. PCM and float files are
+//! read.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Format tags of the fmt chunk.
+const FORMAT_PCM: u16 = 1;
+const FORMAT_IEEE_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+/// Size of the RIFF, fmt and data headers written.
+const HEADER_LEN: u32 = 44;
+
+/// Layout of the samples in a WAV file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WavSpec {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// Writes samples to a WAV file as they are produced. The header is
+/// completed by finish, or on drop ignoring errors.
+pub struct WavWriter {
+    writer: Option<BufWriter<File>>,
+    spec: WavSpec,
+    /// Number of samples written, counting every channel.
+    samples: u32,
+}
+
+impl WavWriter {
+    /// Creates the file at `path`, truncating any existing file.
+    pub fn create<P: AsRef<Path>>(path: P, spec: WavSpec) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_header(&mut writer, spec, 0)?;
+        Ok(WavWriter {
+            writer: Some(writer),
+            spec,
+            samples: 0,
+        })
+    }
+
+    /// Returns the layout of the file.
+    pub fn spec(&self) -> WavSpec {
+        self.spec
+    }
+
+    /// Appends interleaved samples.
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let writer = self.writer.as_mut().unwrap();
+        for sample in samples {
+            writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    /// Completes the header and flushes the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finalize()
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.seek(SeekFrom::Start(0))?;
+            write_header(&mut writer, self.spec, self.samples)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let _ = self.finalize();
+    }
+}
+
+/// Writes interleaved samples to a WAV file.
+pub fn write_wav<P: AsRef<Path>>(path: P, spec: WavSpec, samples: &[f32]) -> io::Result<()> {
+    let mut writer = WavWriter::create(path, spec)?;
+    writer.write(samples)?;
+    writer.finish()
+}
+
+/// Reads a WAV file. Returns its layout and interleaved samples scaled to
+/// [-1, 1].
+pub fn read_wav<P: AsRef<Path>>(path: P) -> io::Result<(WavSpec, Vec<f32>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut riff = [0; 12];
+    reader.read_exact(&mut riff)?;
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        return Err(invalid_data("Not a WAV file!"));
+    }
+
+    let mut format = None;
+    loop {
+        let mut chunk = [0; 8];
+        reader.read_exact(&mut chunk)?;
+        let len = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as usize;
+        // Chunks are padded to an even length. Writers often leave out the
+        // pad byte after the last chunk, so a missing one is tolerated. The
+        // length comes from the file, so read rather than preallocate.
+        let mut body = Vec::new();
+        reader
+            .by_ref()
+            .take((len + len % 2) as u64)
+            .read_to_end(&mut body)?;
+        if body.len() < len {
+            return Err(invalid_data("Truncated chunk!"));
+        }
+        body.truncate(len);
+
+        match &chunk[0..4] {
+            b"fmt " => {
+                if body.len() < 16 {
+                    return Err(invalid_data("Truncated fmt chunk!"));
+                }
+                let word = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+                let mut tag = word(0);
+                if tag == FORMAT_EXTENSIBLE && body.len() >= 26 {
+                    tag = word(24);
+                }
+                let spec = WavSpec {
+                    channels: word(2),
+                    sample_rate: u32::from_le_bytes([body[4], body[5], body[6], body[7]]),
+                };
+                format = Some((spec, tag, word(14)));
+            }
+            b"data" => {
+                let (spec, tag, bits) = format.ok_or_else(|| invalid_data("No fmt chunk!"))?;
+                return Ok((spec, decode(&body, tag, bits)?));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Converts the contents of a data chunk to floats.
+fn decode(data: &[u8], tag: u16, bits: u16) -> io::Result<Vec<f32>> {
+    let samples = match (tag, bits) {
+        (FORMAT_PCM, 8) => data.iter().map(|&b| (b as f32 - 128.0) / 128.0).collect(),
+        (FORMAT_PCM, 16) => data
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+            .collect(),
+        (FORMAT_PCM, 24) => data
+            .chunks_exact(3)
+            .map(|b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8388608.0)
+            .collect(),
+        (FORMAT_PCM, 32) => data
+            .chunks_exact(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0)
+            .collect(),
+        (FORMAT_IEEE_FLOAT, 32) => data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+        _ => return Err(invalid_data("Unsupported WAV sample format!")),
+    };
+    Ok(samples)
+}
+
+/// Writes the header of a 32 bit float file holding `samples` samples.
+fn write_header<W: Write>(writer: &mut W, spec: WavSpec, samples: u32) -> io::Result<()> {
+    let data_len = samples * 4;
+    let block_align = spec.channels * 4;
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(HEADER_LEN - 8 + data_len).to_le_bytes())?;
+    writer.write_all(b"WAVEfmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&FORMAT_IEEE_FLOAT.to_le_bytes())?;
+    writer.write_all(&spec.channels.to_le_bytes())?;
+    writer.write_all(&spec.sample_rate.to_le_bytes())?;
+    writer.write_all(&(spec.sample_rate * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&32u16.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Returns a path in the temporary directory unique to this process.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("realtime_fft_{}_{}.wav", std::process::id(), name))
+    }
+
+    /// Returns the bytes of a file holding the given chunks. The pad byte
+    /// of the last chunk is left out unless `pad_last`.
+    fn riff(chunks: &[(&[u8; 4], &[u8])], pad_last: bool) -> Vec<u8> {
+        let mut body = b"WAVE".to_vec();
+        for (i, (id, data)) in chunks.iter().enumerate() {
+            body.extend_from_slice(*id);
+            body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            body.extend_from_slice(data);
+            if data.len() % 2 == 1 && (pad_last || i + 1 < chunks.len()) {
+                body.push(0);
+            }
+        }
+        let mut file = b"RIFF".to_vec();
+        file.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        file.extend_from_slice(&body);
+        file
+    }
+
+    /// Returns a fmt chunk of mono 8 bit PCM at 8 kHz.
+    fn pcm8_format() -> Vec<u8> {
+        let mut format = Vec::new();
+        format.extend_from_slice(&FORMAT_PCM.to_le_bytes());
+        format.extend_from_slice(&1u16.to_le_bytes());
+        format.extend_from_slice(&8000u32.to_le_bytes());
+        format.extend_from_slice(&8000u32.to_le_bytes());
+        format.extend_from_slice(&1u16.to_le_bytes());
+        format.extend_from_slice(&8u16.to_le_bytes());
+        format
+    }
+
+    #[test]
+    fn written_samples_are_read_back() {
+        let path = temp_path("round_trip");
+        let spec = WavSpec {
+            sample_rate: 48000,
+            channels: 2,
+        };
+        let samples: Vec<f32> = (0..1000).map(|n| (n as f32 * 0.01).sin()).collect();
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        writer.write(&samples[..300]).unwrap();
+        writer.write(&samples[300..]).unwrap();
+        writer.finish().unwrap();
+
+        let (read_spec, read_samples) = read_wav(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read_spec, spec);
+        assert_eq!(read_samples, samples);
+    }
+
+    #[test]
+    fn odd_chunks_are_padded_except_at_the_end() {
+        let format = pcm8_format();
+        let data = [0, 128, 255];
+        for (pad, name) in [(true, "padded"), (false, "unpadded")] {
+            let path = temp_path(name);
+            let file = riff(
+                &[(b"fmt ", &format), (b"LIST", b"odd"), (b"data", &data)],
+                pad,
+            );
+            fs::write(&path, file).unwrap();
+            let result = read_wav(&path);
+            fs::remove_file(&path).unwrap();
+
+            let (spec, samples) = result.unwrap();
+            assert_eq!(spec.sample_rate, 8000);
+            assert_eq!(spec.channels, 1);
+            assert_eq!(samples, [-1.0, 0.0, 127.0 / 128.0]);
+        }
+    }
+
+    #[test]
+    fn truncated_data_is_an_error() {
+        let path = temp_path("truncated");
+        let mut file = riff(&[(b"fmt ", &pcm8_format()), (b"data", &[0; 16])], true);
+        file.truncate(file.len() - 4);
+        fs::write(&path, file).unwrap();
+        let result = read_wav(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,367 @@
+//! Power spectral density estimation using Welch's method. Overlapping
+//! windowed segments are transformed with the fft planner shared with
+//! RealtimeFft and their periodograms averaged.
+
+use crate::error::ConfigError;
+use crate::realtime_fft::realtime_fft_src::SampleTap;
+use crate::window::{self, WindowFunction};
+use realfft::{RealFftPlanner, RealToComplex};
+use rustfft::num_complex::Complex;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Parameters of a Welch estimate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WelchConfig {
+    /// Length of each segment in samples.
+    pub segment_len: usize,
+    /// Fraction of each segment shared with the next one, in [0, 1).
+    pub overlap: f32,
+    /// Window applied to each segment.
+    pub window: WindowFunction,
+}
+
+impl WelchConfig {
+    /// Checks that segments are at least 2 samples long and that the
+    /// overlap is in [0, 1).
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.segment_len < 2 {
+            return Err(ConfigError::new("segment_len must be at least 2"));
+        }
+        if !(0.0..1.0).contains(&self.overlap) {
+            return Err(ConfigError::new("overlap must be in [0, 1)"));
+        }
+        Ok(())
+    }
+
+    /// Number of samples between the starts of consecutive segments.
+    pub fn hop(&self) -> usize {
+        ((self.segment_len as f32 * (1.0 - self.overlap)).round() as usize).max(1)
+    }
+}
+
+impl Default for WelchConfig {
+    fn default() -> Self {
+        WelchConfig {
+            segment_len: 4096,
+            overlap: 0.5,
+            window: WindowFunction::Hann,
+        }
+    }
+}
+
+/// One-sided power spectral density.
+#[derive(Clone, Debug)]
+pub struct Psd {
+    /// Power spectral density of each bin in units^2 / Hz.
+    pub density: Vec<f32>,
+    /// Number of segments averaged.
+    pub averages: usize,
+    /// Equivalent degrees of freedom of each bin, accounting for overlap.
+    pub degrees_of_freedom: f32,
+    /// Sample rate of the signal.
+    pub sample_rate: u32,
+    /// Length of each segment in samples.
+    pub segment_len: usize,
+}
+
+impl Psd {
+    /// Returns the centre frequency of a bin in Hz.
+    pub fn frequency(&self, bin: usize) -> f32 {
+        bin as f32 * self.sample_rate as f32 / self.segment_len as f32
+    }
+
+    /// Returns the factors (lower, upper) by which the density must be
+    /// multiplied to get the confidence interval at the given level,
+    /// e.g. 0.95.
+    pub fn confidence_interval(&self, confidence: f32) -> (f32, f32) {
+        if self.averages == 0 {
+            return (0.0, f32::INFINITY);
+        }
+        let alpha = 1.0 - confidence as f64;
+        let dof = self.degrees_of_freedom as f64;
+        let lower = dof / chi_squared_quantile(1.0 - alpha / 2.0, dof);
+        let upper = dof / chi_squared_quantile(alpha / 2.0, dof);
+        (lower as f32, upper as f32)
+    }
+}
+
+/// Streaming Welch estimator which converges as more samples arrive.
+pub struct WelchEstimator {
+    config: WelchConfig,
+    sample_rate: u32,
+    /// Plan from the shared planner.
+    real_to_complex: Arc<dyn RealToComplex<f32>>,
+    /// Window coefficients.
+    window: Vec<f32>,
+    /// Samples not yet part of a complete segment.
+    pending: Vec<f32>,
+    /// Sum of the periodograms of every segment.
+    accumulated: Vec<f64>,
+    /// Number of segments accumulated.
+    averages: usize,
+    indata: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl WelchEstimator {
+    /// Returns a new WelchEstimator planning its fft with the given planner.
+    pub fn new(
+        fft_planner: &Rc<RefCell<RealFftPlanner<f32>>>,
+        sample_rate: u32,
+        config: WelchConfig,
+    ) -> Result<Self, ConfigError> {
+        config.validate()?;
+        let real_to_complex = fft_planner
+            .borrow_mut()
+            .plan_fft_forward(config.segment_len);
+        let indata = real_to_complex.make_input_vec();
+        let spectrum = real_to_complex.make_output_vec();
+        let scratch = real_to_complex.make_scratch_vec();
+        Ok(WelchEstimator {
+            config,
+            sample_rate,
+            window: config.window.coefficients(config.segment_len),
+            pending: Vec::with_capacity(config.segment_len * 2),
+            accumulated: vec![0.0; spectrum.len()],
+            averages: 0,
+            real_to_complex,
+            indata,
+            spectrum,
+            scratch,
+        })
+    }
+
+    /// Returns the configuration of the estimator.
+    pub fn config(&self) -> &WelchConfig {
+        &self.config
+    }
+
+    /// Returns the number of segments averaged so far.
+    pub fn averages(&self) -> usize {
+        self.averages
+    }
+
+    /// Forgets every segment averaged so far.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+        self.accumulated.iter_mut().for_each(|power| *power = 0.0);
+        self.averages = 0;
+    }
+
+    /// Adds samples, processing every segment that becomes complete.
+    pub fn push(&mut self, samples: &[f32]) {
+        let segment_len = self.config.segment_len;
+        let hop = self.config.hop();
+        for chunk in samples.chunks(segment_len) {
+            self.pending.extend_from_slice(chunk);
+            while self.pending.len() >= segment_len {
+                self.process_segment();
+                let hop = hop.min(self.pending.len());
+                self.pending.drain(..hop);
+            }
+        }
+    }
+
+    /// Adds every sample available in a tap of the audio source.
+    pub fn push_from(&mut self, tap: &mut SampleTap) {
+        tap.drain(|samples| self.push(samples));
+    }
+
+    /// Returns the current estimate.
+    pub fn psd(&self) -> Psd {
+        let scale = if self.averages == 0 {
+            0.0
+        } else {
+            1.0 / self.averages as f64
+        };
+        Psd {
+            density: self
+                .accumulated
+                .iter()
+                .map(|&power| (power * scale) as f32)
+                .collect(),
+            averages: self.averages,
+            degrees_of_freedom: equivalent_degrees_of_freedom(
+                &self.window,
+                self.config.hop(),
+                self.averages,
+            ),
+            sample_rate: self.sample_rate,
+            segment_len: self.config.segment_len,
+        }
+    }
+
+    /// Adds the periodogram of the first segment_len pending samples.
+    fn process_segment(&mut self) {
+        let segment_len = self.config.segment_len;
+        for ((input, &sample), &w) in self
+            .indata
+            .iter_mut()
+            .zip(&self.pending[..segment_len])
+            .zip(&self.window)
+        {
+            *input = sample * w;
+        }
+        self.real_to_complex
+            .process_with_scratch(&mut self.indata, &mut self.spectrum, &mut self.scratch)
+            .unwrap();
+
+        // One-sided density. Every bin but DC and Nyquist is doubled.
+        let norm = 1.0 / (self.sample_rate as f64 * window::power_gain(&self.window) as f64);
+        for (k, (power, bin)) in self.accumulated.iter_mut().zip(&self.spectrum).enumerate() {
+            let one_sided = if k == 0 || 2 * k == segment_len {
+                1.0
+            } else {
+                2.0
+            };
+            *power += one_sided * norm * bin.norm_sqr() as f64;
+        }
+        self.averages += 1;
+    }
+}
+
+/// Estimates the power spectral density of a block of samples.
+pub fn welch(
+    fft_planner: &Rc<RefCell<RealFftPlanner<f32>>>,
+    samples: &[f32],
+    sample_rate: u32,
+    config: WelchConfig,
+) -> Result<Psd, ConfigError> {
+    let mut estimator = WelchEstimator::new(fft_planner, sample_rate, config)?;
+    estimator.push(samples);
+    Ok(estimator.psd())
+}
+
+/// Equivalent degrees of freedom of a Welch estimate with overlapping
+/// segments (Welch, 1967).
+fn equivalent_degrees_of_freedom(window: &[f32], hop: usize, averages: usize) -> f32 {
+    if averages == 0 {
+        return 0.0;
+    }
+    let power = window::power_gain(window) as f64;
+    let k = averages as f64;
+    let mut correlation_sum = 0.0;
+    for j in 1..averages {
+        let lag = j * hop;
+        if lag >= window.len() {
+            break;
+        }
+        let rho = window
+            .iter()
+            .zip(&window[lag..])
+            .map(|(a, b)| (a * b) as f64)
+            .sum::<f64>()
+            / power;
+        correlation_sum += (1.0 - j as f64 / k) * rho * rho;
+    }
+    (2.0 * k / (1.0 + 2.0 * correlation_sum)) as f32
+}
+
+/// Quantile of the chi-squared distribution using the Wilson-Hilferty
+/// approximation.
+fn chi_squared_quantile(p: f64, dof: f64) -> f64 {
+    let a = 2.0 / (9.0 * dof);
+    let x = 1.0 - a + normal_quantile(p) * a.sqrt();
+    dof * x.max(0.0).powi(3)
+}
+
+/// Quantile of the standard normal distribution (Abramowitz and Stegun
+/// 26.2.23, error below 4.5e-4).
+fn normal_quantile(p: f64) -> f64 {
+    let q = if p < 0.5 { p } else { 1.0 - p };
+    let t = (-2.0 * q.ln()).sqrt();
+    let z = t
+        - (2.515517 + 0.802853 * t + 0.010328 * t * t)
+            / (1.0 + 1.432788 * t + 0.189269 * t * t + 0.001308 * t * t * t);
+    if p < 0.5 {
+        -z
+    } else {
+        z
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn planner() -> Rc<RefCell<RealFftPlanner<f32>>> {
+        Rc::new(RefCell::new(RealFftPlanner::new()))
+    }
+
+    #[test]
+    fn white_noise_density_is_flat() {
+        // Uniform noise in [-1, 1) has a variance of 1/3.
+        let mut rng = StdRng::seed_from_u64(1);
+        let noise: Vec<f32> = (0..1 << 18).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let config = WelchConfig {
+            segment_len: 1024,
+            ..WelchConfig::default()
+        };
+        let psd = welch(&planner(), &noise, 8000, config).unwrap();
+        assert_eq!(psd.averages, 511);
+
+        // One-sided density of white noise is 2 variance / fs.
+        let expected = 2.0 / 3.0 / 8000.0;
+        let inner = &psd.density[1..512];
+        let mean = inner.iter().sum::<f32>() / inner.len() as f32;
+        assert!((mean / expected - 1.0).abs() < 0.01);
+        assert!((psd.density[0] / expected - 0.5).abs() < 0.25);
+
+        // Integrating the density gives back the variance.
+        let power = psd.density.iter().sum::<f32>() * 8000.0 / 1024.0;
+        assert!((power * 3.0 - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn streaming_matches_one_shot() {
+        let samples: Vec<f32> = (0..5000).map(|n| (n as f32 * 0.3).sin()).collect();
+        let config = WelchConfig {
+            segment_len: 256,
+            overlap: 0.75,
+            window: WindowFunction::Hann,
+        };
+        let one_shot = welch(&planner(), &samples, 1000, config).unwrap();
+        let mut estimator = WelchEstimator::new(&planner(), 1000, config).unwrap();
+        for chunk in samples.chunks(77) {
+            estimator.push(chunk);
+        }
+        let streamed = estimator.psd();
+        assert_eq!(streamed.averages, one_shot.averages);
+        for (a, b) in streamed.density.iter().zip(&one_shot.density) {
+            assert!((a - b).abs() <= 1e-6 * b.abs().max(1e-6));
+        }
+    }
+
+    #[test]
+    fn disjoint_segments_have_two_degrees_of_freedom_each() {
+        let config = WelchConfig {
+            segment_len: 64,
+            overlap: 0.0,
+            window: WindowFunction::Rectangular,
+        };
+        let psd = welch(&planner(), &[0.0; 640], 1000, config).unwrap();
+        assert_eq!(psd.averages, 10);
+        assert!((psd.degrees_of_freedom - 20.0).abs() < 1e-4);
+
+        // Chi-squared with 20 degrees of freedom: 9.59 and 34.17 at 95%.
+        let (lower, upper) = psd.confidence_interval(0.95);
+        assert!((lower - 20.0 / 34.17).abs() < 0.01);
+        assert!((upper - 20.0 / 9.59).abs() < 0.02);
+    }
+
+    #[test]
+    fn rejects_invalid_overlap() {
+        for &overlap in &[-0.1, 1.0, f32::NAN] {
+            let config = WelchConfig {
+                overlap,
+                ..WelchConfig::default()
+            };
+            assert!(WelchEstimator::new(&planner(), 1000, config).is_err());
+        }
+    }
+}
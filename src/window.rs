@@ -0,0 +1,93 @@
+//! Window functions applied to a block of samples before an fft.
+
+use std::f64::consts::PI;
+
+/// Supported window functions. Windows are periodic (DFT-even) which is the
+/// form suited to spectral analysis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowFunction {
+    /// No windowing.
+    Rectangular,
+    Hann,
+    Hamming,
+    Blackman,
+    /// 4-term Blackman-Harris.
+    BlackmanHarris,
+    /// Flat top window. Accurate amplitudes, poor frequency resolution.
+    FlatTop,
+}
+
+impl WindowFunction {
+    /// Returns the value of the window at sample `n` of a window of length `len`.
+    pub fn value(&self, n: usize, len: usize) -> f32 {
+        let x = 2.0 * PI * n as f64 / len as f64;
+        let cosine_sum = |coefficients: &[f64]| {
+            coefficients
+                .iter()
+                .enumerate()
+                .map(|(k, a)| {
+                    let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+                    sign * a * (k as f64 * x).cos()
+                })
+                .sum::<f64>()
+        };
+        (match self {
+            WindowFunction::Rectangular => 1.0,
+            WindowFunction::Hann => cosine_sum(&[0.5, 0.5]),
+            WindowFunction::Hamming => cosine_sum(&[0.54, 0.46]),
+            WindowFunction::Blackman => cosine_sum(&[0.42, 0.5, 0.08]),
+            WindowFunction::BlackmanHarris => cosine_sum(&[0.35875, 0.48829, 0.14128, 0.01168]),
+            WindowFunction::FlatTop => cosine_sum(&[
+                0.21557895,
+                0.41663158,
+                0.277263158,
+                0.083578947,
+                0.006947368,
+            ]),
+        }) as f32
+    }
+
+    /// Fills `coefficients` with the window.
+    pub fn fill(&self, coefficients: &mut [f32]) {
+        let len = coefficients.len();
+        for (n, coefficient) in coefficients.iter_mut().enumerate() {
+            *coefficient = self.value(n, len);
+        }
+    }
+
+    /// Returns the half width of the main lobe in bins, i.e. the distance
+    /// from the peak of a sinusoid to the first null of its spectrum.
+    pub fn main_lobe_half_width(&self) -> usize {
+        match self {
+            WindowFunction::Rectangular => 1,
+            WindowFunction::Hann | WindowFunction::Hamming => 2,
+            WindowFunction::Blackman => 3,
+            WindowFunction::BlackmanHarris => 4,
+            WindowFunction::FlatTop => 5,
+        }
+    }
+
+    /// Returns the window coefficients for a window of length `len`.
+    pub fn coefficients(&self, len: usize) -> Vec<f32> {
+        let mut coefficients = vec![0.0; len];
+        self.fill(&mut coefficients);
+        coefficients
+    }
+}
+
+/// Sum of the window. A sinusoid of amplitude A shows up in the spectrum
+/// with magnitude A * coherent_gain / 2.
+pub fn coherent_gain(coefficients: &[f32]) -> f32 {
+    coefficients.iter().sum()
+}
+
+/// Sum of the squared window. Used to normalise power spectral densities.
+pub fn power_gain(coefficients: &[f32]) -> f32 {
+    coefficients.iter().map(|w| w * w).sum()
+}
+
+/// Equivalent noise bandwidth of the window in bins.
+pub fn enbw(coefficients: &[f32]) -> f32 {
+    let coherent_gain = coherent_gain(coefficients);
+    coefficients.len() as f32 * power_gain(coefficients) / (coherent_gain * coherent_gain)
+}
@@ -0,0 +1,346 @@
+//! Zoom fft. The signal is mixed down so the centre frequency lands at DC,
+//! low-pass filtered, decimated and transformed as a complex signal, giving
+//! a fine resolution over a narrow band without a huge fft.
+
+use crate::biquad::{self, Cascade};
+use crate::error::ConfigError;
+use crate::realtime_fft::realtime_fft_src::SampleTap;
+use crate::sample_window::SampleWindow;
+use crate::window::{self, WindowFunction};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+/// Parameters of the zoom fft.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZoomConfig {
+    /// Centre of the zoomed band in Hz.
+    pub centre_hz: f64,
+    /// Width of the zoomed band in Hz.
+    pub span_hz: f64,
+    /// Size of the complex fft. The bins are about 2 * span_hz / fft_size
+    /// wide.
+    pub fft_size: usize,
+    pub window: WindowFunction,
+    /// Order of the anti-aliasing Butterworth filter.
+    pub filter_order: usize,
+}
+
+impl ZoomConfig {
+    /// Checks that the band lies between DC and the Nyquist frequency of
+    /// `sample_rate` and that the fft and filter aren't empty.
+    pub fn validate(&self, sample_rate: u32) -> Result<(), ConfigError> {
+        if self.span_hz.is_nan() || self.span_hz <= 0.0 {
+            return Err(ConfigError::new("span_hz must be positive"));
+        }
+        let nyquist = sample_rate as f64 / 2.0;
+        if self.centre_hz.is_nan()
+            || self.centre_hz < 0.0
+            || self.centre_hz + self.span_hz / 2.0 >= nyquist
+        {
+            return Err(ConfigError::new(format!(
+                "the zoomed band must lie below the Nyquist frequency of {} Hz",
+                nyquist
+            )));
+        }
+        if self.fft_size == 0 || self.filter_order == 0 {
+            return Err(ConfigError::new(
+                "fft_size and filter_order must be at least 1",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for ZoomConfig {
+    fn default() -> Self {
+        ZoomConfig {
+            // Mains hum.
+            centre_hz: 50.0,
+            span_hz: 20.0,
+            fft_size: 1024,
+            window: WindowFunction::Hann,
+            filter_order: 8,
+        }
+    }
+}
+
+/// Frequencies of the bins of a zoomed spectrum, lowest first.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZoomAxis {
+    centre_hz: f64,
+    /// Sample rate after decimation.
+    sample_rate: f64,
+    fft_size: usize,
+}
+
+impl ZoomAxis {
+    /// Returns the frequency at the middle of the axis in Hz.
+    pub fn centre_hz(&self) -> f64 {
+        self.centre_hz
+    }
+
+    /// Returns the sample rate of the decimated baseband.
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    /// Returns the number of bins.
+    pub fn num_bins(&self) -> usize {
+        self.fft_size
+    }
+
+    /// Returns the distance between bins in Hz.
+    pub fn bin_width(&self) -> f64 {
+        self.sample_rate / self.fft_size as f64
+    }
+
+    /// Returns the frequency in Hz of a, possibly fractional, bin.
+    pub fn bin_to_hz(&self, bin: f64) -> f64 {
+        self.centre_hz + (bin - (self.fft_size / 2) as f64) * self.bin_width()
+    }
+
+    /// Returns the, possibly fractional, bin of `hz`.
+    pub fn hz_to_bin(&self, hz: f64) -> f64 {
+        (hz - self.centre_hz) / self.bin_width() + (self.fft_size / 2) as f64
+    }
+
+    /// Returns the frequency of each bin.
+    pub fn frequencies(&self) -> impl Iterator<Item = f64> {
+        let axis = *self;
+        (0..self.fft_size).map(move |bin| axis.bin_to_hz(bin as f64))
+    }
+}
+
+/// High resolution spectrum of a narrow band.
+pub struct ZoomFft {
+    config: ZoomConfig,
+    axis: ZoomAxis,
+    decimation: usize,
+    /// Phase of the local oscillator in radians and its increment per
+    /// sample.
+    phase: f64,
+    phase_step: f64,
+    /// Anti-aliasing filters of the in-phase and quadrature signals.
+    filters: [Cascade; 2],
+    /// Input samples until the next decimated sample.
+    countdown: usize,
+    /// Most recent baseband samples.
+    baseband: SampleWindow<Complex<f32>>,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    buffer: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    /// Scaling of each fft bin, compensating the window and the filter.
+    gains: Vec<f32>,
+    amplitudes: Vec<f32>,
+}
+
+impl ZoomFft {
+    /// Returns a zoom fft of a signal at `sample_rate`.
+    pub fn new(sample_rate: u32, config: ZoomConfig) -> Result<Self, ConfigError> {
+        config.validate(sample_rate)?;
+        let fs = sample_rate as f64;
+        // Keep twice the span after decimation so that what the filter lets
+        // through above the band folds outside of it.
+        let decimation = ((fs / (2.0 * config.span_hz)).floor() as usize).max(1);
+        let cutoff = (0.6 * config.span_hz).min(0.45 * fs);
+        let filter = biquad::butterworth_lowpass(config.filter_order, cutoff, fs);
+
+        // Mixing halves the amplitude of a real sinusoid. The roll-off of the
+        // filter is undone inside the band.
+        let window = config.window.coefficients(config.fft_size);
+        let norm = 2.0 / window::coherent_gain(&window);
+        let bin_width = fs / decimation as f64 / config.fft_size as f64;
+        let gains = (0..config.fft_size)
+            .map(|bin| {
+                let bin = if bin <= config.fft_size / 2 {
+                    bin as f64
+                } else {
+                    bin as f64 - config.fft_size as f64
+                };
+                let offset = bin * bin_width;
+                if offset.abs() <= config.span_hz / 2.0 {
+                    norm / filter.response(offset, fs).norm() as f32
+                } else {
+                    norm
+                }
+            })
+            .collect();
+
+        let fft = FftPlanner::new().plan_fft_forward(config.fft_size);
+        let scratch = vec![Complex::new(0.0, 0.0); fft.get_inplace_scratch_len()];
+        Ok(ZoomFft {
+            axis: ZoomAxis {
+                centre_hz: config.centre_hz,
+                sample_rate: fs / decimation as f64,
+                fft_size: config.fft_size,
+            },
+            decimation,
+            phase: 0.0,
+            phase_step: 2.0 * PI * config.centre_hz / fs,
+            filters: [filter.clone(), filter],
+            countdown: decimation,
+            baseband: SampleWindow::new(config.fft_size),
+            window,
+            buffer: vec![Complex::new(0.0, 0.0); config.fft_size],
+            scratch,
+            gains,
+            amplitudes: vec![0.0; config.fft_size],
+            fft,
+            config,
+        })
+    }
+
+    /// Returns the configuration of the zoom fft.
+    pub fn config(&self) -> &ZoomConfig {
+        &self.config
+    }
+
+    /// Returns the frequency axis of the zoomed spectrum. Only the bins
+    /// within span_hz / 2 of the centre are compensated for the filter and
+    /// free of aliases.
+    pub fn axis(&self) -> ZoomAxis {
+        self.axis
+    }
+
+    /// Returns the decimation factor.
+    pub fn decimation(&self) -> usize {
+        self.decimation
+    }
+
+    /// Returns the number of input samples a frame spans.
+    pub fn frame_len(&self) -> usize {
+        self.config.fft_size * self.decimation
+    }
+
+    /// Mixes, filters and decimates samples.
+    pub fn push(&mut self, samples: &[f32]) {
+        for &sample in samples {
+            let (sin, cos) = self.phase.sin_cos();
+            self.phase = (self.phase + self.phase_step) % (2.0 * PI);
+            let x = sample as f64;
+            let i = self.filters[0].process(x * cos);
+            let q = self.filters[1].process(-x * sin);
+
+            self.countdown -= 1;
+            if self.countdown == 0 {
+                self.countdown = self.decimation;
+                self.baseband.push_one(Complex::new(i as f32, q as f32));
+            }
+        }
+    }
+
+    /// Adds every sample available in a tap of the audio source.
+    pub fn push_from(&mut self, tap: &mut SampleTap) {
+        tap.drain(|samples| self.push(samples));
+    }
+
+    /// Forgets the buffered samples and the filter state.
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+        self.filters.iter_mut().for_each(Cascade::reset);
+        self.countdown = self.decimation;
+        self.baseband.reset();
+    }
+
+    /// Computes the zoomed spectrum of the most recent baseband samples as
+    /// the peak amplitude of a sinusoid in each bin, ordered as the axis.
+    /// Returns None until a whole frame has been received.
+    pub fn process(&mut self) -> Option<&[f32]> {
+        if !self.baseband.is_full() {
+            return None;
+        }
+        let baseband = self.baseband.samples();
+        for ((bin, &sample), &w) in self.buffer.iter_mut().zip(baseband).zip(&self.window) {
+            *bin = sample * w;
+        }
+        self.fft
+            .process_with_scratch(&mut self.buffer, &mut self.scratch);
+
+        // Negative frequencies come second in the fft output.
+        let half = self.config.fft_size / 2;
+        let (negative, positive) = self.amplitudes.split_at_mut(half);
+        let (positive_gains, negative_gains) = self.gains.split_at(self.config.fft_size - half);
+        for ((amplitude, bin), gain) in positive.iter_mut().zip(&self.buffer).zip(positive_gains) {
+            *amplitude = gain * bin.norm();
+        }
+        let negative_bins = &self.buffer[self.config.fft_size - half..];
+        for ((amplitude, bin), gain) in negative.iter_mut().zip(negative_bins).zip(negative_gains) {
+            *amplitude = gain * bin.norm();
+        }
+        Some(&self.amplitudes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime_fft::realtime_fft_src::SrcInfo;
+
+    fn config() -> ZoomConfig {
+        ZoomConfig {
+            centre_hz: 1000.0,
+            span_hz: 40.0,
+            ..ZoomConfig::default()
+        }
+    }
+
+    fn sine(hz: f64, amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| amplitude * (2.0 * PI * hz * n as f64 / 8000.0).sin() as f32)
+            .collect()
+    }
+
+    #[test]
+    fn sine_in_band_is_resolved() {
+        let mut zoom = ZoomFft::new(8000, config()).unwrap();
+        assert_eq!(zoom.decimation(), 100);
+        let axis = zoom.axis();
+        assert_eq!(axis.bin_width(), 0.078125);
+
+        zoom.push(&sine(1005.0, 0.5, zoom.frame_len() - 1));
+        assert!(zoom.process().is_none());
+        zoom.push(&sine(1005.0, 0.5, zoom.frame_len()));
+        let spectrum = zoom.process().unwrap();
+        let bin = axis.hz_to_bin(1005.0).round() as usize;
+        assert_eq!(bin, 576);
+        assert!((spectrum[bin] - 0.5).abs() < 0.01);
+        assert!(spectrum[bin - 10] < 0.01);
+        assert!(spectrum[bin + 10] < 0.01);
+    }
+
+    #[test]
+    fn filter_rejects_aliases() {
+        // 70 Hz above the centre folds onto -10 Hz after decimation.
+        let mut zoom = ZoomFft::new(8000, config()).unwrap();
+        zoom.push(&sine(1070.0, 1.0, 2 * zoom.frame_len()));
+        let alias = zoom.axis().hz_to_bin(990.0).round() as usize;
+        let spectrum = zoom.process().unwrap();
+        assert!(spectrum[alias] < 1e-3);
+    }
+
+    #[test]
+    fn tap_is_drained() {
+        let mut zoom = ZoomFft::new(8000, config()).unwrap();
+        let mut src_info: SrcInfo = SrcInfo::new(0);
+        let mut tap = src_info.add_sample_tap(zoom.frame_len());
+        src_info.push_callback_data(&sine(1005.0, 0.5, zoom.frame_len()), 0);
+        zoom.push_from(&mut tap);
+        assert!(tap.is_empty());
+        assert!(zoom.process().is_some());
+        zoom.reset();
+        assert!(zoom.process().is_none());
+    }
+
+    #[test]
+    fn band_above_nyquist_is_rejected() {
+        let config = ZoomConfig {
+            centre_hz: 3990.0,
+            ..config()
+        };
+        assert!(ZoomFft::new(8000, config).is_err());
+        assert!(ZoomFft::new(48000, config).is_ok());
+    }
+}
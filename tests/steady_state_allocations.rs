@@ -0,0 +1,100 @@
+//! Once running, the audio callback and the analysis loop must not allocate.
+//! This lives in its own test binary as it replaces the global allocator.
+
+use realtime_fft::realtime_fft::realtime_fft_src::{ChannelSrc, DualSrcInfo, SrcInfo};
+use realtime_fft::realtime_fft::RealtimeFft;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::time::Duration;
+
+/// Counts the allocations made by a thread while it is measuring.
+struct CountingAllocator;
+
+thread_local! {
+    static MEASURING: Cell<bool> = const { Cell::new(false) };
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn record_allocation() {
+    let _ = MEASURING.try_with(|measuring| {
+        if measuring.get() {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        }
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record_allocation();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record_allocation();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record_allocation();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the number of allocations `f` makes on this thread.
+fn count_allocations<F: FnOnce() -> R, R>(f: F) -> (usize, R) {
+    ALLOCATIONS.with(|count| count.set(0));
+    MEASURING.with(|measuring| measuring.set(true));
+    let result = f();
+    MEASURING.with(|measuring| measuring.set(false));
+    (ALLOCATIONS.with(Cell::get), result)
+}
+
+#[test]
+fn callback_and_update_do_not_allocate() {
+    // 20 sample callbacks of a 1024 Hz source feeding a 250 ms window.
+    let mut src_info: SrcInfo = SrcInfo::new(4096);
+    let mut fft = RealtimeFft::new(
+        ChannelSrc::new(src_info.clone(), 1024),
+        Duration::from_millis(250),
+    );
+    let _tap = src_info.add_sample_tap(4096);
+    let samples: Vec<f32> = (0..20).map(|n| (n as f32 * 0.1).sin()).collect();
+    src_info.push_callback_data(&[0.0; 4096], 4096);
+
+    for callback in 0..40 {
+        let (push_allocations, ()) =
+            count_allocations(|| src_info.push_callback_data(&samples, 4096));
+        // The callback latency is pinned rather than measured between
+        // callbacks so update() always finds a window, however slowly this
+        // thread runs.
+        src_info.latency_info().lock().unwrap().max_latency = Some(Duration::from_secs(1));
+        let (update_allocations, updated) = count_allocations(|| fft.update());
+
+        // The first callbacks settle the buffers.
+        if callback >= 5 {
+            assert!(updated);
+            assert_eq!(push_allocations, 0);
+            assert_eq!(update_allocations, 0);
+        }
+    }
+}
+
+#[test]
+fn dual_callback_does_not_allocate() {
+    let mut src_info = DualSrcInfo::new(1024);
+    let _taps = src_info.add_sample_taps(4096);
+    let data: Vec<f32> = (0..96).map(|n| n as f32).collect();
+    src_info.push_interleaved(&data, 2);
+
+    for _ in 0..20 {
+        let (allocations, ()) = count_allocations(|| src_info.push_interleaved(&data, 2));
+        assert_eq!(allocations, 0);
+    }
+}